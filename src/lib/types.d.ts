@@ -2,4 +2,5 @@ export interface PlayerInfo {
     connected: boolean;
     points: number;
     ready: boolean;
+    color: string;
 }
@@ -0,0 +1,43 @@
+// words handed to storytellers who ask for a random clue
+pub const CLUE_WORDS: &[&str] = &[
+    "adventure",
+    "alone",
+    "balance",
+    "beginning",
+    "blossom",
+    "childhood",
+    "courage",
+    "curiosity",
+    "danger",
+    "dream",
+    "echo",
+    "escape",
+    "fate",
+    "freedom",
+    "friendship",
+    "ghost",
+    "harmony",
+    "home",
+    "hope",
+    "illusion",
+    "journey",
+    "legend",
+    "longing",
+    "magic",
+    "memory",
+    "mystery",
+    "nostalgia",
+    "patience",
+    "puzzle",
+    "secret",
+    "silence",
+    "storm",
+    "surprise",
+    "temptation",
+    "treasure",
+    "trust",
+    "wander",
+    "whisper",
+    "wonder",
+    "yesterday",
+];
@@ -0,0 +1,308 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::deck;
+
+// settings chosen when the room is created
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomConfig {
+    // deal cards that have been seen less often in this room first
+    pub weighted_dealing: bool,
+    // order in which each player's hand is sent
+    pub hand_order: HandOrder,
+    // games that can be played before the room has to be recreated
+    pub max_games: Option<u32>,
+    // house rule: the storyteller may cast a vote that is shown but never scored
+    pub storyteller_can_vote: bool,
+    // fixed seed for the room's shuffles; rooms with a seed publish a
+    // commitment to it at the start and reveal it when the game ends
+    pub seed: Option<u64>,
+    // coalesce room state broadcasts within this many milliseconds, 0 to disable
+    pub coalesce_state_ms: u64,
+    // same, but only for joins to the lobby, where groups tend to arrive at once;
+    // the longer of the two applies
+    pub join_coalesce_ms: u64,
+    // seconds the storyteller has to give a clue before their turn is skipped
+    pub clue_timeout_s: Option<u64>,
+    // seconds players have to pick a card, and then to vote, before the
+    // stragglers get a random one
+    pub stage_timeout_s: Option<u64>,
+    // consecutive clue timeouts before a storyteller is penalized, 0 to never penalize
+    pub silent_storyteller_threshold: u32,
+    // turns a penalized storyteller is passed over in the rotation
+    pub silent_storyteller_skip_turns: u32,
+    // points taken from a penalized storyteller
+    pub silent_storyteller_penalty: u16,
+    // players are split into teams that share a score
+    pub team_mode: bool,
+    // number of teams in team mode, two if unset
+    pub team_count: Option<u8>,
+    // how the next storyteller is picked each round
+    pub storyteller_selection: StorytellerSelection,
+    // tell everyone how many votes are in while voting, without saying who voted for what
+    pub reveal_vote_progress: bool,
+    // casual play: the storyteller sees votes for their card come in, but not whose
+    pub live_votes_to_storyteller: bool,
+    // spectators allowed at once, DEFAULT_MAX_SPECTATORS if unset
+    pub max_spectators: Option<usize>,
+    // spectators see the clue and center cards this many seconds after players,
+    // so a streamed game can't be spoiled in its chat
+    pub spectator_delay_s: Option<u64>,
+    // players who reconnect while results are shown are marked ready for the next round
+    pub auto_ready_on_reconnect: bool,
+    pub theme: Theme,
+    // a bonus point for whoever finds the storyteller's card first
+    pub speed_bonus: bool,
+    // storytellers can't repeat a clue already given this game
+    pub unique_clues: bool,
+    // house rule: clues must be a single word rather than a phrase
+    pub one_word_clues: bool,
+    // storytellers may ask for a random card and clue
+    pub allow_random_clue: bool,
+    // round number to point multiplier; rounds not listed score normally
+    pub round_multipliers: HashMap<u16, u16>,
+    // bonus points for each achievement; achievements not listed are off
+    pub achievements: HashMap<Achievement, u16>,
+    // seconds a player who drops mid-game has to come back before their seat is freed
+    pub reconnect_window_s: Option<u64>,
+    // seconds an empty room is kept before it is garbage collected, the server default if unset
+    pub idle_timeout_s: Option<u64>,
+    pub results_detail: ResultsDetail,
+    // cards in each hand, DEFAULT_HAND_SIZE if unset
+    pub hand_size: Option<usize>,
+    // players allowed in the room, DEFAULT_MAX_PLAYERS if unset
+    pub max_players: Option<usize>,
+    // points that end the game, DEFAULT_TARGET_SCORE if unset
+    pub target_score: Option<u16>,
+    // points for each outcome of a round; the standard rules if unset
+    pub scoring: ScoringRules,
+    // play with this many cards picked at random from the full deck
+    pub pool_size: Option<usize>,
+    // play with a deck from /upload_deck instead of the server's cards
+    pub deck_id: Option<String>,
+    // which of the server's themed decks to play with, the server default if
+    // unset; see GET /decks
+    pub deck_theme: Option<String>,
+    // play only with cards of this orientation
+    pub orientation: Option<deck::Orientation>,
+    // list the room on GET /rooms while it is in the lobby
+    pub public: bool,
+    // players and spectators need this to join; only a hash is kept once the
+    // room is created, so it is never sent back out
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    // issue a token at creation for a non-playing director who can drive the game
+    pub director: bool,
+    // the first round of each game is for practice and scores nothing
+    pub warmup_round: bool,
+    // players who join without a name are given a random one
+    pub anonymous_names: bool,
+    // a soft timer for clients to show; nothing happens when it runs out
+    pub suggested_think_s: Option<u64>,
+    // most points anyone can gain in a single round, after every bonus
+    pub round_point_cap: Option<u16>,
+    // players in last place get a point each round to help them catch up
+    pub comeback_bonus: bool,
+    // what happens when too few players are connected to go on mid-game
+    pub on_too_few_players: TooFewPlayers,
+    // with a clue timeout, hold the clock until every connected player has
+    // confirmed the new hand is on screen, or a short grace period passes
+    pub round_start_ack: bool,
+    // spectators may vote too; their votes are tallied separately and never scored
+    pub audience_poll: bool,
+    // longest spectator chat message in graphemes, the server default if unset
+    pub chat_max_len: Option<usize>,
+    // least time between two chat messages from the same spectator, the server default if unset
+    pub chat_min_interval_ms: Option<u64>,
+    // tell everyone how many players have acted in each stage, without saying who
+    pub stage_progress: bool,
+    // what happens to a player who hasn't picked a card when voting starts
+    pub on_no_submission: NoSubmission,
+    // never pick a card or vote for anyone: stages wait for everyone to act, or
+    // for the director, and whoever still hasn't sits the round out; this also
+    // turns off the stage timeout
+    pub disable_auto_fill: bool,
+}
+
+const MIN_IDLE_TIMEOUT_S: u64 = 60;
+const MAX_IDLE_TIMEOUT_S: u64 = 60 * 60 * 24;
+const MAX_SPECTATOR_DELAY_S: u64 = 60;
+
+pub const DEFAULT_HAND_SIZE: usize = 6;
+pub const DEFAULT_MAX_PLAYERS: usize = 8;
+pub const DEFAULT_TARGET_SCORE: u16 = 10;
+
+const HAND_SIZE_RANGE: std::ops::RangeInclusive<usize> = 3..=10;
+const MAX_PLAYERS_RANGE: std::ops::RangeInclusive<usize> = 3..=12;
+const TARGET_SCORE_RANGE: std::ops::RangeInclusive<u16> = 1..=100;
+const MAX_SCORING_POINTS: u16 = 10;
+
+impl RoomConfig {
+    pub fn hand_size(&self) -> usize {
+        self.hand_size.unwrap_or(DEFAULT_HAND_SIZE)
+    }
+
+    pub fn max_players(&self) -> usize {
+        self.max_players.unwrap_or(DEFAULT_MAX_PLAYERS)
+    }
+
+    pub fn target_score(&self) -> u16 {
+        self.target_score.unwrap_or(DEFAULT_TARGET_SCORE)
+    }
+
+    // enough for every seat to hold a full hand
+    pub fn cards_for_full_room(&self) -> usize {
+        self.max_players() * self.hand_size()
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if let Some(card_back) = &self.theme.card_back {
+            if !deck::card_back_exists(card_back) {
+                return Err("Unknown card back");
+            }
+        }
+
+        if let Some(color) = &self.theme.accent_color {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err("Accent color must look like #rrggbb");
+            }
+        }
+
+        if self
+            .spectator_delay_s
+            .is_some_and(|delay| delay > MAX_SPECTATOR_DELAY_S)
+        {
+            return Err("Spectator delay can be at most a minute");
+        }
+
+        if let Some(timeout) = self.idle_timeout_s {
+            if !(MIN_IDLE_TIMEOUT_S..=MAX_IDLE_TIMEOUT_S).contains(&timeout) {
+                return Err("Idle timeout must be between a minute and a day");
+            }
+        }
+
+        if !HAND_SIZE_RANGE.contains(&self.hand_size()) {
+            return Err("Hand size must be between 3 and 10");
+        }
+
+        if !MAX_PLAYERS_RANGE.contains(&self.max_players()) {
+            return Err("Max players must be between 3 and 12");
+        }
+
+        if !TARGET_SCORE_RANGE.contains(&self.target_score()) {
+            return Err("Target score must be between 1 and 100");
+        }
+
+        let scoring = &self.scoring;
+        if [
+            scoring.storyteller_points,
+            scoring.guesser_points,
+            scoring.all_or_none_points,
+            scoring.bonus_per_vote,
+        ]
+        .iter()
+        .any(|points| *points > MAX_SCORING_POINTS)
+        {
+            return Err("Scoring values must be at most 10");
+        }
+
+        if let Some(pool_size) = self.pool_size {
+            if pool_size < self.cards_for_full_room() {
+                return Err("Card pool is too small to deal a full room");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// how clients draw the room; unset fields use the standard theme
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    // file name in the card backs directory
+    pub card_back: Option<String>,
+    pub accent_color: Option<String>,
+}
+
+pub const DEFAULT_MAX_SPECTATORS: usize = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScoringRules {
+    // for the storyteller when some but not all players find their card
+    pub storyteller_points: u16,
+    // for each player who finds the storyteller's card
+    pub guesser_points: u16,
+    // for every other player when everyone or no one finds it; the storyteller gets nothing
+    pub all_or_none_points: u16,
+    // for each vote a decoy draws, to its owner
+    pub bonus_per_vote: u16,
+}
+
+impl Default for ScoringRules {
+    fn default() -> Self {
+        ScoringRules {
+            storyteller_points: 3,
+            guesser_points: 3,
+            all_or_none_points: 2,
+            bonus_per_vote: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum HandOrder {
+    // cards stay in the order they were dealt, new cards at the end
+    #[default]
+    Stable,
+    // the hand is reshuffled whenever new cards are dealt into it
+    Shuffle,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TooFewPlayers {
+    // hold off on the next round until enough players reconnect; the game
+    // still ends once their seats are freed
+    #[default]
+    Pause,
+    // end the game right away with the current scores
+    End,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoSubmission {
+    // a random card from their hand is played for them
+    #[default]
+    Random,
+    // they sit the round out: no card in the center, no vote and no points
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum StorytellerSelection {
+    // storytellers take turns in player order
+    #[default]
+    RoundRobin,
+    // a random player who hasn't been storyteller since everyone last had a turn
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResultsDetail {
+    // every card is shown with its owner, along with every vote
+    #[default]
+    Full,
+    // only the storyteller's card and the point changes are shown
+    Minimal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Achievement {
+    // a decoy card drew every vote other than its owner's
+    FooledEveryone,
+    // the only player to find the storyteller's card
+    LoneCorrectGuesser,
+}
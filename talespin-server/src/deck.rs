@@ -0,0 +1,172 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::Path};
+use tracing::{info, warn};
+
+pub const CARDS_DIR: &str = "../static/assets/cards/";
+pub const CARD_BACKS_DIR: &str = "../static/assets/backs/";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CardMeta {
+    filename: String,
+    width: u32,
+    height: u32,
+    orientation: Orientation,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Orientation {
+    // also used for square cards and cards whose size couldn't be read,
+    // since that's how cards are usually drawn
+    #[default]
+    Portrait,
+    Landscape,
+}
+
+impl Orientation {
+    fn of(width: u32, height: u32) -> Self {
+        if width > height {
+            Orientation::Landscape
+        } else {
+            Orientation::Portrait
+        }
+    }
+}
+
+// read cards and form array of file names, any image file is ok
+pub fn load_deck(dir: &str) -> Result<Vec<String>> {
+    let mut deck = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        if let Ok(file_name) = entry?.file_name().into_string() {
+            if file_name.ends_with(".jpg")
+                || file_name.ends_with(".jpeg")
+                || file_name.ends_with(".png")
+            {
+                deck.push(file_name);
+            }
+        }
+    }
+    Ok(deck)
+}
+
+// cards directly in the cards directory
+pub const DEFAULT_THEME: &str = "default";
+
+// every subdirectory of the cards directory is a themed deck, named after it;
+// its cards are named relative to the cards directory, e.g. odyssey/1.jpg, so
+// clients load them the same way as the rest
+pub fn load_themes(dir: &str) -> Result<HashMap<String, Vec<String>>> {
+    let mut themes: HashMap<String, Vec<String>> = HashMap::new();
+    let top_level = load_deck(dir)?;
+    if !top_level.is_empty() {
+        themes.insert(DEFAULT_THEME.to_string(), top_level);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let Ok(theme) = entry.file_name().into_string() else {
+            continue;
+        };
+        let deck = load_deck(&entry.path().to_string_lossy())?;
+        if !deck.is_empty() {
+            themes
+                .entry(theme.clone())
+                .or_default()
+                .extend(deck.into_iter().map(|card| format!("{}/{}", theme, card)));
+        }
+    }
+    Ok(themes)
+}
+
+// only the image headers are read; cards that can't be read are left out
+pub fn load_card_meta(dir: &str, deck: &[String]) -> Vec<CardMeta> {
+    deck.iter()
+        .filter_map(
+            |filename| match image::image_dimensions(Path::new(dir).join(filename)) {
+                Ok((width, height)) => Some(CardMeta {
+                    filename: filename.clone(),
+                    width,
+                    height,
+                    orientation: Orientation::of(width, height),
+                }),
+                Err(e) => {
+                    warn!("Could not read dimensions of {}: {}", filename, e);
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+// drops cards smaller than min_width x min_height from the deck and its
+// metadata, returning how many were dropped
+pub fn exclude_low_resolution(
+    deck: &mut Vec<String>,
+    meta: &mut Vec<CardMeta>,
+    min_width: u32,
+    min_height: u32,
+) -> usize {
+    let before = deck.len();
+    meta.retain(|card| {
+        let keep = card.width >= min_width && card.height >= min_height;
+        if !keep {
+            info!(
+                "Excluding {}: {}x{} is below the minimum of {}x{}",
+                card.filename, card.width, card.height, min_width, min_height
+            );
+        }
+        keep
+    });
+    // cards whose dimensions couldn't be read aren't known to be big enough
+    deck.retain(|filename| meta.iter().any(|card| &card.filename == filename));
+    before - deck.len()
+}
+
+// the cards in the deck drawn in the given orientation
+pub fn with_orientation(
+    deck: &[String],
+    meta: &[CardMeta],
+    orientation: Orientation,
+) -> Vec<String> {
+    deck.iter()
+        .filter(|filename| {
+            let card_orientation = meta
+                .iter()
+                .find(|card| &card.filename == *filename)
+                .map(|card| card.orientation)
+                .unwrap_or_default();
+            card_orientation == orientation
+        })
+        .cloned()
+        .collect()
+}
+
+pub const MAX_CUSTOM_DECK_LEN: usize = 500;
+const MAX_CARD_URL_LEN: usize = 2048;
+
+// uploaded decks are lists of image urls for clients to load directly
+pub fn validate_card_urls(cards: &[String]) -> Result<(), &'static str> {
+    if cards.len() > MAX_CUSTOM_DECK_LEN {
+        return Err("Too many cards in the deck");
+    }
+
+    for card in cards {
+        if card.len() > MAX_CARD_URL_LEN {
+            return Err("Card url is too long");
+        }
+        if !card.starts_with("https://") && !card.starts_with("http://") {
+            return Err("Cards must be http or https urls");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn card_back_exists(filename: &str) -> bool {
+    // a bare file name only, so nothing outside the directory can be probed
+    Path::new(filename).file_name() == Some(filename.as_ref())
+        && Path::new(CARD_BACKS_DIR).join(filename).is_file()
+}
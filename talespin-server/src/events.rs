@@ -0,0 +1,85 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::room::{RoundSummary, Standing};
+
+// events waiting for the slowest subscriber before the oldest are dropped
+const EVENT_QUEUE_LEN: usize = 256;
+const WEBHOOK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// room lifecycle, published server-wide for integrations
+#[derive(Debug, Clone, Serialize)]
+pub enum Event {
+    RoomCreated {
+        room_id: String,
+    },
+    GameStarted {
+        room_id: String,
+        game_id: Option<String>,
+        players: Vec<String>,
+    },
+    RoundCompleted {
+        room_id: String,
+        game_id: Option<String>,
+        summary: RoundSummary,
+    },
+    GameOver {
+        room_id: String,
+        game_id: Option<String>,
+        // highest score first
+        standings: Vec<Standing>,
+    },
+    RoomClosed {
+        room_id: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookBody<'a> {
+    event: &'a Event,
+    at_s: u64,
+}
+
+pub fn channel() -> broadcast::Sender<Event> {
+    broadcast::channel(EVENT_QUEUE_LEN).0
+}
+
+// sending only fails when nobody is subscribed, which is fine
+pub fn publish(events: &broadcast::Sender<Event>, event: Event) {
+    let _ = events.send(event);
+}
+
+// POSTs every event to the url as json, one at a time and in order
+pub async fn dispatch_webhooks(mut rx: broadcast::Receiver<Event>, url: String) {
+    let client = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Webhooks disabled, could not build client: {:?}", e);
+            return;
+        }
+    };
+
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Webhook fell behind, skipped {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let body = WebhookBody {
+            event: &event,
+            at_s: crate::room::get_time_s(),
+        };
+        match client.post(&url).json(&body).send().await {
+            Ok(res) if !res.status().is_success() => {
+                warn!("Webhook returned {} for {:?}", res.status(), event);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Webhook failed for {:?}: {:?}", event, e),
+        }
+    }
+}
@@ -1,71 +1,463 @@
 use anyhow::{anyhow, Result};
 use axum::{
+    body::Bytes,
     extract::{
         ws::{Message as WsMessage, WebSocket},
-        Json, State, WebSocketUpgrade,
+        ConnectInfo, Json, Path, Query, State, WebSocketUpgrade,
     },
-    http::{header, Method},
-    response::IntoResponse,
+    http::{header, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
 use dashmap::DashMap;
-use std::{collections::HashMap, fs, net::SocketAddr, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::IntoFuture,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
 use tower_http::{
     cors::{Any, CorsLayer},
     trace::TraceLayer,
 };
+use tracing::{info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 
+mod clues;
+mod config;
+mod deck;
+mod events;
+mod name;
+mod ratelimit;
 mod room;
+mod room_id;
+mod snapshot;
 
-use rand::distributions::{Distribution, Uniform};
-use room::{get_time_s, Room, ServerMsg};
+use config::RoomConfig;
+use room::{get_time_s, Room, RoomStats, ServerMsg};
 
 const GARBAGE_COLLECT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 20); // 20 minutes
 const GC_ROOM_TIMEOUT_S: u64 = 60 * 60; // 1 hour
+const DEFAULT_PORT: u16 = 8081;
+const ABANDONED_ROOM_TIMEOUT_S: u64 = 60 * 15;
+const DEFAULT_DRAIN_TIMEOUT_S: u64 = 60 * 30;
+const DRAIN_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+// time for the shutdown notice to reach clients before the process exits
+const SHUTDOWN_FLUSH: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_EXISTS_BATCH: usize = 50;
+const MAX_MY_ROOMS: usize = 10;
+const DIRECTOR_TOKEN_HEADER: &str = "x-director-token";
+const MAX_CUSTOM_DECKS: usize = 1000;
+// rooms keep their own copy, so this only limits how long a deck can be used for new rooms
+const CUSTOM_DECK_TTL_S: u64 = 60 * 60 * 24;
 
 // main object for server
 #[derive(Debug, Clone)]
 struct ServerState {
     rooms: DashMap<String, Arc<Room>>,
-    base_deck: Arc<Vec<String>>,
+    // uploaded decks by id
+    custom_decks: DashMap<String, CustomDeck>,
+    // read at startup and again on /admin/cards/reload; rooms take a snapshot
+    // when created, so a reload never changes a room that already exists
+    cards: Arc<RwLock<Cards>>,
+    // longest allowed player name, in graphemes
+    max_name_len: usize,
+    // shape of the ids given to new rooms
+    room_ids: room_id::RoomIdFormat,
+    // join links are this followed by the room id
+    join_url_base: String,
+    create_limiter: Arc<Mutex<ratelimit::CreateLimiter>>,
+    // bearer token for the /admin endpoints, which are disabled if unset
+    admin_token: Option<String>,
+    // rooms are left alone while an operator has gc paused
+    gc_paused: Arc<AtomicBool>,
+    // set on SIGTERM; only games already underway are served from then on
+    draining: Arc<AtomicBool>,
+    // longest to wait for games to finish before exiting anyway
+    drain_timeout_s: u64,
+    // since startup, for /metrics
+    rooms_created: Arc<AtomicU64>,
+    rooms_reclaimed: Arc<AtomicU64>,
+    // room lifecycle events for integrations
+    events: tokio::sync::broadcast::Sender<events::Event>,
+    // rooms are saved here and restored from it on startup; off if unset
+    snapshot_path: Option<PathBuf>,
+    // false until a deck has been loaded; the server runs without one so it can
+    // say so on /ready, and a card reload can fix it
+    cards_loaded: Arc<AtomicBool>,
+    started_at: std::time::Instant,
+}
+
+#[derive(Debug, Serialize)]
+struct GcState {
+    paused: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+    rooms: usize,
+    uptime_s: u64,
+}
+
+// what a client can present when joining besides its name
+struct JoinOptions {
+    director: Option<String>,
+    session: Option<String>,
+    password: Option<String>,
+    encoding: room::Encoding,
+}
+
+#[derive(Debug, Clone)]
+struct CustomDeck {
+    cards: Arc<Vec<String>>,
+    created_at_s: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Cards {
+    // theme name to its deck
+    decks: HashMap<String, Arc<Vec<String>>>,
+    // the theme rooms play with when they don't pick one
+    default_theme: String,
+    // image dimensions of every theme's cards
+    card_meta: Arc<Vec<deck::CardMeta>>,
+}
+
+impl Cards {
+    fn load() -> Result<Self> {
+        // optional, e.g. CARDS_DIR=/srv/cards/
+        let cards_dir = std::env::var("CARDS_DIR").unwrap_or_else(|_| deck::CARDS_DIR.to_string());
+
+        // optional, e.g. MIN_CARD_RESOLUTION=512x768
+        let min_resolution = std::env::var("MIN_CARD_RESOLUTION")
+            .ok()
+            .and_then(|v| parse_resolution(&v));
+
+        let mut decks = HashMap::new();
+        let mut card_meta = Vec::new();
+        for (theme, mut deck) in deck::load_themes(&cards_dir)? {
+            let mut meta = deck::load_card_meta(&cards_dir, &deck);
+            if let Some((min_width, min_height)) = min_resolution {
+                let excluded =
+                    deck::exclude_low_resolution(&mut deck, &mut meta, min_width, min_height);
+                info!(
+                    "Excluded {} {} cards below {}x{}",
+                    excluded, theme, min_width, min_height
+                );
+            }
+
+            info!(
+                "Loaded {} {} cards ({} with dimensions)",
+                deck.len(),
+                theme,
+                meta.len()
+            );
+            card_meta.extend(meta);
+            decks.insert(theme, Arc::new(deck));
+        }
+
+        // optional, otherwise the cards outside any theme, or failing that the
+        // first theme by name
+        let default_theme = match std::env::var("DEFAULT_DECK_THEME").ok() {
+            Some(theme) if decks.contains_key(&theme) => theme,
+            Some(theme) => return Err(anyhow!("Default deck theme {:?} has no cards", theme)),
+            None if decks.contains_key(deck::DEFAULT_THEME) => deck::DEFAULT_THEME.to_string(),
+            None => decks
+                .keys()
+                .min()
+                .cloned()
+                .ok_or_else(|| anyhow!("No cards in {}", cards_dir))?,
+        };
+
+        Ok(Cards {
+            decks,
+            default_theme,
+            card_meta: Arc::new(card_meta),
+        })
+    }
+
+    fn card_count(&self) -> usize {
+        self.decks.values().map(|deck| deck.len()).sum()
+    }
 }
 
 impl ServerState {
     fn new() -> Result<Self> {
-        // read cards and form array of file names, any file is ok
-        let base_deck: Vec<String> = fs::read_dir("../static/assets/cards/")?
-            .map(|res| res.map(|e| e.file_name().into_string().unwrap()))
-            .map(|res| res.unwrap())
-            .filter(|s| s.ends_with(".jpg") || s.ends_with(".jpeg") || s.ends_with(".png"))
-            .collect();
+        let cards = Cards::load();
+        if let Err(e) = &cards {
+            warn!(
+                "Could not load cards, not ready until they are reloaded: {:?}",
+                e
+            );
+        }
+        let cards_loaded = Arc::new(AtomicBool::new(cards.is_ok()));
+        let cards = cards.unwrap_or_default();
+
+        let max_name_len = std::env::var("MAX_NAME_LEN")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(name::DEFAULT_MAX_NAME_LEN);
+
+        let join_url_base = std::env::var("JOIN_URL_BASE")
+            .unwrap_or_else(|_| "https://talespin.live/game/".to_string());
+
+        let drain_timeout_s = std::env::var("DRAIN_TIMEOUT_S")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_DRAIN_TIMEOUT_S);
+
+        // off unless set
+        let create_cooldown_s = std::env::var("CREATE_COOLDOWN_S")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
 
-        println!("Loaded {} cards", base_deck.len());
+        let admin_token = std::env::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+
+        let snapshot_path = std::env::var("SNAPSHOT_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+
+        let events = events::channel();
+        let rooms = DashMap::new();
+        if let Some(path) = &snapshot_path {
+            for (room_id, room) in snapshot::load(path, &events, max_name_len) {
+                tokio::spawn({
+                    let room = room.clone();
+                    async move { room.resume().await }
+                });
+                rooms.insert(room_id, room);
+            }
+        }
 
         Ok(ServerState {
-            rooms: DashMap::new(),
-            base_deck: Arc::new(base_deck),
+            rooms,
+            custom_decks: DashMap::new(),
+            cards: Arc::new(RwLock::new(cards)),
+            max_name_len,
+            room_ids: room_id::RoomIdFormat::from_env(),
+            join_url_base,
+            create_limiter: Arc::new(Mutex::new(ratelimit::CreateLimiter::new(
+                std::time::Duration::from_secs(create_cooldown_s),
+            ))),
+            admin_token,
+            gc_paused: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(AtomicBool::new(false)),
+            drain_timeout_s,
+            rooms_created: Arc::new(AtomicU64::new(0)),
+            rooms_reclaimed: Arc::new(AtomicU64::new(0)),
+            events,
+            snapshot_path,
+            cards_loaded,
+            started_at: std::time::Instant::now(),
         })
     }
 
-    async fn create_room(&self) -> Result<ServerMsg> {
-        let mut room_id = generate_room_id(4);
-
-        // println!("create room: 0");
-        while (self.get_room(&room_id)).is_some() {
-            room_id = generate_room_id(4);
+    // also returns the director token, if the room has a director
+    async fn create_room(
+        &self,
+        config: RoomConfig,
+        deck: Arc<Vec<String>>,
+    ) -> Result<(String, ServerMsg, Option<String>), Box<ServerMsg>> {
+        // the id is claimed and the room inserted in one step, so rooms created
+        // at the same time can't end up sharing an id
+        let mut created = None;
+        for _ in 0..room_id::MAX_ATTEMPTS {
+            let room_id = self.room_ids.generate();
+            if let dashmap::mapref::entry::Entry::Vacant(entry) = self.rooms.entry(room_id.clone())
+            {
+                let room = Room::new(
+                    &room_id,
+                    deck,
+                    config,
+                    self.events.clone(),
+                    self.max_name_len,
+                );
+                entry.insert(room.clone());
+                created = Some((room_id, room));
+                break;
+            }
         }
+        let Some((room_id, room)) = created else {
+            warn!("No free room id after {} tries", room_id::MAX_ATTEMPTS);
+            return Err(Box::new(ServerMsg::error(
+                room::ErrorCode::ServerFull,
+                "The server is full, try again later",
+            )));
+        };
 
-        let room = Room::new(&room_id, self.base_deck.clone());
         let msg = room.get_room_state().await;
-        self.rooms.insert(room_id.clone(), Arc::new(room));
-        Ok(msg)
+        let director_token = room.director_token().map(str::to_string);
+        self.rooms_created.fetch_add(1, Ordering::Relaxed);
+        events::publish(
+            &self.events,
+            events::Event::RoomCreated {
+                room_id: room_id.clone(),
+            },
+        );
+        Ok((room_id, msg, director_token))
+    }
+
+    // the deck a room with this config plays with, or why it can't be created
+    fn room_deck(&self, config: &RoomConfig) -> Result<Arc<Vec<String>>, Box<ServerMsg>> {
+        if !self.cards_loaded.load(Ordering::Relaxed) {
+            return Err(Box::new(ServerMsg::error(
+                room::ErrorCode::NotReady,
+                "Server is still starting, try again soon",
+            )));
+        }
+
+        config
+            .validate()
+            .map_err(|e| Box::new(ServerMsg::error(room::ErrorCode::InvalidConfig, e)))?;
+
+        let cards = self.cards();
+        let theme = config.deck_theme.as_deref().unwrap_or(&cards.default_theme);
+        let Some(theme_deck) = cards.decks.get(theme).cloned() else {
+            return Err(Box::new(ServerMsg::error(
+                room::ErrorCode::UnknownDeck,
+                "Unknown deck theme",
+            )));
+        };
+
+        let deck = match (&config.deck_id, config.orientation) {
+            (Some(_), _) if config.deck_theme.is_some() => {
+                return Err(Box::new(ServerMsg::error(
+                    room::ErrorCode::InvalidConfig,
+                    "A theme can't be chosen for a custom deck",
+                )));
+            }
+            (Some(_), Some(_)) => {
+                return Err(Box::new(ServerMsg::error(
+                    room::ErrorCode::InvalidConfig,
+                    "Orientation can't be chosen for a custom deck",
+                )));
+            }
+            (Some(deck_id), None) => {
+                let Some(deck) = self
+                    .custom_decks
+                    .get(deck_id)
+                    .map(|deck| deck.cards.clone())
+                else {
+                    return Err(Box::new(ServerMsg::error(
+                        room::ErrorCode::UnknownDeck,
+                        "Unknown deck",
+                    )));
+                };
+                // a full room plus one more round before the deck is reshuffled
+                let needed = config.cards_for_full_room() + config.max_players();
+                if deck.len() < needed {
+                    return Err(Box::new(ServerMsg::error(
+                        room::ErrorCode::DeckTooSmall,
+                        &format!(
+                            "This deck has {} cards, a room like this needs at least {}",
+                            deck.len(),
+                            needed
+                        ),
+                    )));
+                }
+                deck
+            }
+            (None, Some(orientation)) => {
+                let deck = deck::with_orientation(&theme_deck, &cards.card_meta, orientation);
+                if deck.len() < config.cards_for_full_room() {
+                    return Err(Box::new(ServerMsg::error(
+                        room::ErrorCode::DeckTooSmall,
+                        "Not enough cards in that orientation",
+                    )));
+                }
+                Arc::new(deck)
+            }
+            (None, None) => theme_deck,
+        };
+
+        if config.pool_size.is_some_and(|n| n > deck.len()) {
+            return Err(Box::new(ServerMsg::error(
+                room::ErrorCode::InvalidConfig,
+                "Card pool is larger than the deck",
+            )));
+        }
+
+        Ok(deck)
     }
 
-    async fn join_room(&self, room_id: &str, socket: &mut WebSocket, name: &str) -> Result<()> {
+    async fn join_room(
+        &self,
+        room_id: &str,
+        socket: &mut WebSocket,
+        name: &str,
+        spectator: bool,
+        capabilities: Vec<room::Capability>,
+        options: JoinOptions,
+    ) -> Result<()> {
         // hold no reference to inside the dashmap to prevent deadlock
         if let Some(room) = self.get_room(room_id) {
-            room.on_connection(socket, name).await;
+            // while draining, players may still rejoin a game that is underway
+            if self.draining.load(Ordering::Relaxed) && !room.in_progress().await {
+                socket
+                    .send(
+                        ServerMsg::error(
+                            room::ErrorCode::ServerRestarting,
+                            "Server is restarting, try again soon",
+                        )
+                        .into(),
+                    )
+                    .await?;
+                return Ok(());
+            }
+
+            let director = match options.director {
+                Some(token) if room.director_token() == Some(token.as_str()) => true,
+                Some(_) => {
+                    socket
+                        .send(
+                            ServerMsg::error(
+                                room::ErrorCode::InvalidDirectorToken,
+                                "Invalid director token",
+                            )
+                            .into(),
+                        )
+                        .await?;
+                    return Ok(());
+                }
+                None => false,
+            };
+
+            // directors watch like spectators
+            let span = info_span!("room", room_id = %room_id, player = %name);
+            if spectator || director {
+                room.on_spectator(
+                    socket,
+                    name,
+                    capabilities,
+                    director,
+                    options.password,
+                    options.encoding,
+                )
+                .instrument(span)
+                .await;
+            } else {
+                room.on_connection(
+                    socket,
+                    name,
+                    capabilities,
+                    options.session,
+                    options.password,
+                    options.encoding,
+                )
+                .instrument(span)
+                .await;
+            }
         } else {
             socket.send(ServerMsg::InvalidRoomId {}.into()).await?;
             return Ok(());
@@ -74,158 +466,1008 @@ impl ServerState {
         Ok(())
     }
 
+    async fn save_snapshot(&self, path: &std::path::Path) {
+        // collect first so no dashmap reference is held across an await
+        let rooms: Vec<(String, Arc<Room>)> = self
+            .rooms
+            .iter()
+            .map(|r| (r.key().clone(), r.value().clone()))
+            .collect();
+        if let Err(e) = snapshot::save(path, rooms).await {
+            warn!("Could not save snapshot to {}: {:?}", path.display(), e);
+        }
+    }
+
+    async fn games_in_progress(&self) -> usize {
+        let rooms: Vec<Arc<Room>> = self.rooms.iter().map(|r| r.value().clone()).collect();
+
+        let mut count = 0;
+        for room in rooms {
+            if room.in_progress().await {
+                count += 1;
+            }
+        }
+        count
+    }
+
     fn get_room(&self, room_id: &str) -> Option<Arc<Room>> {
         self.rooms.get(room_id).map(|r| r.value().clone())
     }
 
-    fn stats(&self) -> HashMap<String, (usize, u64)> {
-        self.rooms
+    async fn stats(&self) -> HashMap<String, RoomStats> {
+        // collect first so no dashmap reference is held across an await
+        let rooms: Vec<(String, Arc<Room>)> = self
+            .rooms
             .iter()
-            .map(|r| {
-                (
-                    r.key().clone(),
-                    (r.value().num_active(), r.value().last_access()),
-                )
-            })
-            .collect()
+            .map(|r| (r.key().clone(), r.value().clone()))
+            .collect();
+
+        let mut stats = HashMap::new();
+        for (room_id, room) in rooms {
+            stats.insert(room_id, room.stats().await);
+        }
+        stats
+    }
+
+    // a snapshot; a reload swaps in new cards without touching this one
+    fn cards(&self) -> Cards {
+        self.cards.read().unwrap().clone()
+    }
+
+    fn is_admin(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.admin_token else {
+            return false;
+        };
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            == Some(token.as_str())
     }
 
     fn garbage_collect(&self) {
         let mut to_remove = Vec::new();
         for entry in &self.rooms {
-            // hasn't been accessed in an hour
+            // hasn't been accessed in an hour, or the room's own timeout; a game
+            // everyone walked out of goes sooner
+            let mut timeout = entry.value().idle_timeout_s().unwrap_or(GC_ROOM_TIMEOUT_S);
+            if entry.value().abandoned_at_s().is_some() {
+                timeout = timeout.min(ABANDONED_ROOM_TIMEOUT_S);
+            }
             if entry.value().num_active() == 0
-                && get_time_s() - entry.value().last_access() > GC_ROOM_TIMEOUT_S
+                && get_time_s() - entry.value().last_access() > timeout
             {
                 to_remove.push(entry.key().clone());
             }
         }
 
-        println!("(gc) rooms to delete {:?}", to_remove);
+        info!("(gc) rooms to delete {:?}", to_remove);
         for room_id in to_remove {
-            self.rooms.remove(&room_id);
+            if self.rooms.remove(&room_id).is_some() {
+                self.rooms_reclaimed.fetch_add(1, Ordering::Relaxed);
+            }
+            events::publish(&self.events, events::Event::RoomClosed { room_id });
         }
+
+        // rooms in the middle of something are trimmed on a later pass
+        for entry in &self.rooms {
+            if let Some(bytes) = entry.value().trim() {
+                info!("(gc) room {} is ~{} bytes", entry.key(), bytes);
+            }
+        }
+
+        let now = get_time_s();
+        self.custom_decks
+            .retain(|_, deck| now - deck.created_at_s < CUSTOM_DECK_TTL_S);
+
+        self.create_limiter.lock().unwrap().prune();
     }
 }
 
 async fn garbage_collect(state: Arc<ServerState>) {
     loop {
         tokio::time::sleep(GARBAGE_COLLECT_INTERVAL).await;
+        if state.gc_paused.load(Ordering::Relaxed) {
+            info!("(gc) paused, skipping");
+            continue;
+        }
         state.garbage_collect();
     }
 }
 
-fn generate_room_id(length: usize) -> String {
-    let mut rng = rand::thread_rng();
-    let letters = Uniform::new_inclusive(b'a', b'z'); // Range of lowercase letters
-    (0..length)
-        .map(|_| letters.sample(&mut rng) as char)
-        .collect()
+async fn snapshot_rooms(state: Arc<ServerState>, path: PathBuf) {
+    loop {
+        tokio::time::sleep(snapshot::SNAPSHOT_INTERVAL).await;
+        state.save_snapshot(&path).await;
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+    let mut terminate =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate.recv() => {},
+    }
+}
+
+// stops new rooms and joins, waits for running games to be over, then tells
+// everyone still connected that the server is going away
+async fn drain(state: Arc<ServerState>) {
+    wait_for_games(&state).await;
+
+    for room in state.rooms.iter() {
+        room.value().notify_shutdown();
+    }
+    // anything still running is picked back up by the next process
+    if let Some(path) = &state.snapshot_path {
+        state.save_snapshot(path).await;
+    }
+    tokio::time::sleep(SHUTDOWN_FLUSH).await;
+}
+
+async fn wait_for_games(state: &ServerState) {
+    shutdown_signal().await;
+    state.draining.store(true, Ordering::Relaxed);
+    info!(
+        "Draining, waiting up to {}s for games to finish",
+        state.drain_timeout_s
+    );
+
+    let deadline = get_time_s() + state.drain_timeout_s;
+    loop {
+        let remaining = state.games_in_progress().await;
+        if remaining == 0 {
+            info!("All games finished, shutting down");
+            return;
+        }
+        if get_time_s() >= deadline {
+            info!(
+                "Drain deadline passed with {} games running, shutting down",
+                remaining
+            );
+            return;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+fn parse_resolution(s: &str) -> Option<(u32, u32)> {
+    let (width, height) = s.split_once('x')?;
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+fn invalid_room_id() -> Response {
+    let msg = ServerMsg::error(room::ErrorCode::InvalidRoomId, "Invalid room code");
+    (
+        StatusCode::BAD_REQUEST,
+        serde_json::to_string(&msg).unwrap(),
+    )
+        .into_response()
 }
 
 #[tokio::main]
 async fn main() {
+    // RUST_LOG picks what is logged, e.g. RUST_LOG=talespin_server=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
     let state = Arc::new(ServerState::new().unwrap());
 
     tokio::spawn(garbage_collect(state.clone()));
 
-    // tracing_subscriber::fmt()
-    //     .with_max_level(tracing::Level::DEBUG)
-    //     .init();
+    if let Some(path) = state.snapshot_path.clone() {
+        info!("Saving rooms to {}", path.display());
+        tokio::spawn(snapshot_rooms(state.clone(), path));
+    }
+
+    // off unless set
+    if let Some(url) = std::env::var("WEBHOOK_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+    {
+        info!("Posting room events to {}", url);
+        tokio::spawn(events::dispatch_webhooks(state.events.subscribe(), url));
+    }
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods([Method::GET, Method::POST])
-        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE]);
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+        .expose_headers([
+            header::HeaderName::from_static(DIRECTOR_TOKEN_HEADER),
+            header::RETRY_AFTER,
+        ]);
 
     let app = Router::new()
         .route("/ws", get(ws_handler))
         .route("/create", post(create_room_handler))
         .route("/exists", post(exists_handler))
+        .route("/upload_deck", post(upload_deck_handler))
+        .route("/exists-batch", post(exists_batch_handler))
+        .route("/my-rooms", post(my_rooms_handler))
+        .route("/rooms", get(rooms_handler))
         .route("/stats", get(stats_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/room/:room_id/qr", get(qr_handler))
+        .route("/room/:room_id/summary", get(summary_handler))
+        .route("/deck_manifest/:room_id", get(deck_manifest_handler))
+        .route("/cards/meta", get(card_meta_handler))
+        .route("/decks", get(decks_handler))
+        .route("/admin/gc/pause", post(gc_pause_handler))
+        .route("/admin/gc/resume", post(gc_resume_handler))
+        .route("/admin/card-stats", get(card_stats_handler))
+        .route("/admin/reports", get(reports_handler))
+        .route("/admin/cards/reload", post(reload_cards_handler))
+        .route("/admin/rooms/:room_id/rename", post(rename_room_handler))
+        .route("/health", get(health_handler))
+        .route("/ready", get(ready_handler))
         .route("/", get(root))
         .layer(TraceLayer::new_for_http())
         .layer(cors)
-        .with_state(state);
+        .with_state(state.clone());
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8081").await.unwrap();
-    println!("Listening on {}", listener.local_addr().unwrap());
-    axum::serve(
+    // 0 picks any free port; the one chosen is logged below
+    let port: u16 = std::env::var("PORT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_PORT);
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .unwrap();
+    info!("Listening on {}", listener.local_addr().unwrap());
+    let server = axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .await
-    .unwrap();
+    .into_future();
+
+    // open websockets would hold up a graceful shutdown, so exit once drained
+    tokio::select! {
+        res = server => res.unwrap(),
+        _ = drain(state) => {},
+    }
 }
 
-async fn create_room_handler(State(state): State<Arc<ServerState>>) -> String {
-    let room = state.create_room().await;
-    // json response with room id
+async fn create_room_handler(
+    State(state): State<Arc<ServerState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    body: Bytes,
+) -> Response {
+    if state.draining.load(Ordering::Relaxed) {
+        let msg = room::ServerMsg::error(
+            room::ErrorCode::ServerRestarting,
+            "Server is restarting, try again soon",
+        );
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            serde_json::to_string(&msg).unwrap(),
+        )
+            .into_response();
+    }
 
-    if let Ok(room_state) = room {
-        serde_json::to_string(&room_state).unwrap()
+    let create = state.create_limiter.lock().unwrap().try_create(addr.ip());
+    match create {
+        Ok(()) => {}
+        Err(ratelimit::CreateRejection::RateLimited) => {
+            let msg = room::ServerMsg::error(
+                room::ErrorCode::RateLimited,
+                "Too many rooms created, try again later",
+            );
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                serde_json::to_string(&msg).unwrap(),
+            )
+                .into_response();
+        }
+        Err(ratelimit::CreateRejection::Cooldown(remaining)) => {
+            // whole seconds, rounded up so retrying right on time succeeds
+            let retry_after_s = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+            let msg = room::ServerMsg::error(
+                room::ErrorCode::RateLimited,
+                &format!("Wait {}s before creating another room", retry_after_s),
+            );
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after_s.to_string())],
+                serde_json::to_string(&msg).unwrap(),
+            )
+                .into_response();
+        }
+    }
+
+    // an empty body creates a room with the default settings
+    let config = if body.is_empty() {
+        Ok(RoomConfig::default())
     } else {
-        serde_json::to_string(&room::ServerMsg::ErrorMsg(
-            "Failed to create room".to_string(),
+        serde_json::from_slice::<RoomConfig>(&body)
+    };
+
+    let Ok(config) = config else {
+        return serde_json::to_string(&room::ServerMsg::error(
+            room::ErrorCode::InvalidConfig,
+            "Invalid room config",
         ))
         .unwrap()
+        .into_response();
+    };
+
+    let deck = match state.room_deck(&config) {
+        Ok(deck) => deck,
+        Err(msg) => return serde_json::to_string(&msg).unwrap().into_response(),
+    };
+
+    // json response with room id
+    match state.create_room(config, deck).await {
+        Ok((_, room_state, director_token)) => {
+            let body = serde_json::to_string(&room_state).unwrap();
+            // kept out of the body so it can't end up in a broadcast by mistake
+            match director_token {
+                Some(token) => ([(DIRECTOR_TOKEN_HEADER, token)], body).into_response(),
+                None => body.into_response(),
+            }
+        }
+        Err(msg) => serde_json::to_string(&msg).unwrap().into_response(),
     }
 }
 
 async fn exists_handler(
     State(state): State<Arc<ServerState>>,
     Json(room_id): Json<String>,
-) -> &'static str {
+) -> Response {
+    let Some(room_id) = room_id::normalize(&room_id) else {
+        return invalid_room_id();
+    };
     if state.get_room(&room_id).is_some() {
-        "true"
+        "true".into_response()
+    } else {
+        "false".into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct ExistsBatch {
+    room_ids: Vec<String>,
+}
+
+async fn exists_batch_handler(
+    State(state): State<Arc<ServerState>>,
+    Json(batch): Json<ExistsBatch>,
+) -> Response {
+    if batch.room_ids.len() > MAX_EXISTS_BATCH {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("At most {} room ids per request", MAX_EXISTS_BATCH),
+        )
+            .into_response();
+    }
+
+    let exists: HashMap<String, bool> = batch
+        .room_ids
+        .into_iter()
+        .map(|room_id| {
+            // ids that could never have been generated aren't looked up
+            let exists = room_id::normalize(&room_id)
+                .is_some_and(|normalized| state.get_room(&normalized).is_some());
+            (room_id, exists)
+        })
+        .collect();
+
+    serde_json::to_string(&exists).unwrap().into_response()
+}
+
+#[derive(Deserialize)]
+struct MyRooms {
+    name: String,
+}
+
+// rooms the name has a disconnected seat in. names are only unique within a
+// room, so another player who used the same name elsewhere matches too
+async fn my_rooms_handler(
+    State(state): State<Arc<ServerState>>,
+    Json(query): Json<MyRooms>,
+) -> Response {
+    let Ok(name) = name::normalize_name(&query.name, state.max_name_len) else {
+        return (StatusCode::BAD_REQUEST, "Invalid name").into_response();
+    };
+
+    let rooms: Vec<String> = state
+        .rooms
+        .iter()
+        .filter(|r| r.value().can_resume(&name))
+        .map(|r| r.key().clone())
+        .take(MAX_MY_ROOMS)
+        .collect();
+
+    serde_json::to_string(&rooms).unwrap().into_response()
+}
+
+// rooms that opted into the listing and are still in the lobby
+async fn rooms_handler(State(state): State<Arc<ServerState>>) -> String {
+    // nothing new can be joined while draining
+    let rooms: Vec<room::PublicRoom> = if state.draining.load(Ordering::Relaxed) {
+        Vec::new()
     } else {
-        "false"
+        state
+            .rooms
+            .iter()
+            .filter_map(|r| r.value().listing())
+            .collect()
+    };
+    serde_json::to_string(&rooms).unwrap()
+}
+
+// lists live room ids, so it is for admins only
+async fn stats_handler(State(state): State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    if !state.is_admin(&headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    serde_json::to_string(&state.stats().await)
+        .unwrap()
+        .into_response()
+}
+
+// prometheus text format; scrapers authenticate with the admin token
+async fn metrics_handler(State(state): State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    if !state.is_admin(&headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    // totals only cover rooms that still exist
+    let mut frames = room::FrameStats::default();
+    for room in state.rooms.iter() {
+        let room_frames = room.value().frame_stats();
+        frames.frames_in = frames.frames_in.wrapping_add(room_frames.frames_in);
+        frames.frames_out = frames.frames_out.wrapping_add(room_frames.frames_out);
+        frames.bytes_in = frames.bytes_in.wrapping_add(room_frames.bytes_in);
+        frames.bytes_out = frames.bytes_out.wrapping_add(room_frames.bytes_out);
+    }
+
+    // rooms busy right now are left out of the stage and player counts
+    let mut players_connected = 0;
+    let mut rooms_by_stage: HashMap<String, usize> = HashMap::new();
+    for room in state.rooms.iter() {
+        if let Some((stage, connected)) = room.value().stage_and_connected() {
+            players_connected += connected;
+            *rooms_by_stage.entry(format!("{:?}", stage)).or_insert(0) += 1;
+        }
+    }
+    let mut rooms_by_stage: Vec<_> = rooms_by_stage.into_iter().collect();
+    rooms_by_stage.sort();
+    let rooms_by_stage: String = rooms_by_stage
+        .iter()
+        .map(|(stage, count)| format!("talespin_rooms_by_stage{{stage=\"{}\"}} {}\n", stage, count))
+        .collect();
+
+    let mut limiter = state.create_limiter.lock().unwrap();
+    format!(
+        "# TYPE talespin_rooms gauge\n\
+         talespin_rooms {}\n\
+         # TYPE talespin_players_connected gauge\n\
+         talespin_players_connected {}\n\
+         # TYPE talespin_rooms_by_stage gauge\n\
+         {}\
+         # TYPE talespin_rooms_created_total counter\n\
+         talespin_rooms_created_total {}\n\
+         # TYPE talespin_rooms_reclaimed_total counter\n\
+         talespin_rooms_reclaimed_total {}\n\
+         # TYPE talespin_room_create_rate gauge\n\
+         talespin_room_create_rate {}\n\
+         # TYPE talespin_room_creates_rejected_total counter\n\
+         talespin_room_creates_rejected_total {}\n\
+         # TYPE talespin_ws_frames_in_total counter\n\
+         talespin_ws_frames_in_total {}\n\
+         # TYPE talespin_ws_frames_out_total counter\n\
+         talespin_ws_frames_out_total {}\n\
+         # TYPE talespin_ws_bytes_in_total counter\n\
+         talespin_ws_bytes_in_total {}\n\
+         # TYPE talespin_ws_bytes_out_total counter\n\
+         talespin_ws_bytes_out_total {}\n",
+        state.rooms.len(),
+        players_connected,
+        rooms_by_stage,
+        state.rooms_created.load(Ordering::Relaxed),
+        state.rooms_reclaimed.load(Ordering::Relaxed),
+        limiter.create_rate(),
+        limiter.rejected(),
+        frames.frames_in,
+        frames.frames_out,
+        frames.bytes_in,
+        frames.bytes_out,
+    )
+    .into_response()
+}
+
+async fn card_meta_handler(State(state): State<Arc<ServerState>>) -> String {
+    let card_meta = state.cards().card_meta;
+    serde_json::to_string(&*card_meta).unwrap()
+}
+
+#[derive(Serialize)]
+struct Decks {
+    default: String,
+    themes: Vec<String>,
+}
+
+// theme names to pass as deck_theme when creating a room
+async fn decks_handler(State(state): State<Arc<ServerState>>) -> String {
+    let cards = state.cards();
+    let mut themes: Vec<String> = cards.decks.keys().cloned().collect();
+    themes.sort();
+    serde_json::to_string(&Decks {
+        default: cards.default_theme,
+        themes,
+    })
+    .unwrap()
+}
+
+#[derive(Deserialize)]
+struct UploadDeck {
+    // image urls
+    cards: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct UploadedDeck {
+    deck_id: String,
+    cards: usize,
+}
+
+// returns an id to pass as deck_id when creating a room; whether the deck is
+// big enough depends on the room's settings, so that is checked at /create
+async fn upload_deck_handler(
+    State(state): State<Arc<ServerState>>,
+    Json(upload): Json<UploadDeck>,
+) -> Response {
+    let mut cards = upload.cards;
+    // a card listed twice could end up in two hands at once
+    cards.sort();
+    cards.dedup();
+    if let Err(e) = deck::validate_card_urls(&cards) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    if state.custom_decks.len() >= MAX_CUSTOM_DECKS {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many decks, try again later",
+        )
+            .into_response();
+    }
+
+    let deck_id = uuid::Uuid::new_v4().to_string();
+    let count = cards.len();
+    state.custom_decks.insert(
+        deck_id.clone(),
+        CustomDeck {
+            cards: Arc::new(cards),
+            created_at_s: get_time_s(),
+        },
+    );
+    info!("Uploaded deck {} with {} cards", deck_id, count);
+
+    serde_json::to_string(&UploadedDeck {
+        deck_id,
+        cards: count,
+    })
+    .unwrap()
+    .into_response()
+}
+
+// only rooms created afterwards see the new cards; rooms already running keep
+// the deck they were created with
+async fn reload_cards_handler(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+) -> Response {
+    if !state.is_admin(&headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    // scanning the directory is blocking file io
+    let cards = match tokio::task::spawn_blocking(Cards::load).await {
+        Ok(Ok(cards)) => cards,
+        res => {
+            warn!("Failed to reload cards: {:?}", res.map(|r| r.map(|_| ())));
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to reload cards").into_response();
+        }
+    };
+
+    let count = cards.card_count();
+    *state.cards.write().unwrap() = cards;
+    state.cards_loaded.store(true, Ordering::Relaxed);
+    info!("Reloaded cards, {} across every theme", count);
+    count.to_string().into_response()
+}
+
+async fn qr_handler(
+    State(state): State<Arc<ServerState>>,
+    Path(room_id): Path<String>,
+) -> impl IntoResponse {
+    let Some(room_id) = room_id::normalize(&room_id) else {
+        return invalid_room_id();
+    };
+    if state.get_room(&room_id).is_none() {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    }
+
+    let url = format!("{}{}", state.join_url_base, room_id);
+    match qrcode::QrCode::new(url.as_bytes()) {
+        Ok(code) => {
+            let svg = code.render::<qrcode::render::svg::Color>().build();
+            ([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to build QR code").into_response(),
+    }
+}
+
+async fn summary_handler(
+    State(state): State<Arc<ServerState>>,
+    Path(room_id): Path<String>,
+) -> impl IntoResponse {
+    let Some(room_id) = room_id::normalize(&room_id) else {
+        return invalid_room_id();
+    };
+    let Some(room) = state.get_room(&room_id) else {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    };
+
+    match room.summary().await {
+        Some(summary) => (
+            [(header::CONTENT_TYPE, "application/json")],
+            serde_json::to_string(&summary).unwrap(),
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, "Game is not over").into_response(),
+    }
+}
+
+async fn deck_manifest_handler(
+    State(state): State<Arc<ServerState>>,
+    Path(room_id): Path<String>,
+) -> impl IntoResponse {
+    let Some(room_id) = room_id::normalize(&room_id) else {
+        return invalid_room_id();
+    };
+    let Some(room) = state.get_room(&room_id) else {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    };
+
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        serde_json::to_string(&*room.card_pool()).unwrap(),
+    )
+        .into_response()
+}
+
+async fn gc_pause_handler(State(state): State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    set_gc_paused(&state, &headers, true)
+}
+
+async fn gc_resume_handler(State(state): State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    set_gc_paused(&state, &headers, false)
+}
+
+#[derive(Deserialize)]
+struct RenameRoom {
+    new_room_id: String,
+}
+
+// connected clients stay connected and are told the new id to reconnect with
+async fn rename_room_handler(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Json(body): Json<RenameRoom>,
+) -> Response {
+    if !state.is_admin(&headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    let (Some(room_id), Some(new_room_id)) = (
+        room_id::normalize(&room_id),
+        room_id::normalize(&body.new_room_id),
+    ) else {
+        return invalid_room_id();
+    };
+    let Some(room) = state.get_room(&room_id) else {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    };
+
+    // claim the new id before giving up the old one, so the room is always reachable
+    match state.rooms.entry(new_room_id.clone()) {
+        dashmap::mapref::entry::Entry::Occupied(_) => {
+            return (StatusCode::CONFLICT, "Room id already in use").into_response();
+        }
+        dashmap::mapref::entry::Entry::Vacant(entry) => {
+            entry.insert(room.clone());
+        }
+    }
+    state.rooms.remove(&room_id);
+
+    room.migrate(&new_room_id).await;
+    info!("Room {} renamed to {}", room_id, new_room_id);
+
+    new_room_id.into_response()
+}
+
+// reports from every room that still exists; each is also logged when filed
+async fn reports_handler(State(state): State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    if !state.is_admin(&headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
     }
+
+    let rooms: Vec<Arc<Room>> = state.rooms.iter().map(|r| r.value().clone()).collect();
+    let mut reports = Vec::new();
+    for room in rooms {
+        reports.extend(room.reports().await);
+    }
+
+    serde_json::to_string(&reports).unwrap().into_response()
 }
 
-async fn stats_handler(State(state): State<Arc<ServerState>>) -> String {
-    serde_json::to_string(&state.stats()).unwrap()
+// totals over every room that still exists
+async fn card_stats_handler(State(state): State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    if !state.is_admin(&headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    let rooms: Vec<Arc<Room>> = state.rooms.iter().map(|r| r.value().clone()).collect();
+    let mut totals: HashMap<String, room::CardStats> = HashMap::new();
+    for room in rooms {
+        for (card, stats) in room.card_stats().await {
+            let total = totals.entry(card).or_default();
+            total.as_storyteller_card += stats.as_storyteller_card;
+            total.as_decoy += stats.as_decoy;
+            total.votes_fooled += stats.votes_fooled;
+        }
+    }
+
+    serde_json::to_string(&totals).unwrap().into_response()
+}
+
+fn set_gc_paused(state: &ServerState, headers: &HeaderMap, paused: bool) -> Response {
+    if !state.is_admin(headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    if state.gc_paused.swap(paused, Ordering::Relaxed) != paused {
+        info!("(gc) {}", if paused { "paused" } else { "resumed" });
+    }
+
+    serde_json::to_string(&GcState { paused })
+        .unwrap()
+        .into_response()
 }
 
 async fn root() -> &'static str {
     "Hello, world!"
 }
 
+// liveness: the process is up and serving requests
+async fn health_handler(State(state): State<Arc<ServerState>>) -> Response {
+    Json(Health {
+        status: "ok",
+        rooms: state.rooms.len(),
+        uptime_s: state.started_at.elapsed().as_secs(),
+    })
+    .into_response()
+}
+
+// readiness: new rooms can be created here, so send traffic this way
+async fn ready_handler(State(state): State<Arc<ServerState>>) -> Response {
+    if !state.cards_loaded.load(Ordering::Relaxed) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Cards not loaded").into_response();
+    }
+    if state.draining.load(Ordering::Relaxed) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Draining").into_response();
+    }
+    "ready".into_response()
+}
+
+#[derive(Deserialize)]
+struct WsParams {
+    // e.g. /ws?encoding=msgpack
+    #[serde(default)]
+    encoding: room::Encoding,
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<ServerState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<WsParams>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    ws.on_upgrade(move |socket| handle_socket(socket, state, addr, params.encoding))
 }
 
-async fn handle_socket(mut socket: WebSocket, state: Arc<ServerState>) {
-    let res = initialize_socket(&mut socket, state).await;
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: Arc<ServerState>,
+    addr: SocketAddr,
+    encoding: room::Encoding,
+) {
+    let res = initialize_socket(&mut socket, state, addr, encoding).await;
 
     if let Err(e) = res {
-        println!("Error in initialize_socket: {}", e);
+        warn!("Error in initialize_socket: {}", e);
     }
 }
 
-async fn initialize_socket(socket: &mut WebSocket, state: Arc<ServerState>) -> Result<()> {
+async fn initialize_socket(
+    socket: &mut WebSocket,
+    state: Arc<ServerState>,
+    addr: SocketAddr,
+    encoding: room::Encoding,
+) -> Result<()> {
     let msg = socket
         .recv()
         .await
         .ok_or_else(|| anyhow!("Expected initial message from client"))??;
 
-    if let WsMessage::Text(s) = msg {
-        if let Ok(msg) = serde_json::from_str(&s) {
-            if let room::ClientMsg::JoinRoom { room_id, name } = msg {
-                if name.len() > 30 {
-                    socket
-                        .send(room::ServerMsg::ErrorMsg("Name too long".to_string()).into())
-                        .await?;
-                    return Err(anyhow!("Name too long"));
-                }
-                state
-                    .join_room(&room_id.to_lowercase(), socket, &name)
-                    .await?
+    let join = match &msg {
+        WsMessage::Text(s) => serde_json::from_str(s).ok(),
+        _ => None,
+    };
+
+    if let Some(room::ClientMsg::CreateRoom {
+        name,
+        config,
+        capabilities,
+    }) = join
+    {
+        return create_and_join(socket, state, addr, &name, *config, capabilities, encoding).await;
+    }
+
+    // anything other than a complete JoinRoom gets an answer instead of a silent hang
+    let Some(room::ClientMsg::JoinRoom {
+        room_id,
+        name,
+        spectator,
+        capabilities,
+        director_token,
+        token,
+        password,
+    }) = join
+    else {
+        let message = "Expected a JoinRoom message with a room_id and name, or a CreateRoom";
+        socket
+            .send(ServerMsg::error(room::ErrorCode::MalformedJoin, message).into())
+            .await?;
+        socket.send(WsMessage::Close(None)).await?;
+        return Err(anyhow!("Malformed join message: {:?}", msg));
+    };
+
+    let Some(room_id) = room_id::normalize(&room_id) else {
+        let message = "Invalid room code";
+        socket
+            .send(ServerMsg::error(room::ErrorCode::InvalidRoomId, message).into())
+            .await?;
+        return Err(anyhow!("Invalid room id {:?}", room_id));
+    };
+
+    let name = match name::normalize_name(&name, state.max_name_len) {
+        Ok(name) => name,
+        Err(code) => {
+            let message = format!("Name must be at most {} characters", state.max_name_len);
+            socket.send(ServerMsg::error(code, &message).into()).await?;
+            return Err(anyhow!(message));
+        }
+    };
+    state
+        .join_room(
+            &room_id,
+            socket,
+            &name,
+            spectator,
+            capabilities,
+            JoinOptions {
+                director: director_token,
+                session: token,
+                password,
+                encoding,
+            },
+        )
+        .await?;
+
+    Ok(())
+}
+
+// the same checks as POST /create, then the creator joins on this socket
+async fn create_and_join(
+    socket: &mut WebSocket,
+    state: Arc<ServerState>,
+    addr: SocketAddr,
+    name: &str,
+    config: RoomConfig,
+    capabilities: Vec<room::Capability>,
+    encoding: room::Encoding,
+) -> Result<()> {
+    let name = match name::normalize_name(name, state.max_name_len) {
+        Ok(name) if !name.is_empty() || config.anonymous_names => name,
+        Ok(_) => {
+            let message = "Name cannot be empty";
+            socket
+                .send(ServerMsg::error(room::ErrorCode::EmptyName, message).into())
+                .await?;
+            return Err(anyhow!(message));
+        }
+        Err(code) => {
+            let message = format!("Name must be at most {} characters", state.max_name_len);
+            socket.send(ServerMsg::error(code, &message).into()).await?;
+            return Err(anyhow!(message));
+        }
+    };
+
+    if state.draining.load(Ordering::Relaxed) {
+        let msg = ServerMsg::error(
+            room::ErrorCode::ServerRestarting,
+            "Server is restarting, try again soon",
+        );
+        socket.send(msg.into()).await?;
+        return Ok(());
+    }
+
+    let create = state.create_limiter.lock().unwrap().try_create(addr.ip());
+    if let Err(rejection) = create {
+        let message = match rejection {
+            ratelimit::CreateRejection::RateLimited => {
+                "Too many rooms created, try again later".to_string()
             }
+            ratelimit::CreateRejection::Cooldown(remaining) => format!(
+                "Wait {}s before creating another room",
+                remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)
+            ),
+        };
+        let msg = ServerMsg::error(room::ErrorCode::RateLimited, &message);
+        socket.send(msg.into()).await?;
+        return Ok(());
+    }
+
+    let deck = match state.room_deck(&config) {
+        Ok(deck) => deck,
+        Err(msg) => {
+            socket.send((*msg).into()).await?;
+            return Ok(());
+        }
+    };
+
+    // the room only keeps a hash, so the creator joins with what they set
+    let password = config.password.clone();
+    let (room_id, _, director_token) = match state.create_room(config, deck).await {
+        Ok(created) => created,
+        Err(msg) => {
+            socket.send((*msg).into()).await?;
+            return Ok(());
+        }
+    };
+    let Some(room) = state.get_room(&room_id) else {
+        return Err(anyhow!("Room {} is gone right after creation", room_id));
+    };
+
+    let created = ServerMsg::RoomCreated {
+        room_id: room_id.clone(),
+        director_token,
+    };
+    // nobody else knows the id yet, so a room the creator never got into is dropped
+    let joined = match socket.send(created.into()).await {
+        Ok(()) => {
+            room.on_connection(socket, &name, capabilities, None, password, encoding)
+                .instrument(info_span!("room", room_id = %room_id, player = %name))
+                .await
         }
+        Err(_) => false,
+    };
+    if !joined {
+        info!("Creator of room {} never joined, removing it", room_id);
+        state.rooms.remove(&room_id);
+        events::publish(&state.events, events::Event::RoomClosed { room_id });
     }
 
     Ok(())
@@ -0,0 +1,90 @@
+use rand::seq::SliceRandom;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::room::ErrorCode;
+
+pub const DEFAULT_MAX_NAME_LEN: usize = 30;
+
+// kept short so an assigned name stays well within any sensible length limit
+const ADJECTIVES: &[&str] = &[
+    "Brave", "Calm", "Clever", "Eager", "Fuzzy", "Gentle", "Happy", "Jolly", "Lucky", "Merry",
+    "Nimble", "Proud", "Quick", "Quiet", "Shy", "Silly", "Sleepy", "Swift", "Witty", "Zany",
+];
+const ANIMALS: &[&str] = &[
+    "Badger", "Bear", "Crane", "Deer", "Duck", "Falcon", "Fox", "Gecko", "Heron", "Koala", "Lemur",
+    "Lynx", "Moose", "Otter", "Owl", "Panda", "Puffin", "Seal", "Tiger", "Wolf",
+];
+
+// a friendly name like "BraveOtter" that `taken` says is free; a number is
+// added if every pairing tried is in use
+pub fn random_name(taken: impl Fn(&str) -> bool) -> String {
+    let mut rng = rand::thread_rng();
+    let mut pick = || {
+        format!(
+            "{}{}",
+            ADJECTIVES.choose(&mut rng).unwrap(),
+            ANIMALS.choose(&mut rng).unwrap()
+        )
+    };
+
+    for _ in 0..20 {
+        let name = pick();
+        if !taken(&name) {
+            return name;
+        }
+    }
+
+    let base = pick();
+    (2..)
+        .map(|n| format!("{}{}", base, n))
+        .find(|name| !taken(name))
+        .unwrap() // some number is always free
+}
+
+// trim and validate a player name; length is counted in graphemes so that
+// names in non-latin scripts or with emoji aren't cut short. names are put in
+// NFC so a composed and a decomposed accent are the same player
+pub fn normalize_name(name: &str, max_len: usize) -> Result<String, ErrorCode> {
+    let name: String = name.trim().nfc().collect();
+
+    if name.graphemes(true).count() > max_len {
+        return Err(ErrorCode::NameTooLong);
+    }
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composed_and_decomposed_accents_are_one_name() {
+        let composed = normalize_name("Jos\u{e9}", DEFAULT_MAX_NAME_LEN).unwrap();
+        let decomposed = normalize_name(" Jose\u{301} ", DEFAULT_MAX_NAME_LEN).unwrap();
+        assert_eq!(composed, decomposed);
+    }
+
+    #[test]
+    fn length_is_counted_in_graphemes() {
+        // five graphemes, however many code points the accents take
+        assert!(normalize_name("e\u{301}e\u{301}e\u{301}e\u{301}e\u{301}", 5).is_ok());
+        assert!(normalize_name("👩‍👩‍👧‍👦👩‍👩‍👧‍👦", 2).is_ok());
+        assert_eq!(normalize_name("abcdef", 5), Err(ErrorCode::NameTooLong));
+    }
+
+    #[test]
+    fn lookalikes_from_other_scripts_stay_apart() {
+        // NFC only joins encodings of the same text; a cyrillic "а" is another letter
+        let latin = normalize_name("anna", DEFAULT_MAX_NAME_LEN).unwrap();
+        let cyrillic = normalize_name("\u{430}nn\u{430}", DEFAULT_MAX_NAME_LEN).unwrap();
+        assert_ne!(latin, cyrillic);
+    }
+
+    #[test]
+    fn random_names_avoid_taken_ones() {
+        let name = random_name(|n| !n.ends_with('2'));
+        assert!(name.ends_with('2'));
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// room creations allowed across the whole server
+const GLOBAL_CREATES_PER_S: f64 = 5.0;
+const GLOBAL_CREATE_BURST: f64 = 20.0;
+// room creations allowed from a single ip
+const IP_CREATES_PER_S: f64 = 10.0 / 60.0;
+const IP_CREATE_BURST: f64 = 5.0;
+
+// window the reported create rate is averaged over
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// messages a single websocket connection may send within the window
+const CONNECTION_MSGS_PER_WINDOW: usize = 20;
+const CONNECTION_WINDOW: Duration = Duration::from_secs(5);
+// messages dropped before the connection is closed for good
+const MAX_DROPPED_MSGS: u32 = 50;
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+    per_s: f64,
+    burst: f64,
+}
+
+impl TokenBucket {
+    fn new(per_s: f64, burst: f64) -> Self {
+        TokenBucket {
+            tokens: burst,
+            last: Instant::now(),
+            per_s,
+            burst,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_s).min(self.burst);
+        self.last = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.burst
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreateRejection {
+    RateLimited,
+    // the ip created a room too recently; this is how long until it may again
+    Cooldown(Duration),
+}
+
+// caps POST /create both server-wide and per ip
+#[derive(Debug)]
+pub struct CreateLimiter {
+    global: TokenBucket,
+    per_ip: HashMap<IpAddr, TokenBucket>,
+    // minimum time between two rooms from the same ip
+    cooldown: Duration,
+    last_create: HashMap<IpAddr, Instant>,
+    // when recent rooms were created, for the reported rate
+    recent: VecDeque<Instant>,
+    rejected: u64,
+}
+
+impl CreateLimiter {
+    pub fn new(cooldown: Duration) -> Self {
+        CreateLimiter {
+            global: TokenBucket::new(GLOBAL_CREATES_PER_S, GLOBAL_CREATE_BURST),
+            per_ip: HashMap::new(),
+            cooldown,
+            last_create: HashMap::new(),
+            recent: VecDeque::new(),
+            rejected: 0,
+        }
+    }
+
+    // takes a token from both buckets, or neither if either is empty
+    pub fn try_create(&mut self, ip: IpAddr) -> Result<(), CreateRejection> {
+        let now = Instant::now();
+
+        if let Some(last) = self.last_create.get(&ip) {
+            let since = now.duration_since(*last);
+            if since < self.cooldown {
+                self.rejected += 1;
+                return Err(CreateRejection::Cooldown(self.cooldown - since));
+            }
+        }
+
+        self.global.refill(now);
+        let ip_bucket = self
+            .per_ip
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(IP_CREATES_PER_S, IP_CREATE_BURST));
+        ip_bucket.refill(now);
+
+        if self.global.tokens < 1.0 || ip_bucket.tokens < 1.0 {
+            self.rejected += 1;
+            return Err(CreateRejection::RateLimited);
+        }
+
+        self.global.tokens -= 1.0;
+        ip_bucket.tokens -= 1.0;
+        self.recent.push_back(now);
+        if !self.cooldown.is_zero() {
+            self.last_create.insert(ip, now);
+        }
+        Ok(())
+    }
+
+    // rooms created per second, averaged over the last minute
+    pub fn create_rate(&mut self) -> f64 {
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        self.recent.len() as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    // ips whose bucket has refilled behave the same as new ones, so drop them
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        self.per_ip.retain(|_, bucket| {
+            bucket.refill(now);
+            !bucket.is_full()
+        });
+        let cooldown = self.cooldown;
+        self.last_create
+            .retain(|_, last| now.duration_since(*last) < cooldown);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsgVerdict {
+    Allow,
+    // dropped; the first drop in a row says so to the client
+    Drop { first: bool },
+    Close,
+}
+
+// sliding window over one connection's messages; each connection keeps its own
+#[derive(Debug, Default)]
+pub struct MessageLimiter {
+    recent: VecDeque<Instant>,
+    dropped: u32,
+    dropping: bool,
+}
+
+impl MessageLimiter {
+    pub fn check(&mut self) -> MsgVerdict {
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > CONNECTION_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+
+        if self.recent.len() < CONNECTION_MSGS_PER_WINDOW {
+            self.recent.push_back(now);
+            self.dropping = false;
+            return MsgVerdict::Allow;
+        }
+
+        self.dropped += 1;
+        if self.dropped > MAX_DROPPED_MSGS {
+            return MsgVerdict::Close;
+        }
+        let first = !self.dropping;
+        self.dropping = true;
+        MsgVerdict::Drop { first }
+    }
+}
@@ -1,15 +1,27 @@
 use anyhow::{anyhow, Context, Result};
 use axum::{extract::ws::Message as WsMessage, extract::ws::WebSocket};
-use rand::seq::SliceRandom;
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
     },
 };
 use tokio::sync::{broadcast, mpsc, RwLock, RwLockWriteGuard};
+use tracing::{debug, info, info_span, warn, Instrument};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::clues;
+use crate::config::{
+    Achievement, HandOrder, NoSubmission, ResultsDetail, RoomConfig, StorytellerSelection, Theme,
+    TooFewPlayers, DEFAULT_MAX_SPECTATORS,
+};
+use crate::events::{self, Event};
+use crate::ratelimit::{MessageLimiter, MsgVerdict};
 
 #[derive(Debug, Serialize, Clone)]
 pub enum ServerMsg {
@@ -20,49 +32,518 @@ pub enum ServerMsg {
         active_player: Option<String>,
         player_order: Vec<String>,
         round: u16,
+        win_streaks: HashMap<String, u32>,
+        games_played: u32,
+        shuffle_commitment: Option<String>,
+        // empty unless the room is in team mode
+        teams: HashMap<String, TeamId>,
+        team_scores: HashMap<TeamId, u16>,
+        host: Option<String>,
+        theme: Theme,
+        // points this round are multiplied by this
+        multiplier: u16,
+        // the current round is a practice round
+        warmup: bool,
+        // waiting for players to reconnect before the next round
+        paused: bool,
+        // number of distinct cards the room plays with
+        pool_size: usize,
     },
     StartRound {
         hand: Vec<String>,
+        multiplier: u16,
+        suggested_think_s: Option<u64>,
     },
     PlayersChoose {
         description: String,
         hand: Vec<String>,
+        suggested_think_s: Option<u64>,
+        // the card this player already picked, so a reconnecting client
+        // doesn't offer the pick again; only set on messages sent to a single player
+        already_chosen: Option<String>,
     },
     BeginVoting {
         center_cards: Vec<String>,
         description: String,
+        // the card this player put in the center, which may have been picked
+        // for them; only set on messages sent to a single player
+        already_chosen: Option<String>,
+        // the card this player already voted for; only set on messages sent to
+        // a single player
+        already_voted: Option<String>,
+        suggested_think_s: Option<u64>,
     },
     Results {
         player_to_vote: HashMap<String, String>,
         player_to_current_card: HashMap<String, String>,
+        // empty for a round that was skipped, which has no cards or votes either
         active_card: String,
         point_change: HashMap<String, u16>,
+        team_point_change: HashMap<TeamId, u16>,
+        storyteller_vote: Option<String>,
+        // bonus achievements behind some of the point changes
+        achievements: HashMap<String, Vec<Achievement>>,
+        warmup: bool,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
     },
-    ErrorMsg(String),
     InvalidRoomId {},
-    EndGame {},
+    // answers CreateRoom, before the room's own messages
+    RoomCreated {
+        room_id: String,
+        // only for the creator, like the header POST /create sets
+        director_token: Option<String>,
+    },
+    EndGame {
+        game_id: Option<String>,
+        shuffle_reveal: Option<ShuffleReveal>,
+        standings: Vec<Standing>,
+    },
+    MyStats(PlayerStats),
+    // every finished round of the current game, oldest first
+    History {
+        rounds: Vec<RoundSummary>,
+    },
+    // just the scores, a cheaper resync than the full room state
+    Scoreboard {
+        points: HashMap<String, u16>,
+        player_order: Vec<String>,
+        // None outside of a game
+        active_player: Option<String>,
+    },
+    // only ever sent to spectators, so nothing they say can reach players
+    SpectatorChat {
+        from: String,
+        text: String,
+    },
+    // the server is about to exit; clients should try reconnecting after a while
+    ServerShutdown {},
+    // the room now lives under this id, which any reconnect has to use
+    RoomMigrated {
+        new_room_id: String,
+    },
+    // chat from this person is dropped until this time
+    PlayerMuted {
+        name: String,
+        until_s: u64,
+    },
+    // every room setting; a seed is left out until the game reveals it
+    RoomSettings(RoomConfig),
+    ReadyStatus {
+        ready: Vec<String>,
+        not_ready: Vec<String>,
+    },
+    // cards left to draw, sent at the start of every round
+    DeckRemaining {
+        remaining: usize,
+    },
+    // the name the server picked for a player who joined without one, or
+    // the seat's name when a session token brought them back to it
+    AssignedName {
+        name: String,
+    },
+    // sent to a player on every join; passing it back in JoinRoom reclaims
+    // the seat after a dropped connection, whatever name is asked for
+    SessionToken {
+        token: String,
+    },
+    // lets clients correct for clock skew when rendering deadlines
+    ServerTime {
+        now_s: u64,
+    },
+    // sent to the host while in the lobby so a short deck is caught before the game starts
+    DeckCheck {
+        sufficient: bool,
+        cards: usize,
+        needed_for_full_game: usize,
+        estimated_rounds: usize,
+    },
+    Ack {
+        msg_id: u64,
+    },
+    Pong {},
+    VotingSkipped {
+        reason: String,
+    },
+    // a player didn't come back in time and was taken out of the game
+    SeatFreed {
+        name: String,
+    },
+    VotingProgress {
+        cast: usize,
+        total: usize,
+    },
+    // the deck can't deal another round, so the game ends with these scores;
+    // followed by the usual EndGame
+    GameOver {
+        final_scores: HashMap<String, u16>,
+        // first in the standings; anyone sharing their rank tied with them
+        winner: String,
+        standings: Vec<Standing>,
+    },
+    // only ever sent to the storyteller while voting
+    LiveVote {
+        votes_for_your_card: usize,
+    },
+    // while picking and voting: how many of the players the stage waits on
+    // have acted, out of how many it waits on
+    Progress {
+        ready_count: usize,
+        needed: usize,
+    },
+    // how many players have acted in the current stage out of those expected to
+    StageProgress {
+        submitted: usize,
+        total_expected: usize,
+    },
+    // how spectators voted this round, sent with the results
+    AudiencePoll {
+        card_counts: HashMap<String, u32>,
+    },
+    // the storyteller's clock is running and runs out at this time
+    ClueTimerStarted {
+        ends_at_s: u64,
+    },
+    // the current stage moves on by itself once this runs out
+    StageTimer {
+        remaining_s: u64,
+    },
+    // too few players are connected mid-game; the game either waits or ends
+    TooFewPlayers {
+        connected: usize,
+        paused: bool,
+    },
+    // enough players are back to go on
+    GameResumed {},
+    // who has asked for a rematch so far
+    RematchVotes {
+        votes: Vec<String>,
+        needed: usize,
+    },
+    // sent only to the player; the name they go by from now on
+    Renamed {
+        name: String,
+    },
+    // to everyone already in the room, alongside the new room state
+    PlayerJoined {
+        name: String,
+    },
+    // still_in_game when their seat is kept for them to come back to, false
+    // when they are gone from the room
+    PlayerLeft {
+        name: String,
+        still_in_game: bool,
+    },
+    // the storyteller stayed disconnected while choosing, so the turn moved on
+    StorytellerSkipped {
+        name: String,
+    },
+    StorytellerTimedOut {
+        name: String,
+        // penalized storytellers lose points and sit out a few turns as storyteller
+        penalized: bool,
+    },
+}
+
+// totals for one player across every game played in the room
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PlayerStats {
+    games_played: u32,
+    games_won: u32,
+    total_points: u32,
+    times_storyteller: u32,
+    best_round_points: u16,
+}
+
+// a player's complaint about another, for moderators to follow up on
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Report {
+    room_id: String,
+    reporter: String,
+    target: String,
+    reason: String,
+    at_s: u64,
+}
+
+// how a card has fared in a room, for deck curation
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct CardStats {
+    // played by the storyteller
+    pub as_storyteller_card: u32,
+    // played as a decoy
+    pub as_decoy: u32,
+    // votes it drew while a decoy
+    pub votes_fooled: u32,
+}
+
+// one finished round, kept for the end of game summary
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RoundSummary {
+    round: u16,
+    storyteller: String,
+    description: String,
+    active_card: String,
+    point_change: HashMap<String, u16>,
+    // empty when the room keeps decoys and votes private
+    player_to_current_card: HashMap<String, String>,
+    player_to_vote: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Standing {
+    name: String,
+    points: u16,
+    team: Option<TeamId>,
+    // 1 for the winner; players tied on points and storyteller rounds share a rank
+    rank: usize,
+}
+
+// shareable recap of a finished game
+#[derive(Debug, Serialize)]
+pub struct GameSummary {
+    room_id: String,
+    game_id: Option<String>,
+    // highest score first
+    standings: Vec<Standing>,
+    rounds: Vec<RoundSummary>,
+    stats: HashMap<String, PlayerStats>,
+}
+
+// lets players check the published commitment: sha256(seed + salt)
+#[derive(Debug, Serialize, Clone)]
+pub struct ShuffleReveal {
+    seed: u64,
+    salt: String,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NameTooLong,
+    GameLimitReached,
+    MalformedJoin,
+    SpectatorsFull,
+    DuplicateClue,
+    Internal,
+    RoomBusy,
+    // the join can still be retried as a spectator
+    RoomFull,
+    GameStarted,
+    NameTaken,
+    EmptyName,
+    WrongPassword,
+    Kicked,
+    // the player's seat is already in use by another connection
+    SeatConnected,
+    AlreadyJoined,
+    ServerRestarting,
+    RateLimited,
+    InvalidDirectorToken,
+    // settings that fail validation, at creation or when the host changes them
+    InvalidConfig,
+    UnknownDeck,
+    DeckTooSmall,
+    NotHost,
+    NotEnoughPlayers,
+    BatchTooLarge,
+    SelfVote,
+    // a card that isn't in the player's hand, or isn't in play when voting
+    InvalidCard,
+    // a vote the rules don't allow, other than for your own card
+    InvalidVote,
+    InvalidTeam,
+    TeamsLocked,
+    InvalidHandOrder,
+    UnknownPlayer,
+    ReportRejected,
+    EmptyDescription,
+    ClueTooLong,
+    ClueNotOneWord,
+    // the storyteller tried to change their clue after someone else picked a card
+    ClueLocked,
+    // not the shape of a room code at all, as opposed to one that doesn't exist
+    InvalidRoomId,
+    // the server has no cards to deal yet
+    NotReady,
+    // no unused room id could be found
+    ServerFull,
+}
+
+impl ServerMsg {
+    pub fn error(code: ErrorCode, message: &str) -> Self {
+        ServerMsg::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
 }
 
 impl From<ServerMsg> for WsMessage {
     fn from(msg: ServerMsg) -> Self {
-        // this should never fail
-        let json = serde_json::to_string(&msg).expect("Failed to serialize json");
+        // serialization shouldn't fail, but a panic here would take the whole
+        // connection down, so the client gets an error frame instead
+        let json = serde_json::to_string(&msg).unwrap_or_else(|e| {
+            warn!("Failed to serialize {:?}: {}", msg, e);
+            let fallback = ServerMsg::error(ErrorCode::Internal, "Failed to serialize message");
+            serde_json::to_string(&fallback).unwrap_or_else(|_| {
+                r#"{"Error":{"code":"Internal","message":"Failed to serialize message"}}"#
+                    .to_string()
+            })
+        });
         WsMessage::Text(json)
     }
 }
 
+// how server messages are framed once a client is in a room; anything sent
+// before that is always json, and text frames always hold json
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    // binary frames, with field names kept so messages look the same as in json
+    Msgpack,
+}
+
+impl Encoding {
+    fn frame(self, msg: ServerMsg) -> WsMessage {
+        match self {
+            Encoding::Json => msg.into(),
+            Encoding::Msgpack => match rmp_serde::to_vec_named(&msg) {
+                Ok(bytes) => WsMessage::Binary(bytes),
+                Err(e) => {
+                    warn!("Failed to serialize {:?}: {}", msg, e);
+                    ServerMsg::error(ErrorCode::Internal, "Failed to serialize message").into()
+                }
+            },
+        }
+    }
+}
+
+// a client message with an optional id the server acknowledges once it's handled
+#[derive(Debug, Deserialize)]
+struct ClientEnvelope {
+    #[serde(flatten)]
+    msg: ClientMsg,
+    msg_id: Option<u64>,
+}
+
+// optional protocol features a client asks for when joining
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Acks,
+}
+
 #[derive(Debug, Deserialize)]
 pub enum ClientMsg {
     Ready {},
-    JoinRoom { room_id: String, name: String },
-    CreateRoom { name: String },
-    ActivePlayerChooseCard { card: String, description: String },
-    PlayerChooseCard { card: String },
-    Vote { card: String },
+    Unready {},
+    JoinRoom {
+        room_id: String,
+        name: String,
+        // watch the game without playing
+        #[serde(default)]
+        spectator: bool,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+        // joins as the room's director instead of as a player
+        #[serde(default)]
+        director_token: Option<String>,
+        // from SessionToken, to come back to a seat
+        #[serde(default)]
+        token: Option<String>,
+        // for rooms created with a password
+        #[serde(default)]
+        password: Option<String>,
+    },
+    // creates a room and joins it as a player, in place of POST /create and JoinRoom
+    CreateRoom {
+        name: String,
+        #[serde(default)]
+        config: Box<RoomConfig>,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    // also revises the card and clue, until anyone else has picked a card
+    ActivePlayerChooseCard {
+        card: String,
+        description: String,
+    },
+    PlayerChooseCard {
+        card: String,
+    },
+    Vote {
+        card: String,
+    },
     Ping {},
+    GetMyStats {},
+    GetHistory {},
+    // works for spectators too, in any stage
+    GetScoreboard {},
+    TimeSync {},
+    GetReadyStatus {},
+    // the new round is on screen; see round_start_ack
+    RoundReady {},
+    GetSettings {},
+    // flags another player for moderators; nothing happens in the game
+    Report {
+        target: String,
+        reason: String,
+    },
+    // spectators only; players never see it
+    Chat {
+        text: String,
+    },
+    // host only; removes a player or spectator, who can't come back under that name
+    Kick {
+        name: String,
+    },
+    // host only; silences someone's chat for a while without removing them
+    Mute {
+        name: String,
+        duration_s: u64,
+    },
+    // once the game is over; the host starts a new one right away, anyone else
+    // votes and it starts when every connected player has
+    Rematch {},
+    // host only, in the lobby; fills a seat with a player the server plays for
+    AddBot {},
+    // in the lobby only, since everything from the turn order on is keyed by name
+    Rename {
+        new_name: String,
+    },
+    // host only, in the lobby; replaces every setting that can change after creation
+    UpdateSettings {
+        settings: Box<RoomConfig>,
+    },
+    SetTeam {
+        team: TeamId,
+    },
+    SetHandOrder {
+        order: Vec<String>,
+    },
+    TransferHost {
+        to: String,
+    },
+    RandomClue {},
+    // director only: move past the current stage without waiting on players
+    Advance {},
+    // director only: end voting and show the results now
+    Reveal {},
+    // director only: hold the storyteller's clock
+    PauseTimers {
+        paused: bool,
+    },
+    // handled in order under one lock, stopping at the first error
+    Batch {
+        messages: Vec<ClientMsg>,
+    },
 }
 
-#[derive(Debug, Serialize, Clone, Copy)]
+pub type TeamId = u8;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum RoomStage {
     // waiting for players to join with room code
     Joining,
@@ -76,9 +557,11 @@ pub enum RoomStage {
     Results,
     // game is over
     End,
+    // the deck ran out, so the game ended early with final scores
+    GameOver,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PlayerInfo {
     // player is connected to server
     connected: bool,
@@ -86,9 +569,71 @@ pub struct PlayerInfo {
     points: u16,
     // ready is stage-specific
     ready: bool, // this is round dependent
+    // played by the server; has no socket and acts on its own
+    bot: bool,
+    // from PLAYER_COLORS, kept for as long as the player has a seat
+    color: String,
 }
 
-#[derive(Debug)]
+// a public room in the lobby, as listed by GET /rooms
+#[derive(Debug, Serialize)]
+pub struct PublicRoom {
+    room_id: String,
+    players: usize,
+    max_players: usize,
+    has_password: bool,
+}
+
+// snapshot of a room for the stats endpoint
+#[derive(Debug, Serialize)]
+pub struct RoomStats {
+    num_active: usize,
+    last_access: u64,
+    // the room's lock was held too long to read the fields below
+    busy: bool,
+    stage: Option<RoomStage>,
+    created_at_s: Option<u64>,
+    rounds_played: Option<u32>,
+    num_players: Option<usize>,
+    num_spectators: Option<usize>,
+    // messages waiting in the broadcast channel for the slowest receiver
+    broadcast_queue: usize,
+    // messages dropped because a receiver fell too far behind
+    broadcast_lagged: u64,
+    // connections whose send queue is close to full
+    lagging_connections: usize,
+    frames: FrameStats,
+    connections: Option<HashMap<String, ConnectionStats>>,
+    // rough size of the room's game data, to spot rooms that keep growing
+    approx_bytes: Option<usize>,
+}
+
+// counters wrap around rather than overflow
+#[derive(Debug, Default)]
+struct FrameCounters {
+    frames_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+pub struct FrameStats {
+    pub frames_in: u64,
+    pub frames_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionStats {
+    queue_depth: usize,
+    queue_capacity: usize,
+    lagging: bool,
+}
+
+// snapshots leave out the connections, which can't outlive the process
+#[derive(Debug, Serialize, Deserialize)]
 struct RoomState {
     room_id: String,
     // store general stats about each player
@@ -97,6 +642,8 @@ struct RoomState {
     player_hand: HashMap<String, Vec<String>>,
     // remaining deck; pop from this to players hands
     deck: Vec<String>,
+    // cards played to the center, shuffled back into the deck when it runs low
+    discard: Vec<String>,
     // stage of the game
     stage: RoomStage,
     // round number
@@ -105,9 +652,93 @@ struct RoomState {
     player_order: Vec<String>,
     active_player: usize, // index into player_order
     // map to mpsc which sends messages to specific players
+    #[serde(skip)]
     player_to_socket: HashMap<String, mpsc::Sender<ServerMsg>>,
+    // settings for this room
+    config: RoomConfig,
+    // number of times each card has been dealt in this room
+    card_deal_count: HashMap<String, u32>,
+    // one entry per card that has been played, so bounded by the deck
+    card_stats: HashMap<String, CardStats>,
+    // names the host removed; they can't join again
+    kicked: HashSet<String>,
+    // when each muted name may chat again; kept by name so rejoining doesn't lift it
+    muted_until_s: HashMap<String, u64>,
+    // when each spectator last chatted, in ms
+    last_chat_ms: HashMap<String, u128>,
+    // reports filed in this room, oldest dropped past MAX_REPORTS
+    reports: Vec<Report>,
+    // when each player last filed a report
+    last_report_s: HashMap<String, u64>,
+    // consecutive games won by each player in this room
+    win_streaks: HashMap<String, u32>,
+    // games finished in this room
+    games_played: u32,
+    // rounds dealt in this room, across every game
+    #[serde(default)]
+    rounds_played: u32,
+    #[serde(default)]
+    created_at_s: u64,
+    // per-player totals kept across games
+    player_stats: HashMap<String, PlayerStats>,
+    // seed and rng behind every deck shuffle; the same generator as StdRng,
+    // which can't be saved in a snapshot
+    seed: u64,
+    rng: ChaCha12Rng,
+    // mixed into the published seed commitment so the seed can't be guessed from it
+    shuffle_salt: String,
+    // salted hash of the room password, if it has one
+    password_hash: Option<String>,
+    password_salt: String,
 
     /** Round-specific information */
+    round_state: RoundState,
+
+    // bumped whenever a timer starts so stale timers can tell they were replaced
+    timer_id: u64,
+    // clue timeouts in a row for each storyteller
+    storyteller_timeouts: HashMap<String, u32>,
+    // turns left that a penalized player is passed over as storyteller
+    storyteller_skips: HashMap<String, u32>,
+    // team of each player in team mode; unassigned players are placed when the game starts
+    teams: HashMap<String, TeamId>,
+    // players who have been storyteller since everyone last had a turn
+    storytellers_this_cycle: Vec<String>,
+    // first player to join; passed on if they leave the lobby
+    host: Option<String>,
+    // players who arranged their hand themselves, whose layout is never reshuffled
+    arranged_hands: HashSet<String>,
+    // rounds of the current game, oldest first
+    round_history: Vec<RoundSummary>,
+    // clues given this game, trimmed and lowercased
+    used_clues: HashSet<String>,
+    // when each disconnected player left mid-game
+    disconnected_at: HashMap<String, u64>,
+    // player name to the token that reclaims their seat
+    session_tokens: HashMap<String, String>,
+    // fresh for every game played in the room
+    game_id: Option<String>,
+    // people watching the game; they get broadcasts but are never dealt in
+    #[serde(skip)]
+    spectators: HashMap<String, mpsc::Sender<ServerMsg>>,
+    // spectators who joined with the director token
+    #[serde(skip)]
+    directors: HashSet<String>,
+    // set by the director; the storyteller's clock doesn't run out while set
+    timers_paused: bool,
+    // no new round starts while too few players are connected
+    paused_for_players: bool,
+    // what each connected player or spectator asked for when joining
+    #[serde(skip)]
+    capabilities: HashMap<String, Vec<Capability>>,
+    // players who want to play again once the game is over
+    rematch_votes: HashSet<String>,
+}
+
+// state that only lives for one round; anything added here is reset with the
+// rest of it when the next round starts
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RoundState {
     // chosen description by active player
     current_description: String,
     // for the active player, this is the active card; for other players, this is the card they chose
@@ -115,19 +746,483 @@ struct RoomState {
     // for each player, the card they voted for as being the active's card
     // they cannot vote for themselves
     player_to_vote: HashMap<String, String>,
+    // display-only vote from the storyteller, kept out of scoring
+    storyteller_vote: Option<String>,
+    // when each player cast their vote, in ms; auto-filled votes have none
+    vote_times: HashMap<String, u128>,
+    // the Results message for this round once it has been scored; rebuilt
+    // from the round history when a room is restored
+    #[serde(skip)]
+    results: Option<ServerMsg>,
+    // players who confirmed the round is on screen
+    round_acks: HashSet<String>,
+    // spectator votes for the audience poll, kept apart from player_to_vote
+    audience_votes: HashMap<String, String>,
+    clue_timer_started: bool,
+    // players who didn't pick a card and so take no part in voting or scoring
+    sitting_out: HashSet<String>,
+    // the center cards in the order everyone sees them while voting
+    center_cards: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    state: &'a RoomState,
+    base_deck: &'a [String],
+    director_token: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct RoomSnapshot {
+    state: RoomState,
+    // the room's own deck, which may be a pool or an uploaded deck
+    base_deck: Vec<String>,
+    director_token: Option<String>,
+}
+
+// channels for a player who has just been admitted to the room
+struct Connection {
+    // differs from the requested name when the server picked one
+    name: String,
+    rx: mpsc::Receiver<ServerMsg>,
+    broadcast_updates: broadcast::Receiver<ServerMsg>,
+    initial_msgs: Vec<ServerMsg>,
+    // how far behind the players this connection sees the clue, for spectators
+    spectator_delay: Option<std::time::Duration>,
 }
 
 // main object representing a game
 #[derive(Debug)]
 pub struct Room {
+    // handle to ourselves for spawned timers
+    this: Weak<Room>,
     // store all informationa about the room
     state: RwLock<RoomState>,
-    // send updates to everyone in the room
-    broadcast: broadcast::Sender<ServerMsg>,
+    // send updates to everyone in the room; replaced when the host changes
+    // the room's size, and connections move over to the new one on their own
+    broadcast: std::sync::RwLock<broadcast::Sender<ServerMsg>>,
     // keep pointer to the base deck for refills
     base_deck: Arc<Vec<String>>,
     // last access in seconds
     last_access: AtomicU64,
+    // broadcast messages skipped by lagging receivers
+    broadcast_lagged: AtomicU64,
+    // connections currently flagged as lagging
+    lagging_connections: AtomicUsize,
+    // websocket traffic over the room's lifetime
+    frames: FrameCounters,
+    // copied out of the config so gc can read it without the lock
+    idle_timeout_s: Option<u64>,
+    // latest room state waiting for the coalescing window to close
+    pending_state: Arc<std::sync::Mutex<Option<ServerMsg>>>,
+    // handed to the room's creator; whoever joins with it is the director
+    director_token: Option<String>,
+    // server-wide lifecycle events
+    events: broadcast::Sender<Event>,
+    // longest name allowed, for renames; joins are checked before reaching the room
+    max_name_len: usize,
+    // when the last player left a game underway, 0 while anyone is connected;
+    // the game's clocks stop until someone is back
+    abandoned_at_s: AtomicU64,
+}
+
+const DEFAULT_RECONNECT_WINDOW_S: u64 = 60 * 3;
+const MIN_PLAYERS: usize = 3;
+
+const MAX_BATCH_LEN: usize = 10;
+
+// broadcasts a connection can fall behind by before it lags; a lagging
+// connection loses what it missed and is resynced with the current state, which
+// is correct but costs a full resend, so this is sized so that a round start in
+// a full room (a state update per player readying, then the round itself)
+// never gets there
+const BROADCASTS_PER_PLAYER: usize = 4;
+const MIN_BROADCAST_CAPACITY: usize = 16;
+
+const COMEBACK_BONUS: u16 = 1;
+
+// one per seat in the largest room, so colors only repeat if that limit grows
+const PLAYER_COLORS: &[&str] = &[
+    "#e6194b", "#3cb44b", "#ffe119", "#4363d8", "#f58231", "#911eb4", "#46f0f0", "#f032e6",
+    "#bcf60c", "#fabebe", "#008080", "#9a6324",
+];
+
+// a disconnected storyteller gets this long to come back before their turn is
+// skipped, so a page reload doesn't cost a turn
+const STORYTELLER_GRACE_S: u64 = 15;
+
+// longest the clue timer waits on round acks from slow clients
+const ROUND_ACK_GRACE: std::time::Duration = std::time::Duration::from_secs(10);
+
+// how long bots wait before acting, so their moves don't all land at once
+const BOT_THINK_TIME: std::time::Duration = std::time::Duration::from_secs(2);
+
+// in graphemes
+const MAX_CHAT_LEN: usize = 200;
+const MAX_CLUE_LEN: usize = 100;
+const CHAT_MIN_INTERVAL_MS: u64 = 1000;
+const MAX_MUTE_S: u64 = 60 * 60;
+const MAX_REPORT_REASON_LEN: usize = 500;
+
+const MAX_REPORTS: usize = 100;
+// a player can file one report this often
+const REPORT_INTERVAL_S: u64 = 30;
+
+// per-player queue size and the depth at which a connection counts as lagging
+const PLAYER_CHANNEL_CAPACITY: usize = 10;
+const LAGGING_QUEUE_DEPTH: usize = PLAYER_CHANNEL_CAPACITY * 3 / 4;
+
+// the first frames of a connection get a few quick retries before giving up on it
+const INITIAL_SEND_ATTEMPTS: u32 = 3;
+const INITIAL_SEND_BACKOFF: std::time::Duration = std::time::Duration::from_millis(50);
+
+// a connection that sends nothing for this long, not even a pong, is dead
+const IDLE_CONNECTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+// how often a quiet connection is pinged
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(20);
+
+const JOIN_LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+const STATS_LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(10);
+
+// only rooms created with an explicit seed publish a commitment
+fn shuffle_commitment(state: &RoomState) -> Option<String> {
+    state.config.seed?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(state.seed.to_string());
+    hasher.update(&state.shuffle_salt);
+    Some(
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+// sums per-player points into per-team totals; empty outside team mode
+fn team_totals<'a>(
+    state: &RoomState,
+    points: impl Iterator<Item = (&'a String, u16)>,
+) -> HashMap<TeamId, u16> {
+    let mut totals = HashMap::new();
+    if !state.config.team_mode {
+        return totals;
+    }
+
+    for (player, points) in points {
+        if let Some(team) = state.teams.get(player) {
+            *totals.entry(*team).or_insert(0) += points;
+        }
+    }
+    totals
+}
+
+// counts the strings held by the structures that grow over a room's lifetime, plus a
+// fixed cost per entry
+fn approx_bytes(state: &RoomState) -> usize {
+    let entries =
+        |len: usize, value_size: usize| len * (std::mem::size_of::<String>() + value_size);
+    let string_bytes =
+        |iter: &mut dyn Iterator<Item = &String>| iter.map(|s| s.len()).sum::<usize>();
+
+    let history: usize = state
+        .round_history
+        .iter()
+        .map(|round| {
+            round.storyteller.len()
+                + round.description.len()
+                + round.active_card.len()
+                + string_bytes(&mut round.point_change.keys())
+                + entries(round.point_change.len(), 2)
+        })
+        .sum();
+
+    history
+        + string_bytes(&mut state.deck.iter())
+        + entries(state.deck.len(), 0)
+        + string_bytes(&mut state.discard.iter())
+        + entries(state.discard.len(), 0)
+        + string_bytes(&mut state.card_deal_count.keys())
+        + entries(state.card_deal_count.len(), 4)
+        + string_bytes(&mut state.card_stats.keys())
+        + entries(state.card_stats.len(), std::mem::size_of::<CardStats>())
+        + string_bytes(&mut state.player_stats.keys())
+        + entries(state.player_stats.len(), std::mem::size_of::<PlayerStats>())
+        + string_bytes(&mut state.win_streaks.keys())
+        + entries(state.win_streaks.len(), 4)
+        + string_bytes(&mut state.used_clues.iter())
+        + entries(state.used_clues.len(), 0)
+}
+
+fn hash_password(salt: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(password);
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// rooms without a password let everyone in
+fn password_matches(state: &RoomState, password: Option<&str>) -> bool {
+    match &state.password_hash {
+        Some(hash) => password.is_some_and(|p| hash_password(&state.password_salt, p) == *hash),
+        None => true,
+    }
+}
+
+fn room_settings(state: &RoomState) -> ServerMsg {
+    let mut config = state.config.clone();
+    // the seed is only revealed at the end, after the commitment can be checked
+    config.seed = None;
+    ServerMsg::RoomSettings(config)
+}
+
+// active_player is kept in range whenever a seat is freed, but a bad index
+// should cost a round rather than the whole room
+fn current_storyteller(state: &RoomState) -> Option<&str> {
+    state
+        .player_order
+        .get(state.active_player)
+        .map(String::as_str)
+}
+
+// the players the current stage is waiting on: the connected players other
+// than the storyteller, since the rest are filled in or sit the round out.
+// without auto fill, the disconnected are waited on too
+fn waiting_on(state: &RoomState) -> Vec<&PlayerInfo> {
+    let storyteller = current_storyteller(state);
+    state
+        .players
+        .iter()
+        .filter(|(player, info)| {
+            Some(player.as_str()) != storyteller
+                && (info.connected || state.config.disable_auto_fill)
+        })
+        .map(|(_, info)| info)
+        .collect()
+}
+
+// every player the current stage is waiting on has acted. with none of them
+// connected there is nobody to wait for, so nothing moves on early
+fn everyone_acted(state: &RoomState) -> bool {
+    let waiting_on = waiting_on(state);
+    waiting_on.iter().any(|info| info.connected) && waiting_on.iter().all(|info| info.ready)
+}
+
+fn scoreboard(state: &RoomState) -> ServerMsg {
+    let points = state
+        .players
+        .iter()
+        .map(|(player, info)| (player.clone(), info.points))
+        .collect();
+    // the turn order is only settled once the game starts
+    let (player_order, active_player) = match state.stage {
+        RoomStage::Joining => {
+            let mut players: Vec<String> = state.players.keys().cloned().collect();
+            players.sort();
+            (players, None)
+        }
+        RoomStage::End => (state.player_order.clone(), None),
+        _ => (
+            state.player_order.clone(),
+            current_storyteller(state).map(str::to_string),
+        ),
+    };
+    ServerMsg::Scoreboard {
+        points,
+        player_order,
+        active_player,
+    }
+}
+
+// what a skipped round shows, since nothing was played or scored
+fn skipped_results(state: &RoomState) -> ServerMsg {
+    ServerMsg::Results {
+        player_to_vote: HashMap::new(),
+        player_to_current_card: HashMap::new(),
+        active_card: String::new(),
+        point_change: HashMap::new(),
+        team_point_change: HashMap::new(),
+        storyteller_vote: None,
+        achievements: HashMap::new(),
+        warmup: is_warmup(state),
+    }
+}
+
+// the first player in turn order who isn't a bot
+fn pick_host(state: &RoomState) -> Option<String> {
+    let mut players: Vec<&String> = state.players.keys().collect();
+    players.sort();
+    state
+        .player_order
+        .iter()
+        .chain(players)
+        .find(|p| state.players.get(*p).is_some_and(|info| !info.bot))
+        .cloned()
+}
+
+// a card from the storyteller's hand and a word to go with it
+fn random_clue(state: &mut RoomState, name: &str) -> Result<(String, String)> {
+    let card = state
+        .player_hand
+        .get(name)
+        .and_then(|hand| hand.choose(&mut state.rng))
+        .ok_or_else(|| anyhow!("Empty hand for {}", name))?
+        .clone();
+    // stay clear of used clues when they have to be unique
+    let words: Vec<&str> = clues::CLUE_WORDS
+        .iter()
+        .copied()
+        .filter(|w| !state.config.unique_clues || !state.used_clues.contains(*w))
+        .collect();
+    let clue = words
+        .choose(&mut state.rng)
+        .or_else(|| clues::CLUE_WORDS.choose(&mut state.rng))
+        .unwrap() // the word list is not empty
+        .to_string();
+    Ok((card, clue))
+}
+
+// highest score first; ties go to whoever scored more rounds as storyteller,
+// and are listed alphabetically if that's tied too
+fn standings(state: &RoomState) -> Vec<Standing> {
+    let storyteller_rounds = |name: &str| {
+        state
+            .round_history
+            .iter()
+            .filter(|round| {
+                round.storyteller == name && round.point_change.get(name).is_some_and(|p| *p > 0)
+            })
+            .count()
+    };
+
+    let mut ranked: Vec<(Standing, usize)> = state
+        .players
+        .iter()
+        .map(|(name, info)| {
+            let standing = Standing {
+                name: name.clone(),
+                points: info.points,
+                team: state.teams.get(name).copied(),
+                rank: 0,
+            };
+            (standing, storyteller_rounds(name))
+        })
+        .collect();
+    ranked.sort_by(|(a, a_rounds), (b, b_rounds)| {
+        b.points
+            .cmp(&a.points)
+            .then_with(|| b_rounds.cmp(a_rounds))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    // competition ranking: two players tied for first are followed by third
+    for i in 0..ranked.len() {
+        let rank = match i.checked_sub(1).map(|j| &ranked[j]) {
+            Some((prev, prev_rounds))
+                if prev.points == ranked[i].0.points && *prev_rounds == ranked[i].1 =>
+            {
+                prev.rank
+            }
+            _ => i + 1,
+        };
+        ranked[i].0.rank = rank;
+    }
+    ranked.into_iter().map(|(standing, _)| standing).collect()
+}
+
+// what a spectator delay holds back
+fn reveals_clue(msg: &ServerMsg) -> bool {
+    matches!(
+        msg,
+        ServerMsg::PlayersChoose { .. } | ServerMsg::BeginVoting { .. }
+    )
+}
+
+fn ends_stage(msg: &ServerMsg) -> bool {
+    matches!(
+        msg,
+        ServerMsg::StartRound { .. }
+            | ServerMsg::Results { .. }
+            | ServerMsg::EndGame { .. }
+            | ServerMsg::GameOver { .. }
+    )
+}
+
+// the first color nobody in the room has
+fn free_color(state: &RoomState) -> &'static str {
+    PLAYER_COLORS
+        .iter()
+        .find(|color| !state.players.values().any(|p| p.color == **color))
+        .copied()
+        .unwrap_or(PLAYER_COLORS[state.players.len() % PLAYER_COLORS.len()])
+}
+
+// for timers, which outlive the connection that started them
+fn room_span(state: &RoomState) -> tracing::Span {
+    info_span!(parent: None, "room", room_id = %state.room_id)
+}
+
+// cards it takes to top every hand back up
+fn cards_needed(state: &RoomState) -> usize {
+    state
+        .players
+        .keys()
+        .map(|p| {
+            let held = state.player_hand.get(p).map_or(0, |h| h.len());
+            state.config.hand_size().saturating_sub(held)
+        })
+        .sum()
+}
+
+fn broadcast_capacity(max_players: usize) -> usize {
+    (max_players * BROADCASTS_PER_PLAYER).max(MIN_BROADCAST_CAPACITY)
+}
+
+fn rekey<V>(map: &mut HashMap<String, V>, from: &str, to: &str) {
+    if let Some(value) = map.remove(from) {
+        map.insert(to.to_string(), value);
+    }
+}
+
+fn is_warmup(state: &RoomState) -> bool {
+    state.config.warmup_round && state.round == 1
+}
+
+fn round_multiplier(state: &RoomState) -> u16 {
+    state
+        .config
+        .round_multipliers
+        .get(&state.round)
+        .copied()
+        .unwrap_or(1)
+}
+
+fn team_scores(state: &RoomState) -> HashMap<TeamId, u16> {
+    team_totals(
+        state,
+        state.players.iter().map(|(p, info)| (p, info.points)),
+    )
+}
+
+fn get_time_ms() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+fn frame_len(msg: &WsMessage) -> u64 {
+    let len = match msg {
+        WsMessage::Text(text) => text.len(),
+        WsMessage::Binary(data) | WsMessage::Ping(data) | WsMessage::Pong(data) => data.len(),
+        WsMessage::Close(_) => 0,
+    };
+    len as u64
 }
 
 pub fn get_time_s() -> u64 {
@@ -138,72 +1233,294 @@ pub fn get_time_s() -> u64 {
 }
 
 impl Room {
-    pub fn new(room_id: &str, base_deck: Arc<Vec<String>>) -> Self {
+    pub fn new(
+        room_id: &str,
+        base_deck: Arc<Vec<String>>,
+        mut config: RoomConfig,
+        events: broadcast::Sender<Event>,
+        max_name_len: usize,
+    ) -> Arc<Self> {
+        let password_salt = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let password_hash = config
+            .password
+            .take()
+            .map(|password| hash_password(&password_salt, &password));
+        let seed = config.seed.unwrap_or_else(rand::random);
+        let director_token = config
+            .director
+            .then(|| uuid::Uuid::new_v4().simple().to_string());
+        let shuffle_salt = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+
+        // the room only ever plays with its pool, including when the deck is reloaded
+        let base_deck = match config.pool_size {
+            Some(pool_size) => Arc::new(
+                base_deck
+                    .choose_multiple(&mut rng, pool_size)
+                    .cloned()
+                    .collect(),
+            ),
+            None => base_deck,
+        };
+
         let state = RoomState {
             room_id: room_id.to_string(),
             players: HashMap::new(),
             deck: base_deck.to_vec(),
+            discard: Vec::new(),
             stage: RoomStage::Joining,
             player_order: Vec::new(),
             player_hand: HashMap::new(),
             player_to_socket: HashMap::new(),
+            config,
+            card_deal_count: HashMap::new(),
+            card_stats: HashMap::new(),
+            kicked: HashSet::new(),
+            muted_until_s: HashMap::new(),
+            last_chat_ms: HashMap::new(),
+            reports: Vec::new(),
+            last_report_s: HashMap::new(),
+            win_streaks: HashMap::new(),
+            games_played: 0,
+            rounds_played: 0,
+            created_at_s: get_time_s(),
+            player_stats: HashMap::new(),
+            seed,
+            rng,
+            shuffle_salt,
+            password_hash,
+            password_salt,
             active_player: 0,
-            current_description: "".to_string(),
-            player_to_current_card: HashMap::new(),
-            player_to_vote: HashMap::new(),
+            round_state: RoundState::default(),
             round: 0,
+            timer_id: 0,
+            storyteller_timeouts: HashMap::new(),
+            storyteller_skips: HashMap::new(),
+            teams: HashMap::new(),
+            storytellers_this_cycle: Vec::new(),
+            host: None,
+            arranged_hands: HashSet::new(),
+            round_history: Vec::new(),
+            used_clues: HashSet::new(),
+            disconnected_at: HashMap::new(),
+            session_tokens: HashMap::new(),
+            game_id: None,
+            spectators: HashMap::new(),
+            directors: HashSet::new(),
+            capabilities: HashMap::new(),
+            timers_paused: false,
+            paused_for_players: false,
+            rematch_votes: HashSet::new(),
         };
 
-        let (tx, _) = broadcast::channel(10);
+        Self::from_state(state, base_deck, director_token, events, max_name_len)
+    }
+
+    fn from_state(
+        state: RoomState,
+        base_deck: Arc<Vec<String>>,
+        director_token: Option<String>,
+        events: broadcast::Sender<Event>,
+        max_name_len: usize,
+    ) -> Arc<Self> {
+        let (tx, _) = broadcast::channel(broadcast_capacity(state.config.max_players()));
 
-        Self {
+        Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            idle_timeout_s: state.config.idle_timeout_s,
             state: RwLock::new(state),
-            broadcast: tx,
+            broadcast: std::sync::RwLock::new(tx),
             base_deck,
             last_access: AtomicU64::new(get_time_s()),
+            broadcast_lagged: AtomicU64::new(0),
+            lagging_connections: AtomicUsize::new(0),
+            frames: FrameCounters::default(),
+            pending_state: Arc::new(std::sync::Mutex::new(None)),
+            director_token,
+            events,
+            max_name_len,
+            abandoned_at_s: AtomicU64::new(0),
+        })
+    }
+
+    // everything needed to bring the room back after a restart, as json
+    pub async fn snapshot(&self) -> serde_json::Result<serde_json::Value> {
+        let state = self.state.read().await;
+        serde_json::to_value(SnapshotRef {
+            state: &state,
+            base_deck: &self.base_deck,
+            director_token: self.director_token.as_deref(),
+        })
+    }
+
+    // a room from snapshot(); sockets can't be saved, so nobody is connected
+    // until they come back, with their session token once the game has started
+    pub fn restore(
+        snapshot: serde_json::Value,
+        events: broadcast::Sender<Event>,
+        max_name_len: usize,
+    ) -> serde_json::Result<Arc<Self>> {
+        let RoomSnapshot {
+            mut state,
+            base_deck,
+            director_token,
+        } = serde_json::from_value(snapshot)?;
+
+        // a lobby seat is only held while connected, so lobby players simply join again
+        if matches!(state.stage, RoomStage::Joining) {
+            state.players.retain(|_, info| info.bot);
+            state.teams.clear();
+            state.session_tokens.clear();
+            state.host = None;
+        }
+        for info in state.players.values_mut() {
+            if !info.bot {
+                info.connected = false;
+                info.ready = false;
+            }
         }
+
+        Ok(Self::from_state(
+            state,
+            Arc::new(base_deck),
+            director_token,
+            events,
+            max_name_len,
+        ))
     }
 
-    fn get_msg(
-        &self,
-        name: Option<&str>,
-        state: &RwLockWriteGuard<RoomState>,
-    ) -> Result<ServerMsg> {
+    // timers don't survive a restart, so a restored room starts them again
+    pub async fn resume(&self) {
+        let mut state = self.state.write().await;
+
+        if matches!(state.stage, RoomStage::Results) {
+            let point_change = state
+                .round_history
+                .last()
+                .map(|round| round.point_change.clone())
+                .unwrap_or_default();
+            // a skipped round has no storyteller card to rebuild from
+            let results = self
+                .build_results(&state, point_change)
+                .unwrap_or_else(|e| {
+                    warn!("Could not rebuild results: {:?}", e);
+                    skipped_results(&state)
+                });
+            state.round_state.results = Some(results);
+        }
+
+        let away: Vec<String> = state
+            .players
+            .iter()
+            .filter(|(_, info)| !info.connected)
+            .map(|(player, _)| player.clone())
+            .collect();
+        for player in away {
+            self.start_seat_timer(&mut state, &player);
+        }
+
+        // everyone starts disconnected, so the game's clocks wait for the first of them
+        if !matches!(state.stage, RoomStage::Joining) {
+            self.abandoned_at_s.store(get_time_s(), Ordering::Relaxed);
+        }
+        self.schedule_bots(&state);
+        info!("Room {} restored in {:?}", state.room_id, state.stage);
+    }
+
+    fn get_msg(&self, name: Option<&str>, state: &RoomState) -> Result<ServerMsg> {
         match state.stage {
             RoomStage::ActiveChooses => Ok(ServerMsg::StartRound {
-                hand: state.player_hand[name.ok_or_else(|| anyhow!("No name provided"))?].clone(),
+                hand: self.get_hand(name, state)?,
+                multiplier: round_multiplier(state),
+                suggested_think_s: state.config.suggested_think_s,
             }),
             RoomStage::PlayersChoose => Ok(ServerMsg::PlayersChoose {
-                description: state.current_description.clone(),
-                hand: state.player_hand[name.ok_or_else(|| anyhow!("No name provided"))?].clone(),
+                description: state.round_state.current_description.clone(),
+                hand: self.get_hand(name, state)?,
+                suggested_think_s: state.config.suggested_think_s,
+                already_chosen: name
+                    .and_then(|n| state.round_state.player_to_current_card.get(n).cloned()),
             }),
             RoomStage::Voting => Ok(ServerMsg::BeginVoting {
                 center_cards: self.get_center_cards(state),
-                description: state.current_description.clone(),
+                description: state.round_state.current_description.clone(),
+                already_chosen: name
+                    .and_then(|n| state.round_state.player_to_current_card.get(n).cloned()),
+                already_voted: name.and_then(|n| state.round_state.player_to_vote.get(n).cloned()),
+                suggested_think_s: state.config.suggested_think_s,
             }),
-            RoomStage::Results => Ok(ServerMsg::Results {
-                player_to_vote: state.player_to_vote.clone(),
-                player_to_current_card: state.player_to_current_card.clone(),
-                active_card: state
-                    .player_to_current_card
-                    .get(&self.get_active_player(state)?)
-                    .unwrap()
-                    .to_string(),
-                point_change: self.compute_results(state),
+            RoomStage::Results => state
+                .round_state
+                .results
+                .clone()
+                .ok_or_else(|| anyhow!("No results for this round")),
+            RoomStage::End | RoomStage::GameOver => Ok(ServerMsg::EndGame {
+                game_id: state.game_id.clone(),
+                shuffle_reveal: state.config.seed.map(|seed| ShuffleReveal {
+                    seed,
+                    salt: state.shuffle_salt.clone(),
+                }),
+                standings: standings(state),
             }),
-            RoomStage::End => Ok(ServerMsg::EndGame {}),
             _ => Err(anyhow!("No msg to send")),
         }
     }
 
-    fn get_center_cards(&self, state: &RwLockWriteGuard<RoomState>) -> Vec<String> {
-        let mut center_cards: Vec<String> = state
+    // built once when the round is scored, so later changes to the room
+    // (like a freed seat) can't change what reconnecting players are shown
+    fn build_results(
+        &self,
+        state: &RwLockWriteGuard<RoomState>,
+        point_change: HashMap<String, u16>,
+    ) -> Result<ServerMsg> {
+        let active_player = self.get_active_player(state)?;
+        let active_card = state
+            .round_state
             .player_to_current_card
-            .values()
-            .map(|e| e.to_string())
-            .collect();
-        center_cards.shuffle(&mut rand::thread_rng());
-        center_cards
+            .get(&active_player)
+            .ok_or_else(|| anyhow!("No card from storyteller {}", active_player))?
+            .to_string();
+
+        // scores are always shown, but decoys and votes can be kept private
+        let (player_to_vote, player_to_current_card) = match state.config.results_detail {
+            ResultsDetail::Full => (
+                state.round_state.player_to_vote.clone(),
+                state.round_state.player_to_current_card.clone(),
+            ),
+            ResultsDetail::Minimal => (
+                HashMap::new(),
+                HashMap::from([(active_player, active_card.clone())]),
+            ),
+        };
+
+        Ok(ServerMsg::Results {
+            player_to_vote,
+            player_to_current_card,
+            active_card,
+            team_point_change: team_totals(
+                state,
+                point_change.iter().map(|(p, change)| (p, *change)),
+            ),
+            point_change,
+            storyteller_vote: state.round_state.storyteller_vote.clone(),
+            achievements: self.compute_achievements(state),
+            warmup: is_warmup(state),
+        })
+    }
+
+    // every per-player message sends the hand through here so the order is consistent
+    fn get_hand(&self, name: Option<&str>, state: &RoomState) -> Result<Vec<String>> {
+        let name = name.ok_or_else(|| anyhow!("No name provided"))?;
+        state
+            .player_hand
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("No hand for {}", name))
+    }
+
+    // shuffled once when voting starts, so everyone sees the same order
+    fn get_center_cards(&self, state: &RoomState) -> Vec<String> {
+        state.round_state.center_cards.clone()
     }
 
     fn get_active_player(&self, state: &RwLockWriteGuard<RoomState>) -> Result<String> {
@@ -211,31 +1528,141 @@ impl Room {
             return Err(anyhow!("Failed to find active player"));
         }
 
-        Ok(state
-            .player_order
-            .get(state.active_player)
-            .unwrap() // unreachable
-            .to_string())
+        current_storyteller(state)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("No player at position {}", state.active_player))
     }
 
-    fn init_voting(&self, state: &mut RwLockWriteGuard<RoomState>) -> Result<()> {
-        state.stage = RoomStage::Voting;
-
+    // in team mode players may not vote for a card put down by a teammate,
+    // though the storyteller's card is always fair game
+    fn is_teammate_card(
+        &self,
+        state: &RwLockWriteGuard<RoomState>,
+        name: &str,
+        card: &str,
+    ) -> bool {
+        if !state.config.team_mode {
+            return false;
+        }
+
+        let Some(storyteller) = current_storyteller(state) else {
+            return false;
+        };
+        let team = state.teams.get(name);
+        state
+            .round_state
+            .player_to_current_card
+            .iter()
+            .any(|(player, c)| {
+                c == card
+                    && player != name
+                    && player != storyteller
+                    && state.teams.get(player) == team
+            })
+    }
+
+    // the leading score and everyone sharing it, by team in team mode
+    fn leaders(&self, state: &RwLockWriteGuard<RoomState>) -> (u16, Vec<String>) {
+        if state.config.team_mode {
+            let scores = team_scores(state);
+            let max_score = scores.values().copied().max().unwrap_or_default();
+            let leaders = state
+                .teams
+                .iter()
+                .filter(|(_, team)| scores.get(team) == Some(&max_score))
+                .map(|(player, _)| player.clone())
+                .collect();
+            return (max_score, leaders);
+        }
+
+        let max_points = state
+            .players
+            .values()
+            .map(|p| p.points)
+            .max()
+            .unwrap_or_default();
+        let leaders = state
+            .players
+            .iter()
+            .filter(|(_, info)| info.points == max_points)
+            .map(|(player, _)| player.clone())
+            .collect();
+        (max_points, leaders)
+    }
+
+    // put anyone who didn't pick a team on the smallest one
+    fn assign_teams(&self, state: &mut RwLockWriteGuard<RoomState>) {
+        let team_count = state.config.team_count.unwrap_or(2).max(1);
+        let mut sizes = vec![0; team_count as usize];
+        state.teams.retain(|_, team| *team < team_count);
+        for team in state.teams.values() {
+            sizes[*team as usize] += 1;
+        }
+
+        // player_order is already shuffled, so this is random but follows the seed
+        for player in state.player_order.clone() {
+            if state.teams.contains_key(&player) {
+                continue;
+            }
+
+            let (team, _) = sizes
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, size)| **size)
+                .unwrap(); // team_count is at least 1
+            sizes[team] += 1;
+            state.teams.insert(player, team as TeamId);
+        }
+    }
+
+    fn init_voting(&self, state: &mut RwLockWriteGuard<RoomState>) -> Result<()> {
+        state.stage = RoomStage::Voting;
+
         // choose random card for those who didn't choose by the deadline
         for player in state.player_order.clone().iter() {
-            if !state.player_to_current_card.contains_key(player) {
-                let mut rng = rand::thread_rng();
-                let card = state.player_hand[player].choose(&mut rng).unwrap().clone();
+            if !state
+                .round_state
+                .player_to_current_card
+                .contains_key(player)
+            {
+                if (state.config.on_no_submission == NoSubmission::Skip
+                    || state.config.disable_auto_fill)
+                    && current_storyteller(state) != Some(player.as_str())
+                {
+                    state.round_state.sitting_out.insert(player.clone());
+                    continue;
+                }
+
+                // a player with nothing in hand simply has no card in the center
+                let card = {
+                    let state = &mut **state;
+                    state
+                        .player_hand
+                        .get(player)
+                        .and_then(|hand| hand.choose(&mut state.rng))
+                        .cloned()
+                };
+                let Some(card) = card else {
+                    info!("No card to play for {}", player);
+                    continue;
+                };
                 state
+                    .round_state
                     .player_to_current_card
                     .insert(player.to_string(), card);
             }
         }
 
         self.clear_ready(state);
+        // nothing to wait for from players sitting out
+        for player in state.round_state.sitting_out.clone() {
+            if let Some(info) = state.players.get_mut(&player) {
+                info.ready = true;
+            }
+        }
 
         // remove cards from hand that were put in the center
-        for (player, card) in state.player_to_current_card.clone().iter() {
+        for (player, card) in state.round_state.player_to_current_card.clone().iter() {
             if let Some(hand) = state.player_hand.get_mut(player) {
                 if let Some(pos) = hand.iter().position(|e| e == card) {
                     hand.remove(pos);
@@ -243,36 +1670,115 @@ impl Room {
             }
         }
 
-        self.broadcast_msg(self.get_msg(None, &state)?)?;
-        self.broadcast_msg(self.room_state(&state))?;
+        // hashmap order is random, sort so the seed alone decides the order
+        let mut center_cards: Vec<String> = state
+            .round_state
+            .player_to_current_card
+            .values()
+            .cloned()
+            .collect();
+        center_cards.sort();
+        center_cards.shuffle(&mut state.rng);
+        // out of every hand now, so they're done with once the round is
+        state.discard.extend(center_cards.iter().cloned());
+        state.round_state.center_cards = center_cards;
 
-        Ok(())
+        self.broadcast_msg(self.get_msg(None, state)?)?;
+        self.broadcast_state(state)?;
+        self.broadcast_stage_progress(state)?;
+        self.start_stage_timer(state);
+        self.schedule_bots(state);
+
+        self.skip_voting_if_stuck(state)
+    }
+
+    // nobody left who could vote means nobody will, so score what we have;
+    // init_results fills in random votes for the missing players
+    fn skip_voting_if_stuck(&self, state: &mut RwLockWriteGuard<RoomState>) -> Result<()> {
+        if !matches!(state.stage, RoomStage::Voting) {
+            return Ok(());
+        }
+
+        let storyteller = self.get_active_player(state)?;
+        let can_vote = state.players.iter().any(|(player, info)| {
+            player != &storyteller
+                && info.connected
+                && !state.round_state.player_to_vote.contains_key(player)
+                && !state.round_state.sitting_out.contains(player)
+        });
+        if can_vote {
+            return Ok(());
+        }
+
+        info!("Room {} has no one left to vote", state.room_id);
+        self.broadcast_msg(ServerMsg::VotingSkipped {
+            reason: "No connected players are left to vote".to_string(),
+        })?;
+        self.init_results(state)
     }
 
     fn init_results(&self, state: &mut RwLockWriteGuard<RoomState>) -> Result<()> {
         state.stage = RoomStage::Results;
 
+        // there's nothing to score against, so the round is dropped and
+        // everyone readies up for the next one as usual
+        let storyteller = self.get_active_player(state)?;
+        let Some(active_card) = state
+            .round_state
+            .player_to_current_card
+            .get(&storyteller)
+            .cloned()
+        else {
+            info!(
+                "Room {} has no card from storyteller {}, skipping the round",
+                state.room_id, storyteller
+            );
+            // the cards are already in the discard pile
+            state.round_state.player_to_current_card.clear();
+            self.clear_ready(state);
+            self.broadcast_msg(ServerMsg::VotingSkipped {
+                reason: "The storyteller's card is missing, so this round is skipped".to_string(),
+            })?;
+            // so reconnecting players have results to show like any other round
+            let results = skipped_results(state);
+            state.round_state.results = Some(results.clone());
+            self.broadcast_msg(results)?;
+            return self.broadcast_state(state);
+        };
+
         let center_cards = self.get_center_cards(state);
 
         // choose random card to vote for if the player didn't choose
         for player in state.player_order.clone().iter() {
-            if player != &state.player_order[state.active_player]
-                && !state.player_to_vote.contains_key(player)
+            if !state.config.disable_auto_fill
+                && player != &storyteller
+                && !state.round_state.player_to_vote.contains_key(player)
+                && !state.round_state.sitting_out.contains(player)
             {
-                // choose random card
-                let mut rng = rand::thread_rng();
-                let mut card = center_cards.choose(&mut rng).unwrap().clone();
-
-                // ensure player cannot choose their own card
-                while &card == state.player_to_current_card.get(player).unwrap() {
-                    card = center_cards.choose(&mut rng).unwrap().clone();
-                }
+                // choose random card, never their own or a teammate's
+                let own_card = state.round_state.player_to_current_card.get(player);
+                let options: Vec<String> = center_cards
+                    .iter()
+                    .filter(|card| {
+                        Some(*card) != own_card && !self.is_teammate_card(state, player, card)
+                    })
+                    .cloned()
+                    .collect();
+                // with nothing they're allowed to vote for, the player just doesn't vote
+                let Some(card) = options.choose(&mut state.rng).cloned() else {
+                    info!("No card left for {} to vote for", player);
+                    continue;
+                };
 
-                state.player_to_vote.insert(player.to_string(), card);
+                state
+                    .round_state
+                    .player_to_vote
+                    .insert(player.to_string(), card);
             }
         }
 
         let point_change = self.compute_results(state);
+        self.record_card_stats(state);
 
         // update with the point change
         state.players.iter_mut().for_each(|(player, info)| {
@@ -281,502 +1787,4509 @@ impl Room {
             }
         });
 
-        self.clear_ready(state);
+        for (player, points) in point_change.iter() {
+            let stats = state.player_stats.entry(player.clone()).or_default();
+            stats.total_points += *points as u32;
+            stats.best_round_points = stats.best_round_points.max(*points);
+        }
 
-        // send results to everyone
-        self.broadcast_msg(self.get_msg(None, &state)?)?;
-        self.broadcast_msg(self.room_state(&state))?;
+        let (player_to_current_card, player_to_vote) = match state.config.results_detail {
+            ResultsDetail::Full => (
+                state.round_state.player_to_current_card.clone(),
+                state.round_state.player_to_vote.clone(),
+            ),
+            ResultsDetail::Minimal => (HashMap::new(), HashMap::new()),
+        };
+        let summary = RoundSummary {
+            round: state.round,
+            active_card,
+            storyteller,
+            description: state.round_state.current_description.clone(),
+            point_change: point_change.clone(),
+            player_to_current_card,
+            player_to_vote,
+        };
+        events::publish(
+            &self.events,
+            Event::RoundCompleted {
+                room_id: state.room_id.clone(),
+                game_id: state.game_id.clone(),
+                summary: summary.clone(),
+            },
+        );
+        state.round_history.push(summary);
 
-        Ok(())
-    }
+        self.clear_ready(state);
 
-    fn check_deck(&self, state: &mut RwLockWriteGuard<'_, RoomState>) {
-        if state.deck.len() < state.player_order.len() {
-            let mut new_deck = self.base_deck.to_vec();
+        // send results to everyone; the points were computed against the
+        // scores before this round, so they aren't computed again here
+        let results = self.build_results(state, point_change)?;
+        state.round_state.results = Some(results.clone());
+        self.broadcast_msg(results)?;
 
-            // get all cards currently in hands
-            let mut all_hands = Vec::new();
-            for player in state.player_hand.keys() {
-                all_hands.append(&mut state.player_hand[player].clone());
+        if state.config.audience_poll {
+            let mut card_counts: HashMap<String, u32> = HashMap::new();
+            for card in state.round_state.audience_votes.values() {
+                *card_counts.entry(card.clone()).or_insert(0) += 1;
             }
+            self.broadcast_msg(ServerMsg::AudiencePoll { card_counts })?;
+        }
+        self.broadcast_state(state)?;
+        self.schedule_bots(state);
 
-            // remove cards from deck
-            for card in all_hands {
-                if let Some(pos) = new_deck.iter().position(|e| e == &card) {
-                    new_deck.remove(pos);
-                }
-            }
+        Ok(())
+    }
 
-            state.deck = new_deck;
-            state.deck.shuffle(&mut rand::thread_rng());
+    fn record_card_stats(&self, state: &mut RwLockWriteGuard<'_, RoomState>) {
+        let state = &mut **state;
+        let Some(storyteller) = current_storyteller(state).map(str::to_string) else {
+            return;
+        };
+        for (player, card) in state.round_state.player_to_current_card.iter() {
+            let stats = state.card_stats.entry(card.clone()).or_default();
+            if *player == storyteller {
+                stats.as_storyteller_card += 1;
+            } else {
+                stats.as_decoy += 1;
+                stats.votes_fooled += state
+                    .round_state
+                    .player_to_vote
+                    .values()
+                    .filter(|vote| *vote == card)
+                    .count() as u32;
+            }
         }
     }
 
-    async fn init_round(&self, state: &mut RwLockWriteGuard<'_, RoomState>) -> Result<()> {
-        if state.players.len() < 3 {
-            return Err(anyhow!("Not enough players"));
+    // shuffle the discard pile back into the deck once the deck can't fill every hand
+    fn check_deck(&self, state: &mut RwLockWriteGuard<'_, RoomState>) {
+        if state.deck.len() >= cards_needed(state) || state.discard.is_empty() {
+            return;
         }
 
-        state.round += 1;
+        let state = &mut **state;
+        info!(
+            "Room {} reshuffling {} discarded cards into the deck",
+            state.room_id,
+            state.discard.len()
+        );
+        // a card can't be in a hand and the discard pile at once, but a
+        // duplicate would be dealt twice, so make sure
+        let in_hands: HashSet<&String> = state.player_hand.values().flatten().collect();
+        let discard = std::mem::take(&mut state.discard);
+        state
+            .deck
+            .extend(discard.into_iter().filter(|card| !in_hands.contains(card)));
+        state.deck.shuffle(&mut state.rng);
+    }
 
-        // finalize players
-        if state.round == 1 {
-            // first round
-            state.active_player = 0;
-            state.player_order = state.players.keys().cloned().collect::<Vec<_>>();
-            state.player_order.shuffle(&mut rand::thread_rng());
-        } else {
-            state.active_player = (state.active_player + 1) % state.player_order.len();
+    fn weight_deck(&self, state: &mut RwLockWriteGuard<'_, RoomState>) {
+        // cards are popped from the back, so the least dealt cards go last;
+        // the sort is stable so ties keep their shuffled order
+        let state = &mut **state;
+        let counts = &state.card_deal_count;
+        state
+            .deck
+            .sort_by_key(|card| std::cmp::Reverse(counts.get(card).copied().unwrap_or(0)));
+    }
 
-            // not enough cards, reload
-            self.check_deck(state);
+    // next player in the rotation, passing over anyone still serving a penalty
+    fn next_storyteller(&self, state: &mut RwLockWriteGuard<'_, RoomState>) -> usize {
+        if matches!(
+            state.config.storyteller_selection,
+            StorytellerSelection::Random
+        ) {
+            return self.random_storyteller(state);
         }
 
-        // shuffle deck
-        state.deck.shuffle(&mut rand::thread_rng());
+        let state = &mut **state;
+        let len = state.player_order.len();
+        let mut next = state.active_player;
+
+        for _ in 0..len {
+            next = (next + 1) % len;
+            match state.storyteller_skips.get_mut(&state.player_order[next]) {
+                Some(skips) if *skips > 0 => *skips -= 1,
+                _ => return next,
+            }
+        }
 
-        // clear current chosen cards
-        state.player_to_current_card.clear();
-        state.player_to_vote.clear();
+        // everyone is being skipped, so fall back to the plain rotation
+        (state.active_player + 1) % len
+    }
 
-        // ensure all players have 6 cards
-        let mut player_hand = state.player_hand.clone();
+    // random pick among players who haven't told yet this cycle
+    fn random_storyteller(&self, state: &mut RwLockWriteGuard<'_, RoomState>) -> usize {
+        let state = &mut **state;
+        let len = state.player_order.len();
 
-        let mut deck = state.deck.clone();
-        for player in state.players.keys() {
-            if !player_hand.contains_key(player) {
-                player_hand.insert(player.clone(), Vec::new());
+        let mut candidates: Vec<usize> = Vec::new();
+        for (i, player) in state.player_order.iter().enumerate() {
+            if state.storytellers_this_cycle.contains(player) {
+                continue;
             }
-
-            while player_hand.get(player).unwrap().len() < 6 {
-                player_hand.get_mut(player).unwrap().push(
-                    deck.pop()
-                        .ok_or_else(|| anyhow!("Not enough cards in the deck"))?,
-                );
+            match state.storyteller_skips.get_mut(player) {
+                Some(skips) if *skips > 0 => *skips -= 1,
+                _ => candidates.push(i),
             }
         }
 
-        state.deck = deck;
-        state.player_hand = player_hand;
-        state.stage = RoomStage::ActiveChooses;
-
-        // notify players of the game start and their hands
-        for player in state.player_order.iter() {
-            let _ = self
-                .send_msg(&state, &player, self.get_msg(Some(&player), &state)?)
-                .await;
+        if candidates.is_empty() {
+            // everyone has had a turn, start a new cycle without repeating the last storyteller
+            state.storytellers_this_cycle.clear();
+            candidates = (0..len)
+                .filter(|i| len == 1 || *i != state.active_player)
+                .collect();
         }
 
-        self.clear_ready(state);
-        self.broadcast_msg(self.room_state(&state))?;
-
-        Ok(())
+        *candidates.choose(&mut state.rng).unwrap() // there is always a candidate
     }
 
-    pub async fn handle_client_msg(&self, name: &str, msg: WsMessage) -> Result<()> {
-        let mut state = self.state.write().await;
+    fn start_clue_timer(&self, state: &mut RwLockWriteGuard<'_, RoomState>) {
+        state.timer_id += 1;
 
-        let msg: ClientMsg = serde_json::from_str(msg.to_text()?)
-            .context(format!("Failed to deserialize client msg: {:?}", msg))?;
+        let Some(timeout) = state.config.clue_timeout_s else {
+            return;
+        };
 
-        println!("Handling client message: {:?}", msg);
+        state.round_state.clue_timer_started = true;
+        let _ = self.broadcast_msg(ServerMsg::ClueTimerStarted {
+            ends_at_s: get_time_s() + timeout,
+        });
 
-        match msg {
-            ClientMsg::Ready {} => {
-                if matches!(state.stage, RoomStage::Joining)
-                    || matches!(state.stage, RoomStage::Results)
-                {
-                    state
-                        .players
-                        .get_mut(name)
-                        .ok_or_else(|| anyhow!("Unreachable: cannot ready player {}", name))?
-                        .ready = true;
+        let timer_id = state.timer_id;
+        let room = self.this.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(std::time::Duration::from_secs(timeout)).await;
+                if let Some(room) = room.upgrade() {
+                    if let Err(e) = room.on_clue_timeout(timer_id).await {
+                        warn!("Error in on_clue_timeout: {:?}", e);
+                    }
+                }
+            }
+            .instrument(room_span(state)),
+        );
+    }
 
-                    self.broadcast_msg(self.room_state(&state))?;
+    // picking and voting both move on without whoever hasn't acted by the deadline;
+    // starting a newer timer or leaving the stage makes this one a no-op
+    fn start_stage_timer(&self, state: &mut RwLockWriteGuard<'_, RoomState>) {
+        state.timer_id += 1;
 
-                    // if any player has 10 points, end game
-                    let max_points = state
-                        .players
-                        .values()
-                        .map(|p| p.points)
-                        .max()
-                        .unwrap_or_default();
+        let Some(timeout) = state.config.stage_timeout_s else {
+            return;
+        };
+        // running out the clock would mean filling in for people
+        if state.config.disable_auto_fill {
+            return;
+        }
 
-                    if max_points >= 10 {
-                        state.stage = RoomStage::End;
-                        self.broadcast_msg(self.get_msg(None, &state)?)?;
-                        return Ok(());
-                    }
+        let _ = self.broadcast_msg(ServerMsg::StageTimer {
+            remaining_s: timeout,
+        });
 
-                    // otherwise, check if everyone is ready for next round
-                    if state.players.values().filter(|p| p.ready).count() == state.players.len() {
-                        if state.players.len() >= 3 {
-                            self.init_round(&mut state).await?;
-                        } else {
-                            self.broadcast_msg(
-                                ServerMsg::ErrorMsg("Need at least 3 players".to_string()).into(),
-                            )?;
-                        }
+        let timer_id = state.timer_id;
+        let room = self.this.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(std::time::Duration::from_secs(timeout)).await;
+                if let Some(room) = room.upgrade() {
+                    if let Err(e) = room.on_stage_timeout(timer_id).await {
+                        warn!("Error in on_stage_timeout: {:?}", e);
                     }
                 }
             }
-            ClientMsg::ActivePlayerChooseCard { card, description } => {
-                if matches!(state.stage, RoomStage::ActiveChooses)
-                    && state.player_order[state.active_player] == name
-                {
-                    // verify that player has this card
-                    if !state.player_hand[name].contains(&card) {
-                        return Err(anyhow!("Invalid card chosen by active player"));
-                    }
-
-                    let description = description.trim();
-                    // verify that the description is not empty and is one word
-                    if description.is_empty() {
-                        if let Some(tx) = state.player_to_socket.get(name) {
-                            tx.send(
-                                ServerMsg::ErrorMsg("Description must not be empty".to_string())
-                                    .into(),
-                            )
-                            .await?;
-                        }
-                        return Ok(());
-                    }
-                    state.current_description = description.to_string();
-                    state.stage = RoomStage::PlayersChoose;
+            .instrument(room_span(state)),
+        );
+    }
 
-                    // record choice
-                    state
-                        .player_to_current_card
-                        .insert(name.to_string(), card.to_string());
+    // moves choosing on to voting, or voting on to results
+    fn end_stage(&self, state: &mut RwLockWriteGuard<RoomState>) -> Result<()> {
+        match state.stage {
+            RoomStage::PlayersChoose => self.init_voting(state),
+            RoomStage::Voting => self.init_results(state),
+            _ => Ok(()),
+        }
+    }
 
-                    // notify players of the active player's choice
-                    for player in state.player_order.iter() {
-                        let _ = self
-                            .send_msg(&state, &player, self.get_msg(Some(&player), &state)?)
-                            .await;
-                    }
+    async fn on_stage_timeout(&self, timer_id: u64) -> Result<()> {
+        let mut state = self.state.write().await;
+        if state.timer_id != timer_id || self.clock_stopped(&state) {
+            return Ok(());
+        }
 
-                    self.clear_ready(&mut state);
-                    self.broadcast_msg(self.room_state(&state))?;
-                }
+        match state.stage {
+            RoomStage::PlayersChoose => {
+                info!("Choosing timed out in room {}", state.room_id);
+                self.init_voting(&mut state)
             }
-            ClientMsg::PlayerChooseCard { card } => {
-                if matches!(state.stage, RoomStage::PlayersChoose) {
-                    if state.player_order[state.active_player] != name {
-                        // verify that player has this card
-                        if !state.player_hand.get(name).unwrap().contains(&card) {
-                            return Err(anyhow!("Invalid card chosen by player"));
-                        }
-
-                        // record choice
-                        state
-                            .player_to_current_card
-                            .insert(name.to_string(), card.to_string());
+            RoomStage::Voting => {
+                info!("Voting timed out in room {}", state.room_id);
+                self.init_results(&mut state)
+            }
+            _ => Ok(()),
+        }
+    }
 
-                        // ready
-                        state.players.get_mut(name).unwrap().ready = true;
-                        self.broadcast_msg(self.room_state(&state))?;
+    // starts the clue timer once the grace period is over, if the acks haven't already
+    fn start_round_ack_timer(&self, state: &mut RwLockWriteGuard<'_, RoomState>) {
+        // anything still pending from the last round goes stale
+        state.timer_id += 1;
 
-                        // check if everyone except for the active player is ready
-                        if state.players.values().filter(|p| p.ready).count()
-                            == state.players.len() - 1
-                        {
-                            self.init_voting(&mut state)?;
-                        }
+        let timer_id = state.timer_id;
+        let room = self.this.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(ROUND_ACK_GRACE).await;
+                if let Some(room) = room.upgrade() {
+                    let mut state = room.state.write().await;
+                    if state.timer_id == timer_id
+                        && !state.round_state.clue_timer_started
+                        && matches!(state.stage, RoomStage::ActiveChooses)
+                    {
+                        info!("Round ack grace period over in room {}", state.room_id);
+                        room.start_clue_timer(&mut state);
                     }
                 }
             }
-            ClientMsg::Vote { card } => {
-                if matches!(state.stage, RoomStage::Voting) {
-                    // verify that the player is not the active player
-                    if state.player_order[state.active_player] == name {
-                        println!(
-                            "{} is the active player",
-                            state.player_order[state.active_player]
-                        );
-                        println!("{} is trying to vote", name);
-                        return Err(anyhow!("Active player cannot vote"));
-                    }
+            .instrument(room_span(state)),
+        );
+    }
 
-                    // verify that the card is in the center
-                    if !state.player_to_current_card.values().any(|e| e == &card) {
-                        return Err(anyhow!("Invalid card"));
-                    }
+    // frees the seat of a player who is still gone once the reconnect window closes
+    fn start_seat_timer(&self, state: &mut RwLockWriteGuard<'_, RoomState>, name: &str) {
+        let window = state
+            .config
+            .reconnect_window_s
+            .unwrap_or(DEFAULT_RECONNECT_WINDOW_S);
+        state.disconnected_at.insert(name.to_string(), get_time_s());
 
-                    // verify that this player is not voting for their own code or send an error message
-                    if state.player_to_current_card.get(name).unwrap() == &card {
-                        state
-                            .player_to_socket
-                            .get(name)
-                            .unwrap()
-                            .send(
-                                ServerMsg::ErrorMsg(
-                                    "You cannot vote for your own card".to_string(),
-                                )
-                                .into(),
-                            )
-                            .await?;
-                        return Ok(());
+        let name = name.to_string();
+        let room = self.this.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(std::time::Duration::from_secs(window)).await;
+                if let Some(room) = room.upgrade() {
+                    if let Err(e) = room.on_seat_timeout(&name, window).await {
+                        warn!("Error in on_seat_timeout: {:?}", e);
                     }
+                }
+            }
+            .instrument(room_span(state)),
+        );
+    }
 
-                    // record vote
-                    state
-                        .player_to_vote
-                        .insert(name.to_string(), card.to_string());
-
-                    // ready
-                    state.players.get_mut(name).unwrap().ready = true;
-                    self.broadcast_msg(self.room_state(&state))?;
+    // only does something while a disconnected storyteller is holding up the round
+    fn start_storyteller_grace(&self, state: &RwLockWriteGuard<'_, RoomState>) {
+        if !matches!(state.stage, RoomStage::ActiveChooses) {
+            return;
+        }
+        let Some(storyteller) = current_storyteller(state) else {
+            return;
+        };
+        if state.players.get(storyteller).is_none_or(|p| p.connected) {
+            return;
+        }
 
-                    // check if everyone except for the active player is ready
-                    if state.players.values().filter(|p| p.ready).count() == state.players.len() - 1
-                    {
-                        self.init_results(&mut state)?;
+        let storyteller = storyteller.to_string();
+        let round = state.round;
+        let room = self.this.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(std::time::Duration::from_secs(STORYTELLER_GRACE_S)).await;
+                if let Some(room) = room.upgrade() {
+                    if let Err(e) = room.on_storyteller_absent(&storyteller, round).await {
+                        warn!("Error in on_storyteller_absent: {:?}", e);
                     }
                 }
             }
-            _ => {
-                // nothing
-            }
-        }
-
-        Ok(())
+            .instrument(room_span(state)),
+        );
     }
 
-    fn compute_results(&self, state: &RwLockWriteGuard<RoomState>) -> HashMap<String, u16> {
-        let mut point_change: HashMap<String, u16> = HashMap::new();
-        let active_player = state.player_order[state.active_player].clone();
-        let active_card = state
-            .player_to_current_card
-            .get(&active_player)
-            .unwrap()
-            .clone();
-
-        let mut votes_for_card: HashMap<String, u16> = HashMap::new();
+    // the director paused the clock, or there's nobody left to play
+    fn clock_stopped(&self, state: &RoomState) -> bool {
+        state.timers_paused || self.abandoned_at_s.load(Ordering::Relaxed) != 0
+    }
 
-        for (_, card) in state.player_to_vote.iter() {
-            *votes_for_card.entry(card.to_string()).or_insert(0) += 1;
+    // the current stage gets a fresh clock once nothing is holding it
+    fn restart_stage_clock(&self, state: &mut RwLockWriteGuard<'_, RoomState>) {
+        if self.clock_stopped(state) {
+            return;
+        }
+        match state.stage {
+            RoomStage::ActiveChooses => self.start_clue_timer(state),
+            RoomStage::PlayersChoose | RoomStage::Voting => self.start_stage_timer(state),
+            _ => {}
         }
+    }
 
-        let votes_for_active_card = *votes_for_card.get(&active_card).unwrap_or(&0);
-        if votes_for_active_card == 0 {
-            // nobody voted for active card
-            for (player, _) in state.player_to_vote.iter() {
-                point_change.insert(player.to_string(), 2);
-            }
+    // gives every bot a chance to act in the stage that just started
+    fn schedule_bots(&self, state: &RwLockWriteGuard<'_, RoomState>) {
+        if !state.players.values().any(|p| p.bot) {
+            return;
+        }
 
-            for (player, card) in state.player_to_current_card.iter() {
-                if player != &active_player {
-                    *point_change.get_mut(player).unwrap() +=
-                        votes_for_card.get(card).unwrap_or(&0);
+        let round = state.round;
+        let stage = state.stage;
+        let room = self.this.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(BOT_THINK_TIME).await;
+                if let Some(room) = room.upgrade() {
+                    if let Err(e) = room.on_bot_turn(round, stage).await {
+                        warn!("Error in on_bot_turn: {:?}", e);
+                    }
                 }
             }
+            .instrument(room_span(state)),
+        );
+    }
 
-            point_change.insert(active_player.clone(), 0);
-        } else if votes_for_active_card == (state.player_order.len() - 1) as u16 {
-            // everyone voted for active card
-            for (player, _) in state.player_to_vote.iter() {
-                point_change.insert(player.to_string(), 2);
+    async fn on_bot_turn(&self, round: u16, stage: RoomStage) -> Result<()> {
+        let mut state = self.state.write().await;
+
+        let mut bots: Vec<String> = state
+            .players
+            .iter()
+            .filter(|(_, info)| info.bot)
+            .map(|(player, _)| player.clone())
+            .collect();
+        bots.sort();
+
+        // bots act through the same messages players send, so every rule and
+        // transition applies to them too; a transition schedules the next turn
+        for bot in bots {
+            if state.round != round || state.stage != stage {
+                break;
             }
-            point_change.insert(active_player.clone(), 0);
-        } else {
-            // someone voted for the active card
-            for (player, card) in state.player_to_vote.iter() {
-                if card == &active_card {
-                    point_change.insert(player.to_string(), 3);
-                } else {
-                    point_change.insert(player.to_string(), 0);
-                }
+            if let Some(msg) = self.bot_move(&mut state, &bot)? {
+                self.apply_client_msg(&mut state, &bot, msg).await?;
             }
+        }
+        Ok(())
+    }
 
-            for (player, card) in state.player_to_current_card.iter() {
-                if player != &active_player {
-                    *point_change.get_mut(player).unwrap() +=
-                        votes_for_card.get(card).unwrap_or(&0);
-                }
+    // what a bot does in the current stage, if it has anything left to do
+    fn bot_move(
+        &self,
+        state: &mut RwLockWriteGuard<'_, RoomState>,
+        bot: &str,
+    ) -> Result<Option<ClientMsg>> {
+        let is_storyteller = current_storyteller(state) == Some(bot);
+        let msg = match state.stage {
+            RoomStage::ActiveChooses if is_storyteller => {
+                let (card, description) = random_clue(state, bot)?;
+                Some(ClientMsg::ActivePlayerChooseCard { card, description })
+            }
+            RoomStage::PlayersChoose
+                if !is_storyteller
+                    && !state.round_state.player_to_current_card.contains_key(bot) =>
+            {
+                let state = &mut **state;
+                state
+                    .player_hand
+                    .get(bot)
+                    .and_then(|hand| hand.choose(&mut state.rng))
+                    .map(|card| ClientMsg::PlayerChooseCard { card: card.clone() })
+            }
+            RoomStage::Voting
+                if !is_storyteller
+                    && !state.round_state.player_to_vote.contains_key(bot)
+                    && !state.round_state.sitting_out.contains(bot) =>
+            {
+                let own_card = state.round_state.player_to_current_card.get(bot);
+                let options: Vec<String> = state
+                    .round_state
+                    .center_cards
+                    .iter()
+                    .filter(|card| {
+                        Some(*card) != own_card && !self.is_teammate_card(state, bot, card)
+                    })
+                    .cloned()
+                    .collect();
+                options
+                    .choose(&mut state.rng)
+                    .map(|card| ClientMsg::Vote { card: card.clone() })
+            }
+            RoomStage::Results if state.players.get(bot).is_some_and(|p| !p.ready) => {
+                Some(ClientMsg::Ready {})
             }
+            _ => None,
+        };
+        Ok(msg)
+    }
 
-            point_change.insert(active_player.clone(), 3);
+    async fn on_storyteller_absent(&self, storyteller: &str, round: u16) -> Result<()> {
+        let mut state = self.state.write().await;
+        if state.round != round
+            || self.clock_stopped(&state)
+            || !matches!(state.stage, RoomStage::ActiveChooses)
+            || current_storyteller(&state) != Some(storyteller)
+        {
+            return Ok(());
         }
 
-        point_change
-    }
+        // reconnecting clears this, and a newer disconnect gets its own grace period
+        let Some(disconnected_at) = state.disconnected_at.get(storyteller) else {
+            return Ok(());
+        };
+        if get_time_s() - disconnected_at < STORYTELLER_GRACE_S {
+            return Ok(());
+        }
 
-    pub async fn on_connection(&self, socket: &mut WebSocket, name: &str) {
-        // public funciton
-        if let Err(e) = self.attempt_join(socket, name).await {
-            println!("Error in attempt_join: {:?}", e);
-            return;
+        // with nobody left to take the turn, wait for the seat timers instead
+        if !state.players.values().any(|p| p.connected) {
+            return Ok(());
         }
 
-        let res = self.run_ws_loop(socket, name).await;
-        println!("Player {} has left", name);
+        info!("Skipping disconnected storyteller {}", storyteller);
+        self.broadcast_msg(ServerMsg::StorytellerSkipped {
+            name: storyteller.to_string(),
+        })?;
+        self.init_round(&mut state).await
+    }
 
-        self.last_access.store(get_time_s(), Ordering::Relaxed);
+    async fn on_seat_timeout(&self, name: &str, window: u64) -> Result<()> {
         let mut state = self.state.write().await;
 
-        if matches!(state.stage, RoomStage::Joining) {
-            state.players.remove(name);
-        } else {
-            if let Some(player) = state.players.get_mut(name) {
-                player.connected = false;
-            }
+        // reconnecting clears this, and disconnecting again starts a newer timer
+        let Some(disconnected_at) = state.disconnected_at.get(name) else {
+            return Ok(());
+        };
+        if get_time_s() - disconnected_at < window || matches!(state.stage, RoomStage::Joining) {
+            return Ok(());
         }
 
-        state.player_to_socket.remove(name);
+        self.free_seat(&mut state, name).await
+    }
 
-        if let Err(e) = res {
-            println!("Error in run_ws_loop: {:?}", e);
+    async fn free_seat(
+        &self,
+        state: &mut RwLockWriteGuard<'_, RoomState>,
+        name: &str,
+    ) -> Result<()> {
+        info!("Freeing seat of {} in room {}", name, state.room_id);
+
+        state.disconnected_at.remove(name);
+        state.players.remove(name);
+        state.teams.remove(name);
+        state.session_tokens.remove(name);
+        state.storyteller_skips.remove(name);
+        state.storyteller_timeouts.remove(name);
+        state.storytellers_this_cycle.retain(|p| p != name);
+        state.arranged_hands.remove(name);
+
+        // their cards go back to the deck
+        if let Some(hand) = state.player_hand.remove(name) {
+            state.deck.extend(hand);
+        }
+
+        let mid_round = matches!(
+            state.stage,
+            RoomStage::ActiveChooses | RoomStage::PlayersChoose | RoomStage::Voting
+        );
+        let storyteller_left = state
+            .player_order
+            .get(state.active_player)
+            .map(String::as_str)
+            == Some(name);
+
+        if let Some(pos) = state.player_order.iter().position(|p| p == name) {
+            state.player_order.remove(pos);
+            let len = state.player_order.len();
+            if len > 0
+                && (pos < state.active_player || (storyteller_left && pos == state.active_player))
+            {
+                // keep pointing at the same storyteller, or just before the next one
+                state.active_player = (state.active_player + len - 1) % len;
+            }
+        }
+
+        if mid_round {
+            state.round_state.player_to_vote.remove(name);
+            state.round_state.vote_times.remove(name);
+            state.round_state.sitting_out.remove(name);
+
+            // before voting the card is still in the hand that just went back to
+            // the deck; once voting starts their decoy stays in the center
+            if matches!(
+                state.stage,
+                RoomStage::ActiveChooses | RoomStage::PlayersChoose
+            ) {
+                state.round_state.player_to_current_card.remove(name);
+            }
+        }
+
+        if state.host.as_deref() == Some(name) {
+            state.host = pick_host(state);
+        }
+
+        self.broadcast_msg(ServerMsg::SeatFreed {
+            name: name.to_string(),
+        })?;
+
+        if state.players.len() < MIN_PLAYERS {
+            if !matches!(state.stage, RoomStage::End | RoomStage::GameOver) {
+                return self.end_game(state);
+            }
+        } else if storyteller_left && mid_round {
+            // the round can't go on without its storyteller, so start the next one;
+            // cards already in the center are in the discard pile
+            return self.init_round(state).await;
+        } else {
+            // they may have been the last one everyone was waiting on
+            match state.stage {
+                RoomStage::PlayersChoose | RoomStage::Voting if everyone_acted(state) => {
+                    return self.end_stage(state);
+                }
+                RoomStage::Results => return self.advance_if_ready(state).await,
+                _ => {}
+            }
+        }
+
+        self.broadcast_state(state)
+    }
+
+    async fn on_clue_timeout(&self, timer_id: u64) -> Result<()> {
+        let mut state = self.state.write().await;
+        if state.timer_id != timer_id
+            || self.clock_stopped(&state)
+            || !matches!(state.stage, RoomStage::ActiveChooses)
+        {
+            return Ok(());
+        }
+
+        let storyteller = self.get_active_player(&state)?;
+        let threshold = state.config.silent_storyteller_threshold;
+        let timeouts = state
+            .storyteller_timeouts
+            .entry(storyteller.clone())
+            .or_insert(0);
+        *timeouts += 1;
+        let penalized = threshold > 0 && *timeouts >= threshold;
+        if penalized {
+            *timeouts = 0;
+            let skip_turns = state.config.silent_storyteller_skip_turns;
+            let penalty = state.config.silent_storyteller_penalty;
+            state
+                .storyteller_skips
+                .insert(storyteller.clone(), skip_turns);
+            if let Some(info) = state.players.get_mut(&storyteller) {
+                info.points = info.points.saturating_sub(penalty);
+            }
+        }
+
+        info!(
+            "Storyteller {} timed out (penalized: {})",
+            storyteller, penalized
+        );
+        self.broadcast_msg(ServerMsg::StorytellerTimedOut {
+            name: storyteller,
+            penalized,
+        })?;
+
+        // skip their turn
+        self.init_round(&mut state).await
+    }
+
+    // applies on_too_few_players whenever a player drops or comes back mid-game;
+    // returns true if a paused game can go on again
+    fn check_player_count(&self, state: &mut RwLockWriteGuard<'_, RoomState>) -> Result<bool> {
+        if matches!(
+            state.stage,
+            RoomStage::Joining | RoomStage::End | RoomStage::GameOver
+        ) {
+            return Ok(false);
+        }
+
+        let connected = state.players.values().filter(|p| p.connected).count();
+        if connected >= MIN_PLAYERS {
+            if state.paused_for_players {
+                info!("Room {} resumed", state.room_id);
+                state.paused_for_players = false;
+                self.broadcast_msg(ServerMsg::GameResumed {})?;
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+
+        match state.config.on_too_few_players {
+            TooFewPlayers::End => {
+                info!("Room {} ended with {} connected", state.room_id, connected);
+                self.broadcast_msg(ServerMsg::TooFewPlayers {
+                    connected,
+                    paused: false,
+                })?;
+                self.end_game(state)?;
+            }
+            TooFewPlayers::Pause if !state.paused_for_players => {
+                info!("Room {} paused with {} connected", state.room_id, connected);
+                state.paused_for_players = true;
+                self.broadcast_msg(ServerMsg::TooFewPlayers {
+                    connected,
+                    paused: true,
+                })?;
+            }
+            TooFewPlayers::Pause => {}
+        }
+        Ok(false)
+    }
+
+    // everything a round leaves behind, cleared before the next one is dealt
+    fn reset_round_state(&self, state: &mut RwLockWriteGuard<'_, RoomState>) {
+        state.round_state = RoundState::default();
+    }
+
+    async fn init_round(&self, state: &mut RwLockWriteGuard<'_, RoomState>) -> Result<()> {
+        if state.players.len() < MIN_PLAYERS {
+            return Err(anyhow!("Not enough players"));
+        }
+
+        // not enough cards, reload
+        if state.round > 0 {
+            self.check_deck(state);
+        }
+
+        // shuffle deck
+        {
+            let state = &mut **state;
+            state.deck.shuffle(&mut state.rng);
+        }
+        if state.config.weighted_dealing {
+            self.weight_deck(state);
+        }
+
+        // ensure all players have a full hand; dealt into copies so a short
+        // deck ends the game with the room as it was
+        let mut player_hand = state.player_hand.clone();
+
+        let mut deck = state.deck.clone();
+        let hand_size = state.config.hand_size();
+        let mut dealt = Vec::new();
+        // hashmap order is random, sort so the seed alone decides who gets what
+        let mut players: Vec<String> = state.players.keys().cloned().collect();
+        players.sort();
+        for player in &players {
+            let hand = player_hand.entry(player.clone()).or_default();
+            let before = hand.len();
+
+            // a reloaded deck can hold a card the player already has; set it
+            // aside for someone else and draw the next one
+            let mut skipped = Vec::new();
+            while hand.len() < hand_size {
+                // even a reload can come up short when the deck is small
+                let Some(card) = deck.pop() else {
+                    info!(
+                        "Room {} ran out of cards dealing to {}",
+                        state.room_id, player
+                    );
+                    return self.deck_exhausted(state);
+                };
+                if hand.contains(&card) {
+                    skipped.push(card);
+                    continue;
+                }
+                dealt.push(card.clone());
+                hand.push(card);
+            }
+            deck.extend(skipped.into_iter().rev());
+
+            debug_assert!(
+                hand.iter().collect::<HashSet<_>>().len() == hand.len(),
+                "duplicate card dealt to {}",
+                player
+            );
+
+            if matches!(state.config.hand_order, HandOrder::Shuffle)
+                && hand.len() != before
+                && !state.arranged_hands.contains(player)
+            {
+                hand.shuffle(&mut state.rng);
+            }
+        }
+
+        state.round += 1;
+        state.rounds_played += 1;
+
+        // finalize players
+        if state.round == 1 {
+            // first round
+            state.active_player = 0;
+            state.storytellers_this_cycle.clear();
+            state.round_history.clear();
+            state.used_clues.clear();
+            let game_id = uuid::Uuid::new_v4().to_string();
+            info!("Room {} starting game {}", state.room_id, game_id);
+            state.game_id = Some(game_id);
+            state.player_order = state.players.keys().cloned().collect::<Vec<_>>();
+            // hashmap order is random, sort so the seed alone decides the order
+            state.player_order.sort();
+            let state = &mut **state;
+            state.player_order.shuffle(&mut state.rng);
+
+            if let Some(commitment) = shuffle_commitment(state) {
+                info!("Room {} shuffle commitment {}", state.room_id, commitment);
+            }
+        } else {
+            state.active_player = self.next_storyteller(state);
+        }
+
+        if state.round == 1 && state.config.team_mode {
+            self.assign_teams(state);
+        }
+
+        if state.round == 1 {
+            events::publish(
+                &self.events,
+                Event::GameStarted {
+                    room_id: state.room_id.clone(),
+                    game_id: state.game_id.clone(),
+                    players: state.player_order.clone(),
+                },
+            );
+        }
+
+        let storyteller = current_storyteller(state)
+            .ok_or_else(|| anyhow!("No storyteller at position {}", state.active_player))?
+            .to_string();
+        state.storytellers_this_cycle.push(storyteller.clone());
+        state
+            .player_stats
+            .entry(storyteller)
+            .or_default()
+            .times_storyteller += 1;
+
+        self.reset_round_state(state);
+
+        for card in dealt {
+            *state.card_deal_count.entry(card).or_insert(0) += 1;
+        }
+
+        state.deck = deck;
+        state.player_hand = player_hand;
+        state.stage = RoomStage::ActiveChooses;
+
+        // notify players of the game start and their hands
+        for player in state.player_order.iter() {
+            let _ = self
+                .send_msg(state, player, self.get_msg(Some(player), state)?)
+                .await;
+        }
+
+        self.clear_ready(state);
+        if state.config.round_start_ack && state.config.clue_timeout_s.is_some() {
+            self.start_round_ack_timer(state);
+        } else {
+            self.start_clue_timer(state);
+        }
+        self.broadcast_state(state)?;
+        self.broadcast_stage_progress(state)?;
+        self.broadcast_msg(ServerMsg::DeckRemaining {
+            remaining: state.deck.len(),
+        })?;
+        // the turn may have gone to someone who isn't connected
+        self.start_storyteller_grace(state);
+        self.schedule_bots(state);
+
+        Ok(())
+    }
+
+    fn deck_exhausted(&self, state: &mut RwLockWriteGuard<RoomState>) -> Result<()> {
+        let standings = standings(state);
+        let winner = standings
+            .iter()
+            .find(|standing| standing.rank == 1)
+            .map(|standing| standing.name.clone())
+            .unwrap_or_default();
+        let final_scores = state
+            .players
+            .iter()
+            .map(|(player, info)| (player.clone(), info.points))
+            .collect();
+
+        self.broadcast_msg(ServerMsg::GameOver {
+            final_scores,
+            winner,
+            standings,
+        })?;
+        self.finish_game(state, RoomStage::GameOver)
+    }
+
+    // back to the lobby with the same room and whoever is still connected
+    fn rematch(&self, state: &mut RwLockWriteGuard<RoomState>) -> Result<()> {
+        info!("Room {} starting a rematch", state.room_id);
+
+        // seats left empty would hold up the lobby forever; bots are added again
+        // by the host if they're still wanted
+        let gone: Vec<String> = state
+            .players
+            .iter()
+            .filter(|(_, info)| !info.connected || info.bot)
+            .map(|(player, _)| player.clone())
+            .collect();
+        for player in gone {
+            state.players.remove(&player);
+            state.teams.remove(&player);
+            state.session_tokens.remove(&player);
+            state.disconnected_at.remove(&player);
+        }
+        if state
+            .host
+            .as_ref()
+            .is_some_and(|host| !state.players.contains_key(host))
+        {
+            state.host = pick_host(state);
+        }
+
+        for info in state.players.values_mut() {
+            info.points = 0;
+            info.ready = false;
+        }
+
+        // stale timers from the last game see the new id and do nothing
+        state.timer_id += 1;
+        state.stage = RoomStage::Joining;
+        state.round = 0;
+        state.deck = self.base_deck.to_vec();
+        state.discard.clear();
+        state.player_hand.clear();
+        state.arranged_hands.clear();
+        state.player_order.clear();
+        state.active_player = 0;
+        state.rematch_votes.clear();
+        state.storyteller_timeouts.clear();
+        state.storyteller_skips.clear();
+        state.paused_for_players = false;
+        self.reset_round_state(state);
+
+        self.broadcast_state(state)?;
+        Ok(())
+    }
+
+    // the new name if the rename went through; the player is told why otherwise
+    async fn rename_player(
+        &self,
+        state: &mut RwLockWriteGuard<'_, RoomState>,
+        name: &str,
+        new_name: &str,
+    ) -> Result<Option<String>> {
+        let (code, message) = if !matches!(state.stage, RoomStage::Joining) {
+            (
+                ErrorCode::GameStarted,
+                "Names can't change once the game has started".to_string(),
+            )
+        } else {
+            match crate::name::normalize_name(new_name, self.max_name_len) {
+                Err(code) => (
+                    code,
+                    format!("Name must be at most {} characters", self.max_name_len),
+                ),
+                Ok(new_name) if new_name.is_empty() => {
+                    (ErrorCode::EmptyName, "Name cannot be empty".to_string())
+                }
+                Ok(new_name) if new_name == name => return Ok(None),
+                Ok(new_name)
+                    if state.players.contains_key(&new_name)
+                        || state.spectators.contains_key(&new_name) =>
+                {
+                    (ErrorCode::NameTaken, "Name already taken".to_string())
+                }
+                Ok(new_name) if state.kicked.contains(&new_name) => (
+                    ErrorCode::Kicked,
+                    "That name was removed from this room".to_string(),
+                ),
+                Ok(new_name) => {
+                    info!("{} is now {} in room {}", name, new_name, state.room_id);
+                    let renamed = &mut **state;
+                    rekey(&mut renamed.players, name, &new_name);
+                    rekey(&mut renamed.player_hand, name, &new_name);
+                    rekey(&mut renamed.player_to_socket, name, &new_name);
+                    rekey(&mut renamed.teams, name, &new_name);
+                    rekey(&mut renamed.session_tokens, name, &new_name);
+                    rekey(&mut renamed.capabilities, name, &new_name);
+                    // what the room remembers about the player goes with them
+                    rekey(&mut renamed.player_stats, name, &new_name);
+                    rekey(&mut renamed.win_streaks, name, &new_name);
+                    rekey(&mut renamed.muted_until_s, name, &new_name);
+                    rekey(&mut renamed.last_report_s, name, &new_name);
+                    if renamed.host.as_deref() == Some(name) {
+                        renamed.host = Some(new_name.clone());
+                    }
+
+                    let msg = ServerMsg::Renamed {
+                        name: new_name.clone(),
+                    };
+                    self.send_msg(state, &new_name, msg).await?;
+                    self.broadcast_state(state)?;
+                    return Ok(Some(new_name));
+                }
+            }
+        };
+
+        self.send_msg(state, name, ServerMsg::error(code, &message))
+            .await?;
+        Ok(None)
+    }
+
+    fn end_game(&self, state: &mut RwLockWriteGuard<RoomState>) -> Result<()> {
+        self.finish_game(state, RoomStage::End)
+    }
+
+    // stage is End, or GameOver when the deck ran out
+    fn finish_game(&self, state: &mut RwLockWriteGuard<RoomState>, stage: RoomStage) -> Result<()> {
+        state.stage = stage;
+        state.paused_for_players = false;
+        state.games_played += 1;
+        info!("Room {} finished game {:?}", state.room_id, state.game_id);
+
+        // everyone tied for the most points extends their streak, the rest reset
+        let (_, winners) = self.leaders(state);
+
+        for player in state.players.keys().cloned().collect::<Vec<_>>() {
+            let won = winners.contains(&player);
+
+            let streak = state.win_streaks.entry(player.clone()).or_insert(0);
+            if won {
+                *streak += 1;
+            } else {
+                *streak = 0;
+            }
+
+            let stats = state.player_stats.entry(player).or_default();
+            stats.games_played += 1;
+            if won {
+                stats.games_won += 1;
+            }
+        }
+
+        events::publish(
+            &self.events,
+            Event::GameOver {
+                room_id: state.room_id.clone(),
+                game_id: state.game_id.clone(),
+                standings: standings(state),
+            },
+        );
+
+        self.broadcast_msg(self.get_msg(None, state)?)?;
+        self.broadcast_state(state)?;
+
+        Ok(())
+    }
+
+    // a rename changes `name` for the rest of the connection
+    pub async fn handle_client_msg(&self, name: &mut String, msg: WsMessage) -> Result<()> {
+        let mut state = self.state.write().await;
+
+        // clients may send msgpack in binary frames whatever they receive
+        let envelope = match &msg {
+            WsMessage::Binary(bytes) => rmp_serde::from_slice(bytes).map_err(anyhow::Error::from),
+            _ => serde_json::from_str(msg.to_text()?).map_err(anyhow::Error::from),
+        };
+        let ClientEnvelope { msg, msg_id } =
+            envelope.context(format!("Failed to deserialize client msg: {:?}", msg))?;
+
+        debug!("Handling client message: {:?}", msg);
+
+        if state.spectators.contains_key(name) {
+            // spectators can only watch, talk among themselves and vote in the
+            // audience poll; directors can also drive the game
+            match msg {
+                ClientMsg::Chat { text } => self.spectator_chat(&mut state, name, &text),
+                ClientMsg::Ping {} => self.send_msg(&state, name, ServerMsg::Pong {}).await?,
+                ClientMsg::GetScoreboard {} => {
+                    self.send_msg(&state, name, scoreboard(&state)).await?
+                }
+                ClientMsg::Vote { card }
+                    if state.config.audience_poll
+                        && matches!(state.stage, RoomStage::Voting)
+                        && state
+                            .round_state
+                            .player_to_current_card
+                            .values()
+                            .any(|c| c == &card) =>
+                {
+                    state
+                        .round_state
+                        .audience_votes
+                        .insert(name.to_string(), card);
+                }
+                msg if state.directors.contains(name) => {
+                    self.apply_director_msg(&mut state, msg).await?;
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        let messages = match msg {
+            ClientMsg::Batch { messages } if messages.len() > MAX_BATCH_LEN => {
+                self.send_msg(
+                    &state,
+                    name,
+                    ServerMsg::error(
+                        ErrorCode::BatchTooLarge,
+                        &format!("At most {} messages per batch", MAX_BATCH_LEN),
+                    ),
+                )
+                .await?;
+                return Ok(());
+            }
+            ClientMsg::Batch { messages } => messages,
+            // not allowed in a batch, where the rest would still go by the old name
+            ClientMsg::Rename { new_name } => {
+                if let Some(new_name) = self.rename_player(&mut state, name, &new_name).await? {
+                    tracing::Span::current().record("player", new_name.as_str());
+                    *name = new_name;
+                }
+                vec![]
+            }
+            msg => vec![msg],
+        };
+
+        // a batch nested in a batch is ignored like any unknown message
+        for msg in messages {
+            self.apply_client_msg(&mut state, name, msg).await?;
+        }
+
+        // errors end the connection, so reaching here means the message was handled
+        let wants_acks = state
+            .capabilities
+            .get(name)
+            .is_some_and(|caps| caps.contains(&Capability::Acks));
+        if let Some(msg_id) = msg_id.filter(|_| wants_acks) {
+            self.send_msg(&state, name, ServerMsg::Ack { msg_id })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // directors aren't players, so nothing here touches ready flags or scores directly
+    async fn apply_director_msg(
+        &self,
+        state: &mut RwLockWriteGuard<'_, RoomState>,
+        msg: ClientMsg,
+    ) -> Result<()> {
+        match msg {
+            ClientMsg::Advance {} => match state.stage {
+                RoomStage::Joining | RoomStage::Results => self.advance(state, true).await?,
+                RoomStage::PlayersChoose => self.init_voting(state)?,
+                _ => {}
+            },
+            ClientMsg::Reveal {} if matches!(state.stage, RoomStage::Voting) => {
+                self.init_results(state)?;
+            }
+            ClientMsg::PauseTimers { paused } if paused != state.timers_paused => {
+                state.timers_paused = paused;
+                if !paused {
+                    self.restart_stage_clock(state);
+                }
+            }
+            _ => {
+                // nothing
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn apply_client_msg(
+        &self,
+        state: &mut RwLockWriteGuard<'_, RoomState>,
+        name: &str,
+        msg: ClientMsg,
+    ) -> Result<()> {
+        match msg {
+            ClientMsg::Ready {}
+                if (matches!(state.stage, RoomStage::Joining)
+                    || matches!(state.stage, RoomStage::Results)) =>
+            {
+                let player = state
+                    .players
+                    .get_mut(name)
+                    .ok_or_else(|| anyhow!("Unreachable: cannot ready player {}", name))?;
+                // only a player becoming ready can make everyone ready
+                if !player.ready {
+                    player.ready = true;
+                    self.broadcast_state(state)?;
+                    self.advance_if_ready(state).await?;
+                }
+            }
+            // the round starts the moment everyone is ready, so this only helps before then
+            ClientMsg::Unready {}
+                if matches!(state.stage, RoomStage::Joining | RoomStage::Results) =>
+            {
+                let player = state
+                    .players
+                    .get_mut(name)
+                    .ok_or_else(|| anyhow!("Unreachable: cannot unready player {}", name))?;
+                if player.ready {
+                    player.ready = false;
+                    self.broadcast_state(state)?;
+                }
+            }
+            ClientMsg::ActivePlayerChooseCard { card, description }
+                if matches!(state.stage, RoomStage::ActiveChooses)
+                    && current_storyteller(state) == Some(name) =>
+            {
+                self.submit_clue(state, name, card, &description).await?;
+            }
+            ClientMsg::ActivePlayerChooseCard { card, description }
+                if matches!(state.stage, RoomStage::PlayersChoose)
+                    && current_storyteller(state) == Some(name) =>
+            {
+                if state.round_state.player_to_current_card.len() > 1 {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(
+                            ErrorCode::ClueLocked,
+                            "Someone has already picked a card for this clue",
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                // the old clue is free again, unless the revision is turned down
+                let previous = state.round_state.current_description.to_lowercase();
+                state.used_clues.remove(&previous);
+                self.submit_clue(state, name, card, &description).await?;
+                if state.round_state.current_description.to_lowercase() == previous {
+                    state.used_clues.insert(previous);
+                }
+            }
+            ClientMsg::RandomClue {}
+                if matches!(state.stage, RoomStage::ActiveChooses)
+                    && current_storyteller(state) == Some(name)
+                    && state.config.allow_random_clue =>
+            {
+                let (card, clue) = random_clue(state, name)?;
+                self.submit_clue(state, name, card, &clue).await?;
+            }
+            ClientMsg::PlayerChooseCard { card }
+                if matches!(state.stage, RoomStage::PlayersChoose)
+                    && current_storyteller(state) != Some(name) =>
+            {
+                let Some(hand) = state.player_hand.get(name) else {
+                    return Err(anyhow!("No hand for {}", name));
+                };
+                // verify that player has this card
+                if !hand.contains(&card) {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(ErrorCode::InvalidCard, "That card is not in your hand"),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                // a pick can be changed until voting starts; only the latest counts,
+                // and the player was already counted as ready the first time
+                if state.round_state.player_to_current_card.get(name) == Some(&card) {
+                    return Ok(());
+                }
+
+                // record choice
+                state
+                    .round_state
+                    .player_to_current_card
+                    .insert(name.to_string(), card.to_string());
+
+                // ready
+                let Some(player) = state.players.get_mut(name) else {
+                    return Err(anyhow!("Unreachable: cannot ready player {}", name));
+                };
+                player.ready = true;
+                self.broadcast_state(state)?;
+                self.broadcast_progress(state)?;
+                self.broadcast_stage_progress(state)?;
+
+                if everyone_acted(state) {
+                    self.init_voting(state)?;
+                }
+            }
+            ClientMsg::Vote { card } => {
+                if matches!(state.stage, RoomStage::Voting) {
+                    // verify that the player is not the active player
+                    if current_storyteller(state) == Some(name) {
+                        if state.config.storyteller_can_vote {
+                            // recorded for display only: not scored and not counted as ready
+                            if state.round_state.player_to_current_card.get(name) != Some(&card)
+                                && state
+                                    .round_state
+                                    .player_to_current_card
+                                    .values()
+                                    .any(|e| e == &card)
+                            {
+                                state.round_state.storyteller_vote = Some(card);
+                            }
+                            return Ok(());
+                        }
+
+                        info!("{} is the active player and is trying to vote", name);
+                        self.send_msg(
+                            state,
+                            name,
+                            ServerMsg::error(ErrorCode::InvalidVote, "The storyteller cannot vote"),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+
+                    // verify that the card is in the center
+                    if !state
+                        .round_state
+                        .player_to_current_card
+                        .values()
+                        .any(|e| e == &card)
+                    {
+                        self.send_msg(
+                            state,
+                            name,
+                            ServerMsg::error(ErrorCode::InvalidCard, "That card is not in play"),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+
+                    if self.is_teammate_card(state, name, &card) {
+                        self.send_msg(
+                            state,
+                            name,
+                            ServerMsg::error(
+                                ErrorCode::InvalidVote,
+                                "You cannot vote for your teammate's card",
+                            ),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+
+                    if state.round_state.sitting_out.contains(name) {
+                        self.send_msg(
+                            state,
+                            name,
+                            ServerMsg::error(
+                                ErrorCode::InvalidVote,
+                                "You have no card in this round, so you cannot vote",
+                            ),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+
+                    // verify that this player is not voting for their own code or send an error message
+                    if state.round_state.player_to_current_card.get(name) == Some(&card) {
+                        self.send_msg(
+                            state,
+                            name,
+                            ServerMsg::error(
+                                ErrorCode::SelfVote,
+                                "You cannot vote for your own card",
+                            ),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+
+                    // a vote can be changed until results are in; only the latest
+                    // counts, and it is timed from when it was changed
+                    if state.round_state.player_to_vote.get(name) == Some(&card) {
+                        return Ok(());
+                    }
+
+                    // record vote
+                    let previous = state
+                        .round_state
+                        .player_to_vote
+                        .insert(name.to_string(), card.to_string());
+                    state
+                        .round_state
+                        .vote_times
+                        .insert(name.to_string(), get_time_ms());
+
+                    // ready
+                    let Some(player) = state.players.get_mut(name) else {
+                        return Err(anyhow!("Unreachable: cannot ready player {}", name));
+                    };
+                    player.ready = true;
+                    self.broadcast_state(state)?;
+                    self.broadcast_progress(state)?;
+
+                    if state.config.reveal_vote_progress {
+                        // only those who can still vote; the rest are filled in at the end
+                        let storyteller = current_storyteller(state);
+                        let total = state
+                            .players
+                            .iter()
+                            .filter(|(player, info)| {
+                                info.connected
+                                    && Some(player.as_str()) != storyteller
+                                    && !state.round_state.sitting_out.contains(*player)
+                            })
+                            .count();
+                        self.broadcast_msg(ServerMsg::VotingProgress {
+                            cast: state.round_state.player_to_vote.len(),
+                            total,
+                        })?;
+                    }
+                    self.broadcast_stage_progress(state)?;
+
+                    if state.config.live_votes_to_storyteller {
+                        let storyteller = self.get_active_player(state)?;
+                        let storyteller_card =
+                            state.round_state.player_to_current_card.get(&storyteller);
+                        // moving a vote off their card changes the count too
+                        if storyteller_card == Some(&card) || previous.as_ref() == storyteller_card
+                        {
+                            let votes_for_your_card = state
+                                .round_state
+                                .player_to_vote
+                                .values()
+                                .filter(|vote| Some(*vote) == storyteller_card)
+                                .count();
+                            // the storyteller may be disconnected
+                            let _ = self
+                                .send_msg(
+                                    state,
+                                    &storyteller,
+                                    ServerMsg::LiveVote {
+                                        votes_for_your_card,
+                                    },
+                                )
+                                .await;
+                        }
+                    }
+
+                    if everyone_acted(state) {
+                        self.init_results(state)?;
+                    } else {
+                        self.skip_voting_if_stuck(state)?;
+                    }
+                }
+            }
+            ClientMsg::SetTeam { team }
+                if matches!(state.stage, RoomStage::Joining) && state.config.team_mode =>
+            {
+                if team >= state.config.team_count.unwrap_or(2) {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(ErrorCode::InvalidTeam, "Invalid team"),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                state.teams.insert(name.to_string(), team);
+                self.broadcast_state(state)?;
+            }
+            // teams are kept by name through disconnects, so a reconnect
+            // can't be used to land on a different team either
+            ClientMsg::SetTeam { .. } if state.config.team_mode => {
+                self.send_msg(
+                    state,
+                    name,
+                    ServerMsg::error(
+                        ErrorCode::TeamsLocked,
+                        "Teams are locked once the game starts",
+                    ),
+                )
+                .await?;
+            }
+            ClientMsg::SetHandOrder { order } => {
+                // the hand is kept in this order: new cards are appended and
+                // played cards removed, so it survives reconnects
+                let Some(hand) = state.player_hand.get(name) else {
+                    return Ok(());
+                };
+
+                let mut sorted_order = order.clone();
+                sorted_order.sort();
+                let mut sorted_hand = hand.clone();
+                sorted_hand.sort();
+                if sorted_order != sorted_hand {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(
+                            ErrorCode::InvalidHandOrder,
+                            "Hand order does not match your hand",
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                state.player_hand.insert(name.to_string(), order);
+                state.arranged_hands.insert(name.to_string());
+            }
+            ClientMsg::Rematch {}
+                if matches!(state.stage, RoomStage::End | RoomStage::GameOver) =>
+            {
+                if state
+                    .config
+                    .max_games
+                    .is_some_and(|max| state.games_played >= max)
+                {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(
+                            ErrorCode::GameLimitReached,
+                            "This room has played its last game, create a new room",
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                state.rematch_votes.insert(name.to_string());
+                let connected: Vec<String> = state
+                    .players
+                    .iter()
+                    .filter(|(_, info)| info.connected)
+                    .map(|(player, _)| player.clone())
+                    .collect();
+                let everyone = connected.iter().all(|p| state.rematch_votes.contains(p));
+
+                if everyone || state.host.as_deref() == Some(name) {
+                    self.rematch(state)?;
+                } else {
+                    let mut votes: Vec<String> = state.rematch_votes.iter().cloned().collect();
+                    votes.sort();
+                    self.broadcast_msg(ServerMsg::RematchVotes {
+                        votes,
+                        needed: connected.len(),
+                    })?;
+                }
+            }
+            ClientMsg::AddBot {}
+                if state.host.as_deref() == Some(name)
+                    && matches!(state.stage, RoomStage::Joining) =>
+            {
+                if state.players.len() >= state.config.max_players() {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(ErrorCode::RoomFull, "The room is full"),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                let bot = crate::name::random_name(|n| {
+                    state.players.contains_key(n) || state.spectators.contains_key(n)
+                });
+                info!("Room {} added bot {}", state.room_id, bot);
+                // bots are always ready in the lobby
+                let color = free_color(state).to_string();
+                state.players.insert(
+                    bot,
+                    PlayerInfo {
+                        connected: true,
+                        points: 0,
+                        ready: true,
+                        bot: true,
+                        color,
+                    },
+                );
+                self.broadcast_state(state)?;
+                self.send_deck_check(state).await;
+            }
+            ClientMsg::Kick { name: target }
+                if state.host.as_deref() == Some(name) && target != name =>
+            {
+                let removed = ServerMsg::error(
+                    ErrorCode::Kicked,
+                    "You were removed from the room by the host",
+                );
+                // dropping the sender ends the connection's loop once this message is out
+                if let Some(tx) = state.spectators.remove(&target) {
+                    let _ = tx.try_send(removed);
+                    state.directors.remove(&target);
+                } else if state.players.contains_key(&target) {
+                    if let Some(tx) = state.player_to_socket.remove(&target) {
+                        let _ = tx.try_send(removed);
+                    }
+                    if matches!(state.stage, RoomStage::Joining) {
+                        state.players.remove(&target);
+                        state.teams.remove(&target);
+                        state.session_tokens.remove(&target);
+                    } else {
+                        self.free_seat(state, &target).await?;
+                    }
+                } else {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(
+                            ErrorCode::UnknownPlayer,
+                            &format!("{} is not in the room", target),
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                info!("{} kicked {} from room {}", name, target, state.room_id);
+                state.kicked.insert(target);
+                self.broadcast_state(state)?;
+            }
+            ClientMsg::Mute {
+                name: target,
+                duration_s,
+            } if state.host.as_deref() == Some(name) => {
+                if !state.spectators.contains_key(&target) && !state.players.contains_key(&target) {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(
+                            ErrorCode::UnknownPlayer,
+                            &format!("{} is not in the room", target),
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                let until_s = get_time_s() + duration_s.min(MAX_MUTE_S);
+                info!("{} muted {} until {}", name, target, until_s);
+                state.muted_until_s.insert(target.clone(), until_s);
+                self.broadcast_msg(ServerMsg::PlayerMuted {
+                    name: target,
+                    until_s,
+                })?;
+            }
+            ClientMsg::TransferHost { to } if state.host.as_deref() == Some(name) => {
+                if !state.players.get(&to).is_some_and(|p| p.connected) {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(
+                            ErrorCode::UnknownPlayer,
+                            &format!("{} is not connected", to),
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                info!(
+                    "Host of room {} passed from {} to {}",
+                    state.room_id, name, to
+                );
+                state.host = Some(to);
+                self.broadcast_state(state)?;
+                self.send_deck_check(state).await;
+            }
+            ClientMsg::Ping {} => {
+                self.send_msg(state, name, ServerMsg::Pong {}).await?;
+            }
+            ClientMsg::GetMyStats {} => {
+                let stats = state.player_stats.get(name).cloned().unwrap_or_default();
+                self.send_msg(state, name, ServerMsg::MyStats(stats))
+                    .await?;
+            }
+            // a connection belongs to the room it first joined for its whole life
+            ClientMsg::JoinRoom { .. } => {
+                self.send_msg(
+                    state,
+                    name,
+                    ServerMsg::error(
+                        ErrorCode::AlreadyJoined,
+                        "This connection has already joined a room",
+                    ),
+                )
+                .await?;
+            }
+            ClientMsg::UpdateSettings { settings }
+                if state.host.as_deref() == Some(name)
+                    && matches!(state.stage, RoomStage::Joining) =>
+            {
+                let mut settings = *settings;
+                // these shaped the room when it was created and stay as they were
+                settings.seed = state.config.seed;
+                settings.director = state.config.director;
+                settings.pool_size = state.config.pool_size;
+                settings.orientation = state.config.orientation;
+                settings.deck_id = state.config.deck_id.clone();
+                settings.deck_theme = state.config.deck_theme.clone();
+                settings.password = state.config.password.clone();
+                settings.idle_timeout_s = state.config.idle_timeout_s;
+
+                if let Err(e) = settings.validate() {
+                    self.send_msg(state, name, ServerMsg::error(ErrorCode::InvalidConfig, e))
+                        .await?;
+                    return Ok(());
+                }
+
+                // the same check the room was created with, against the deck it got;
+                // an uploaded deck also needs a round's worth to reshuffle from
+                let needed = settings.cards_for_full_room()
+                    + settings
+                        .deck_id
+                        .as_ref()
+                        .map_or(0, |_| settings.max_players());
+                if self.base_deck.len() < needed {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(
+                            ErrorCode::DeckTooSmall,
+                            &format!(
+                                "This room's deck has {} cards, those settings need at least {}",
+                                self.base_deck.len(),
+                                needed
+                            ),
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                info!("Room {} settings updated by {}", state.room_id, name);
+                if broadcast_capacity(settings.max_players())
+                    != broadcast_capacity(state.config.max_players())
+                {
+                    self.resize_broadcast(settings.max_players());
+                }
+                state.config = settings;
+                self.broadcast_msg(room_settings(state))?;
+                self.broadcast_state(state)?;
+                self.send_deck_check(state).await;
+            }
+            ClientMsg::UpdateSettings { .. } => {
+                self.send_msg(
+                    state,
+                    name,
+                    ServerMsg::error(
+                        ErrorCode::NotHost,
+                        "Only the host can change settings, and only before the game starts",
+                    ),
+                )
+                .await?;
+            }
+            ClientMsg::GetHistory {} => {
+                let rounds = state.round_history.clone();
+                self.send_msg(state, name, ServerMsg::History { rounds })
+                    .await?;
+            }
+            ClientMsg::GetScoreboard {} => {
+                self.send_msg(state, name, scoreboard(state)).await?;
+            }
+            ClientMsg::GetSettings {} => {
+                self.send_msg(state, name, room_settings(state)).await?;
+            }
+            ClientMsg::RoundReady {}
+                if matches!(state.stage, RoomStage::ActiveChooses)
+                    && state.config.round_start_ack
+                    && !state.round_state.clue_timer_started =>
+            {
+                state.round_state.round_acks.insert(name.to_string());
+                let all_acked = state
+                    .players
+                    .iter()
+                    .filter(|(_, info)| info.connected && !info.bot)
+                    .all(|(player, _)| state.round_state.round_acks.contains(player));
+                if all_acked {
+                    self.start_clue_timer(state);
+                }
+            }
+            ClientMsg::Report { target, reason } => {
+                let now = get_time_s();
+                let too_soon = state
+                    .last_report_s
+                    .get(name)
+                    .is_some_and(|last| now - last < REPORT_INTERVAL_S);
+                let reason = reason.trim();
+                if too_soon
+                    || target == name
+                    || !state.players.contains_key(&target)
+                    || reason.graphemes(true).count() > MAX_REPORT_REASON_LEN
+                {
+                    self.send_msg(
+                        state,
+                        name,
+                        ServerMsg::error(ErrorCode::ReportRejected, "Report not accepted"),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                let report = Report {
+                    room_id: state.room_id.clone(),
+                    reporter: name.to_string(),
+                    target,
+                    reason: reason.to_string(),
+                    at_s: now,
+                };
+                info!("(report) {:?}", report);
+                state.last_report_s.insert(name.to_string(), now);
+                if state.reports.len() >= MAX_REPORTS {
+                    state.reports.remove(0);
+                }
+                state.reports.push(report);
+            }
+            ClientMsg::GetReadyStatus {} => {
+                let mut ready = Vec::new();
+                let mut not_ready = Vec::new();
+                for (player, info) in state.players.iter() {
+                    if info.ready {
+                        ready.push(player.clone());
+                    } else {
+                        not_ready.push(player.clone());
+                    }
+                }
+                ready.sort();
+                not_ready.sort();
+                self.send_msg(state, name, ServerMsg::ReadyStatus { ready, not_ready })
+                    .await?;
+            }
+            ClientMsg::TimeSync {} => {
+                let now_s = get_time_s();
+                self.send_msg(state, name, ServerMsg::ServerTime { now_s })
+                    .await?;
+            }
+            _ => {
+                // nothing
+            }
+        }
+
+        Ok(())
+    }
+
+    // the storyteller's card and clue, whether typed or picked at random
+    async fn submit_clue(
+        &self,
+        state: &mut RwLockWriteGuard<'_, RoomState>,
+        name: &str,
+        card: String,
+        description: &str,
+    ) -> Result<()> {
+        let Some(hand) = state.player_hand.get(name) else {
+            return Err(anyhow!("No hand for {}", name));
+        };
+        // verify that player has this card
+        if !hand.contains(&card) {
+            self.send_msg(
+                state,
+                name,
+                ServerMsg::error(ErrorCode::InvalidCard, "That card is not in your hand"),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let description = description.trim();
+        // verify that the description is not empty, and is one word if the room says so
+        if description.is_empty() {
+            if let Some(tx) = state.player_to_socket.get(name) {
+                tx.send(ServerMsg::error(
+                    ErrorCode::EmptyDescription,
+                    "Description must not be empty",
+                ))
+                .await?;
+            }
+            return Ok(());
+        }
+
+        if description.graphemes(true).count() > MAX_CLUE_LEN {
+            self.send_msg(
+                state,
+                name,
+                ServerMsg::error(
+                    ErrorCode::ClueTooLong,
+                    &format!("Description must be at most {} characters", MAX_CLUE_LEN),
+                ),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        if state.config.one_word_clues && description.split_whitespace().count() > 1 {
+            self.send_msg(
+                state,
+                name,
+                ServerMsg::error(ErrorCode::ClueNotOneWord, "Description must be one word"),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let clue = description.to_lowercase();
+        if state.config.unique_clues && state.used_clues.contains(&clue) {
+            self.send_msg(
+                state,
+                name,
+                ServerMsg::error(
+                    ErrorCode::DuplicateClue,
+                    "That clue has already been used this game",
+                ),
+            )
+            .await?;
+            return Ok(());
+        }
+        state.used_clues.insert(clue);
+
+        state.round_state.current_description = description.to_string();
+        state.stage = RoomStage::PlayersChoose;
+        state.storyteller_timeouts.remove(name);
+
+        // record choice
+        state
+            .round_state
+            .player_to_current_card
+            .insert(name.to_string(), card.to_string());
+
+        // notify players of the active player's choice
+        for player in state.player_order.iter() {
+            let _ = self
+                .send_msg(state, player, self.get_msg(Some(player), state)?)
+                .await;
+        }
+
+        self.clear_ready(state);
+        self.broadcast_state(state)?;
+        self.broadcast_stage_progress(state)?;
+        self.start_stage_timer(state);
+        self.schedule_bots(state);
+
+        Ok(())
+    }
+
+    // called whenever a player readies up in Joining or Results
+    async fn advance_if_ready(&self, state: &mut RwLockWriteGuard<'_, RoomState>) -> Result<()> {
+        self.advance(state, false).await
+    }
+
+    // forced advances come from the director and don't wait for everyone to be ready
+    async fn advance(
+        &self,
+        state: &mut RwLockWriteGuard<'_, RoomState>,
+        force: bool,
+    ) -> Result<()> {
+        // if any player (or team) has reached the target score, end game
+        let (max_points, _) = self.leaders(state);
+
+        if max_points >= state.config.target_score() {
+            return self.end_game(state);
+        }
+
+        if state.paused_for_players && !force {
+            return Ok(());
+        }
+
+        // otherwise, check if everyone is ready for next round
+        if force || state.players.values().filter(|p| p.ready).count() == state.players.len() {
+            let game_limit_reached = state.round == 0
+                && state
+                    .config
+                    .max_games
+                    .is_some_and(|max| state.games_played >= max);
+
+            if game_limit_reached {
+                self.broadcast_msg(ServerMsg::error(
+                    ErrorCode::GameLimitReached,
+                    "This room has played its last game, create a new room",
+                ))?;
+            } else if state.players.len() >= MIN_PLAYERS {
+                self.init_round(state).await?;
+            } else {
+                self.broadcast_msg(ServerMsg::error(
+                    ErrorCode::NotEnoughPlayers,
+                    "Need at least 3 players",
+                ))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // only achievements the room has given a value are awarded
+    fn compute_achievements(
+        &self,
+        state: &RwLockWriteGuard<RoomState>,
+    ) -> HashMap<String, Vec<Achievement>> {
+        let mut achievements: HashMap<String, Vec<Achievement>> = HashMap::new();
+        if state.config.achievements.is_empty() {
+            return achievements;
+        }
+
+        let Some(active_player) = current_storyteller(state) else {
+            return achievements;
+        };
+        let Some(active_card) = state.round_state.player_to_current_card.get(active_player) else {
+            return achievements;
+        };
+        let mut votes_for_card: HashMap<&String, usize> = HashMap::new();
+        for card in state.round_state.player_to_vote.values() {
+            *votes_for_card.entry(card).or_insert(0) += 1;
+        }
+        let voters = state.round_state.player_to_vote.len();
+
+        let mut award = |player: &String, achievement| {
+            if state.config.achievements.contains_key(&achievement) {
+                achievements
+                    .entry(player.clone())
+                    .or_default()
+                    .push(achievement);
+            }
+        };
+
+        // every vote except the player's own went to their card
+        for (player, card) in state.round_state.player_to_current_card.iter() {
+            if player != active_player
+                && voters > 1
+                && votes_for_card.get(card).copied().unwrap_or(0) == voters - 1
+            {
+                award(player, Achievement::FooledEveryone);
+            }
+        }
+
+        // only one player found the storyteller's card
+        let correct: Vec<&String> = state
+            .round_state
+            .player_to_vote
+            .iter()
+            .filter(|(_, card)| *card == active_card)
+            .map(|(player, _)| player)
+            .collect();
+        if voters > 1 && correct.len() == 1 {
+            award(correct[0], Achievement::LoneCorrectGuesser);
+        }
+
+        achievements
+    }
+
+    fn compute_results(&self, state: &RwLockWriteGuard<RoomState>) -> HashMap<String, u16> {
+        let mut point_change: HashMap<String, u16> = HashMap::new();
+        // nobody scores in the practice round
+        if is_warmup(state) {
+            return point_change;
+        }
+
+        // init_results makes sure there is one
+        let Some(active_player) = current_storyteller(state).map(str::to_string) else {
+            return point_change;
+        };
+        let Some(active_card) = state
+            .round_state
+            .player_to_current_card
+            .get(&active_player)
+            .cloned()
+        else {
+            return point_change;
+        };
+
+        let mut votes_for_card: HashMap<String, u16> = HashMap::new();
+
+        for (_, card) in state.round_state.player_to_vote.iter() {
+            *votes_for_card.entry(card.to_string()).or_insert(0) += 1;
+        }
+
+        let scoring = &state.config.scoring;
+        let votes_for_active_card = *votes_for_card.get(&active_card).unwrap_or(&0);
+        // a freed seat can leave the order while its player is still sitting out
+        let voters = state
+            .player_order
+            .len()
+            .saturating_sub(1 + state.round_state.sitting_out.len());
+        if votes_for_active_card == 0 {
+            // nobody voted for active card
+            for (player, _) in state.round_state.player_to_vote.iter() {
+                point_change.insert(player.to_string(), scoring.all_or_none_points);
+            }
+
+            for (player, card) in state.round_state.player_to_current_card.iter() {
+                if player != &active_player {
+                    *point_change.entry(player.to_string()).or_insert(0) +=
+                        votes_for_card.get(card).unwrap_or(&0) * scoring.bonus_per_vote;
+                }
+            }
+
+            point_change.insert(active_player.clone(), 0);
+        } else if votes_for_active_card as usize >= voters {
+            // everyone voted for active card
+            for (player, _) in state.round_state.player_to_vote.iter() {
+                point_change.insert(player.to_string(), scoring.all_or_none_points);
+            }
+            point_change.insert(active_player.clone(), 0);
+        } else {
+            // someone voted for the active card
+            for (player, card) in state.round_state.player_to_vote.iter() {
+                if card == &active_card {
+                    point_change.insert(player.to_string(), scoring.guesser_points);
+                } else {
+                    point_change.insert(player.to_string(), 0);
+                }
+            }
+
+            for (player, card) in state.round_state.player_to_current_card.iter() {
+                if player != &active_player {
+                    *point_change.entry(player.to_string()).or_insert(0) +=
+                        votes_for_card.get(card).unwrap_or(&0) * scoring.bonus_per_vote;
+                }
+            }
+
+            point_change.insert(active_player.clone(), scoring.storyteller_points);
+        }
+
+        if state.config.speed_bonus {
+            // earliest correct vote wins, ties go to the first name alphabetically
+            let fastest = state
+                .round_state
+                .player_to_vote
+                .iter()
+                .filter(|(_, card)| **card == active_card)
+                .filter_map(|(player, _)| {
+                    state
+                        .round_state
+                        .vote_times
+                        .get(player)
+                        .map(|t| (*t, player))
+                })
+                .min();
+            if let Some((_, player)) = fastest {
+                *point_change.entry(player.clone()).or_insert(0) += 1;
+            }
+        }
+
+        for (player, achievements) in self.compute_achievements(state) {
+            for achievement in achievements {
+                *point_change.entry(player.clone()).or_insert(0) += state
+                    .config
+                    .achievements
+                    .get(&achievement)
+                    .copied()
+                    .unwrap_or(0);
+            }
+        }
+
+        // last place is decided on the scores before this round
+        if state.config.comeback_bonus {
+            let lowest = state.players.values().map(|info| info.points).min();
+            let highest = state.players.values().map(|info| info.points).max();
+            if lowest != highest {
+                for (player, info) in state.players.iter() {
+                    if Some(info.points) == lowest {
+                        *point_change.entry(player.clone()).or_insert(0) += COMEBACK_BONUS;
+                    }
+                }
+            }
+        }
+
+        let multiplier = round_multiplier(state);
+        for points in point_change.values_mut() {
+            *points = points.saturating_mul(multiplier);
+            if let Some(cap) = state.config.round_point_cap {
+                *points = (*points).min(cap);
+            }
+        }
+
+        point_change
+    }
+
+    // false if the player never got into the room
+    pub async fn on_connection(
+        &self,
+        socket: &mut WebSocket,
+        name: &str,
+        capabilities: Vec<Capability>,
+        token: Option<String>,
+        password: Option<String>,
+        encoding: Encoding,
+    ) -> bool {
+        // public funciton
+        let connection = match self
+            .attempt_join(socket, name, capabilities, token, password)
+            .await
+        {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Error in attempt_join: {:?}", e);
+                return false;
+            }
+        };
+
+        // the player is in the room now, so every exit from here on,
+        // including failing to send the initial state, runs the cleanup below
+        let mut name = connection.name.clone();
+        let res = self
+            .run_ws_loop(socket, &mut name, connection, encoding)
+            .await;
+        let name = &name;
+        info!("Player {} has left", name);
+
+        self.last_access.store(get_time_s(), Ordering::Relaxed);
+        let mut state = self.state.write().await;
+
+        if matches!(state.stage, RoomStage::Joining) {
+            state.players.remove(name);
+            state.teams.remove(name);
+            state.session_tokens.remove(name);
+
+            if state.host.as_deref() == Some(name) {
+                state.host = pick_host(&state);
+            }
+        } else {
+            if let Some(player) = state.players.get_mut(name) {
+                player.connected = false;
+            }
+
+            if let Err(e) = self.check_player_count(&mut state) {
+                warn!("Error checking player count: {:?}", e);
+            }
+
+            if let Err(e) = self.skip_voting_if_stuck(&mut state) {
+                warn!("Error skipping voting: {:?}", e);
+            }
+
+            // they may have been the last one everyone was waiting on, and
+            // either way there is one fewer to wait for
+            if everyone_acted(&state) {
+                if let Err(e) = self.end_stage(&mut state) {
+                    warn!("Error ending stage: {:?}", e);
+                }
+            } else if matches!(state.stage, RoomStage::PlayersChoose | RoomStage::Voting) {
+                if let Err(e) = self.broadcast_progress(&state) {
+                    warn!("Error sending progress: {:?}", e);
+                }
+            }
+
+            // a kicked player's seat is already gone
+            if state.players.contains_key(name) {
+                self.start_seat_timer(&mut state, name);
+                self.start_storyteller_grace(&state);
+            }
+
+            // nobody is playing, so nothing runs out until somebody comes back
+            if !state.players.values().any(|p| p.connected && !p.bot) {
+                info!("Room {} abandoned mid-game, pausing", state.room_id);
+                self.abandoned_at_s.store(get_time_s(), Ordering::Relaxed);
+            }
+        }
+
+        state.player_to_socket.remove(name);
+        state.capabilities.remove(name);
+
+        if let Err(e) = res {
+            warn!("Error in run_ws_loop: {:?}", e);
+        }
+
+        if let Err(e) = self.broadcast_state(&state) {
+            warn!("Error sending broadcast: {}", e);
+        }
+        let _ = self.broadcast_msg(ServerMsg::PlayerLeft {
+            name: name.clone(),
+            still_in_game: state.players.contains_key(name),
+        });
+        self.send_deck_check(&state).await;
+        true
+    }
+
+    // only fails before the player is added to the room
+    async fn attempt_join(
+        &self,
+        socket: &mut WebSocket,
+        name: &str,
+        capabilities: Vec<Capability>,
+        token: Option<String>,
+        password: Option<String>,
+    ) -> Result<Connection> {
+        // the write lock is held from this check until the player's socket is
+        // registered below, so two joins with the same name can't both get in
+        let mut state = self.lock_for_join(socket).await?;
+
+        // a known token wins over the requested name; an unknown one is ignored
+        let seat = token.and_then(|token| {
+            state
+                .session_tokens
+                .iter()
+                .find(|(_, t)| **t == token)
+                .map(|(seat, _)| seat.clone())
+        });
+        if let Some(seat) = &seat {
+            if state.players.get(seat).is_some_and(|p| p.connected) {
+                socket
+                    .send(
+                        ServerMsg::error(
+                            ErrorCode::SeatConnected,
+                            "That seat is already connected",
+                        )
+                        .into(),
+                    )
+                    .await?;
+                return Err(anyhow!("Session token for {} already connected", seat));
+            }
+        }
+
+        let reclaimed = seat.is_some();
+        let assigned_name = if seat.is_some() {
+            seat
+        } else if name.is_empty() {
+            if !state.config.anonymous_names {
+                socket
+                    .send(ServerMsg::error(ErrorCode::EmptyName, "Name cannot be empty").into())
+                    .await?;
+                return Err(anyhow!("Name cannot be empty"));
+            }
+            Some(crate::name::random_name(|n| {
+                state.players.contains_key(n) || state.spectators.contains_key(n)
+            }))
+        } else {
+            None
+        };
+        let name = assigned_name.as_deref().unwrap_or(name);
+
+        // a session token already proves the player got in once
+        if !reclaimed && !password_matches(&state, password.as_deref()) {
+            socket
+                .send(ServerMsg::error(ErrorCode::WrongPassword, "Wrong password").into())
+                .await?;
+            return Err(anyhow!("Wrong password from {}", name));
+        }
+
+        if state.kicked.contains(name) {
+            socket
+                .send(ServerMsg::error(ErrorCode::Kicked, "You were removed from this room").into())
+                .await?;
+            return Err(anyhow!("{} was kicked", name));
+        }
+
+        info!("Handling join for {}", name);
+
+        let auto_ready =
+            matches!(state.stage, RoomStage::Results) && state.config.auto_ready_on_reconnect;
+        let mut auto_readied = false;
+
+        if state.spectators.contains_key(name) {
+            socket
+                .send(ServerMsg::error(ErrorCode::NameTaken, "Name already taken").into())
+                .await?;
+            return Err(anyhow!("Name already taken"));
+        }
+
+        if let Some(player) = state.players.get_mut(name) {
+            // player already exists in the game
+            // and not in joining anymore
+            // if in joining then player.active will be true
+
+            if !player.connected {
+                player.connected = true;
+                // a ready player's flag survives the disconnect; this also
+                // readies players who come back while results are up
+                if auto_ready {
+                    player.ready = true;
+                    auto_readied = true;
+                }
+                state.disconnected_at.remove(name);
+            } else {
+                socket
+                    .send(ServerMsg::error(ErrorCode::NameTaken, "Name already taken").into())
+                    .await?;
+                return Err(anyhow!("Name already taken"));
+            }
+        } else if matches!(state.stage, RoomStage::Joining) {
+            // still in joining and not yet joined
+            if state.players.len() < state.config.max_players() {
+                let color = free_color(&state).to_string();
+                state.players.insert(
+                    name.to_string(),
+                    PlayerInfo {
+                        connected: true,
+                        points: 0,
+                        ready: false,
+                        bot: false,
+                        color,
+                    },
+                );
+                state
+                    .session_tokens
+                    .insert(name.to_string(), uuid::Uuid::new_v4().to_string());
+            } else {
+                socket
+                    .send(
+                        ServerMsg::error(
+                            ErrorCode::RoomFull,
+                            "Too many players! You can still join as a spectator",
+                        )
+                        .into(),
+                    )
+                    .await?;
+                return Err(anyhow!("Too many players!"));
+            }
+        } else {
+            socket
+                .send(
+                    ServerMsg::error(
+                        ErrorCode::GameStarted,
+                        "Game has already started. You can still join as a spectator",
+                    )
+                    .into(),
+                )
+                .await?;
+            return Err(anyhow!("Game has already started"));
+        }
+
+        if matches!(state.stage, RoomStage::Joining) && state.host.is_none() {
+            state.host = Some(name.to_string());
+        }
+
+        // the joining player gets the state directly below, so only everyone
+        // else waits on the batch
+        let sent = if matches!(state.stage, RoomStage::Joining) {
+            let window = state
+                .config
+                .coalesce_state_ms
+                .max(state.config.join_coalesce_ms);
+            self.broadcast_state_within(&state, window)
+        } else {
+            self.broadcast_state(&state)
+        };
+        if let Err(e) = sent {
+            // will not receive this one yet
+            warn!("Error sending broadcast: {}", e);
+        }
+        // also before subscribing, so the player isn't told they joined; the
+        // player is already in, so this mustn't turn the join into a failure
+        let _ = self.broadcast_msg(ServerMsg::PlayerJoined {
+            name: name.to_string(),
+        });
+        self.send_deck_check(&state).await;
+
+        let resumed = self.check_player_count(&mut state).unwrap_or_else(|e| {
+            warn!("Error checking player count: {:?}", e);
+            false
+        });
+
+        // before registering, so the new round reaches this player only through
+        // the initial messages below
+        if auto_readied || resumed {
+            if let Err(e) = self.advance_if_ready(&mut state).await {
+                warn!("Error advancing after reconnect: {:?}", e);
+            }
+        }
+        // a storyteller left waiting while nobody else was connected can be skipped now
+        self.start_storyteller_grace(&state);
+
+        if self.abandoned_at_s.swap(0, Ordering::Relaxed) != 0 {
+            info!("Room {} is back with {}", state.room_id, name);
+            self.restart_stage_clock(&mut state);
+        }
+
+        // register and subscribe under the same lock the initial state is read
+        // with, so nothing between the two is missed
+        let (tx, rx) = mpsc::channel(PLAYER_CHANNEL_CAPACITY);
+        state.player_to_socket.insert(name.to_string(), tx);
+        state.capabilities.insert(name.to_string(), capabilities);
+        let broadcast_updates = self.broadcaster().subscribe();
+
+        let mut initial_msgs = Vec::new();
+        if let Some(name) = &assigned_name {
+            initial_msgs.push(ServerMsg::AssignedName { name: name.clone() });
+        }
+        if let Some(token) = state.session_tokens.get(name) {
+            initial_msgs.push(ServerMsg::SessionToken {
+                token: token.clone(),
+            });
+        }
+        initial_msgs.push(self.room_state(&state));
+        if let Ok(msg) = self.get_msg(Some(name), &state) {
+            initial_msgs.push(msg);
+        }
+        if matches!(state.stage, RoomStage::Joining) && state.host.as_deref() == Some(name) {
+            initial_msgs.push(self.deck_check(&state));
+        }
+
+        Ok(Connection {
+            name: name.to_string(),
+            rx,
+            broadcast_updates,
+            initial_msgs,
+            spectator_delay: None,
+        })
+    }
+
+    pub async fn on_spectator(
+        &self,
+        socket: &mut WebSocket,
+        name: &str,
+        capabilities: Vec<Capability>,
+        director: bool,
+        password: Option<String>,
+        encoding: Encoding,
+    ) {
+        let connection = match self
+            .attempt_spectate(socket, name, capabilities, director, password)
+            .await
+        {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Error in attempt_spectate: {:?}", e);
+                return;
+            }
+        };
+
+        // spectators can't rename, so this stays the name they joined with
+        let res = self
+            .run_ws_loop(socket, &mut name.to_string(), connection, encoding)
+            .await;
+        info!("Spectator {} has left", name);
+
+        self.last_access.store(get_time_s(), Ordering::Relaxed);
+        let mut state = self.state.write().await;
+        state.spectators.remove(name);
+        state.directors.remove(name);
+        state.capabilities.remove(name);
+
+        if let Err(e) = res {
+            warn!("Error in run_ws_loop: {:?}", e);
+        }
+    }
+
+    async fn attempt_spectate(
+        &self,
+        socket: &mut WebSocket,
+        name: &str,
+        capabilities: Vec<Capability>,
+        director: bool,
+        password: Option<String>,
+    ) -> Result<Connection> {
+        if name.is_empty() {
+            socket
+                .send(ServerMsg::error(ErrorCode::EmptyName, "Name cannot be empty").into())
+                .await?;
+            return Err(anyhow!("Name cannot be empty"));
+        }
+
+        let mut state = self.lock_for_join(socket).await?;
+
+        // the director token is enough on its own
+        if !director && !password_matches(&state, password.as_deref()) {
+            socket
+                .send(ServerMsg::error(ErrorCode::WrongPassword, "Wrong password").into())
+                .await?;
+            return Err(anyhow!("Wrong password from spectator {}", name));
+        }
+
+        if state.kicked.contains(name) {
+            socket
+                .send(ServerMsg::error(ErrorCode::Kicked, "You were removed from this room").into())
+                .await?;
+            return Err(anyhow!("{} was kicked", name));
+        }
+
+        if state.players.contains_key(name) || state.spectators.contains_key(name) {
+            socket
+                .send(ServerMsg::error(ErrorCode::NameTaken, "Name already taken").into())
+                .await?;
+            return Err(anyhow!("Name already taken"));
+        }
+
+        let max_spectators = state
+            .config
+            .max_spectators
+            .unwrap_or(DEFAULT_MAX_SPECTATORS);
+        if state.spectators.len() >= max_spectators {
+            socket
+                .send(
+                    ServerMsg::error(
+                        ErrorCode::SpectatorsFull,
+                        "This room has too many spectators",
+                    )
+                    .into(),
+                )
+                .await?;
+            return Err(anyhow!("Too many spectators"));
+        }
+
+        let (tx, rx) = mpsc::channel(PLAYER_CHANNEL_CAPACITY);
+        state.spectators.insert(name.to_string(), tx);
+        state.capabilities.insert(name.to_string(), capabilities);
+        if director {
+            info!("Director {} joined room {}", name, state.room_id);
+            state.directors.insert(name.to_string());
+        }
+        let broadcast_updates = self.broadcaster().subscribe();
+
+        // no hand to send, but the center cards and results are public
+        let mut initial_msgs = vec![self.room_state(&state)];
+        if let Ok(msg) = self.get_msg(None, &state) {
+            initial_msgs.push(msg);
+        }
+
+        Ok(Connection {
+            name: name.to_string(),
+            rx,
+            broadcast_updates,
+            initial_msgs,
+            spectator_delay: state
+                .config
+                .spectator_delay_s
+                .map(std::time::Duration::from_secs),
+        })
+    }
+
+    // a room stuck in a transition turns joiners away instead of leaving them hanging
+    async fn lock_for_join(
+        &self,
+        socket: &mut WebSocket,
+    ) -> Result<RwLockWriteGuard<'_, RoomState>> {
+        if let Ok(state) = tokio::time::timeout(JOIN_LOCK_TIMEOUT, self.state.write()).await {
+            return Ok(state);
+        }
+
+        info!(
+            "Room lock not acquired within {:?}, turning a join away",
+            JOIN_LOCK_TIMEOUT
+        );
+        socket
+            .send(ServerMsg::error(ErrorCode::RoomBusy, "The room is busy, try again").into())
+            .await?;
+        socket.send(WsMessage::Close(None)).await?;
+        Err(anyhow!("Room busy"))
+    }
+
+    async fn send_frame(
+        &self,
+        socket: &mut WebSocket,
+        msg: ServerMsg,
+        encoding: Encoding,
+    ) -> Result<()> {
+        let msg = encoding.frame(msg);
+        self.frames.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.frames
+            .bytes_out
+            .fetch_add(frame_len(&msg), Ordering::Relaxed);
+        socket.send(msg).await?;
+        Ok(())
+    }
+
+    // the room state and hand are what a client needs to render anything, so a
+    // hiccup on them is retried; a dead socket still fails every attempt quickly
+    async fn send_initial_frame(
+        &self,
+        socket: &mut WebSocket,
+        msg: ServerMsg,
+        encoding: Encoding,
+    ) -> Result<()> {
+        let mut backoff = INITIAL_SEND_BACKOFF;
+        for attempt in 1..INITIAL_SEND_ATTEMPTS {
+            match self.send_frame(socket, msg.clone(), encoding).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!("Initial send attempt {} failed: {}", attempt, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+        self.send_frame(socket, msg, encoding).await
+    }
+
+    // what a client needs to catch back up to the room
+    async fn resync_msgs(&self, name: &str) -> Vec<ServerMsg> {
+        let state = self.state.read().await;
+        let mut msgs = vec![self.room_state(&state)];
+        let name = state.players.contains_key(name).then_some(name);
+        if let Ok(msg) = self.get_msg(name, &state) {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    async fn run_ws_loop(
+        &self,
+        socket: &mut WebSocket,
+        name: &mut String,
+        connection: Connection,
+        encoding: Encoding,
+    ) -> Result<()> {
+        let Connection {
+            name: _,
+            mut rx,
+            mut broadcast_updates,
+            initial_msgs,
+            spectator_delay,
+        } = connection;
+
+        // held back from a delayed spectator, oldest first
+        let mut delayed: VecDeque<(tokio::time::Instant, ServerMsg)> = VecDeque::new();
+
+        for msg in initial_msgs {
+            match spectator_delay {
+                Some(delay) if reveals_clue(&msg) => {
+                    delayed.push_back((tokio::time::Instant::now() + delay, msg));
+                }
+                _ => self.send_initial_frame(socket, msg, encoding).await?,
+            }
+        }
+
+        // any frame from the client counts, including the pongs browsers send
+        // on their own in answer to our pings
+        let mut last_seen = std::time::Instant::now();
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        let mut limiter = MessageLimiter::default();
+
+        loop {
+            tokio::select! {
+                msg = broadcast_updates.recv() => {
+                    match msg {
+                        Ok(msg) => match spectator_delay {
+                            Some(delay) if reveals_clue(&msg) => {
+                                delayed.push_back((tokio::time::Instant::now() + delay, msg));
+                            }
+                            _ => {
+                                // the next stage never overtakes what is still held back
+                                if ends_stage(&msg) {
+                                    for (_, held) in delayed.drain(..) {
+                                        self.send_frame(socket, held, encoding).await?;
+                                    }
+                                }
+                                self.send_frame(socket, msg, encoding).await?;
+                            }
+                        },
+                        // the missed messages are gone, but the current state
+                        // covers everything they could have changed
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            self.broadcast_lagged.fetch_add(skipped, Ordering::Relaxed);
+                            warn!("{} missed {} messages, resyncing", name, skipped);
+                            for msg in self.resync_msgs(name).await {
+                                self.send_frame(socket, msg, encoding).await?;
+                            }
+                        }
+                        // the room was resized and its old channel closed; anything
+                        // sent on the new one before subscribing is in the resync
+                        Err(broadcast::error::RecvError::Closed) => {
+                            broadcast_updates = self.broadcaster().subscribe();
+                            for msg in self.resync_msgs(name).await {
+                                self.send_frame(socket, msg, encoding).await?;
+                            }
+                        }
+                    }
+                }
+                msg = socket.recv() => {
+                    match msg {
+                        Some(Ok(msg)) => {
+                            self.frames.frames_in.fetch_add(1, Ordering::Relaxed);
+                            self.frames.bytes_in.fetch_add(frame_len(&msg), Ordering::Relaxed);
+                            last_seen = std::time::Instant::now();
+                            if matches!(msg, WsMessage::Ping(_) | WsMessage::Pong(_)) {
+                                continue;
+                            }
+                            match limiter.check() {
+                                MsgVerdict::Allow => self.handle_client_msg(name, msg).await?,
+                                MsgVerdict::Drop { first: true } => {
+                                    let msg = ServerMsg::error(ErrorCode::RateLimited, "Rate limited");
+                                    self.send_frame(socket, msg, encoding).await?;
+                                }
+                                MsgVerdict::Drop { first: false } => {}
+                                MsgVerdict::Close => {
+                                    warn!("{} kept flooding, dropping", name);
+                                    break;
+                                }
+                            }
+                        }
+                        _ => break
+                    }
+                },
+                _ = tokio::time::sleep_until(
+                    delayed.front().map_or_else(tokio::time::Instant::now, |(at, _)| *at)
+                ), if !delayed.is_empty() => {
+                    let now = tokio::time::Instant::now();
+                    while delayed.front().is_some_and(|(at, _)| *at <= now) {
+                        let (_, msg) = delayed.pop_front().unwrap();
+                        self.send_frame(socket, msg, encoding).await?;
+                    }
+                }
+                _ = heartbeat.tick() => {
+                    let idle = last_seen.elapsed();
+                    if idle >= IDLE_CONNECTION_TIMEOUT {
+                        info!("{} has been silent for {:?}, dropping", name, idle);
+                        break;
+                    }
+                    if idle >= HEARTBEAT_INTERVAL {
+                        socket.send(WsMessage::Ping(Vec::new())).await?;
+                    }
+                }
+                msg = rx.recv() => {
+                    match msg {
+                        Some(msg) => {
+                            self.send_frame(socket, msg, encoding).await?;
+                        }
+                        _ => break,
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // room state is a full snapshot, so with coalescing on only the latest one
+    // in each window is sent; other messages are never delayed
+    fn broadcast_state(&self, state: &RwLockWriteGuard<RoomState>) -> Result<()> {
+        self.broadcast_state_within(state, state.config.coalesce_state_ms)
+    }
+
+    fn broadcast_state_within(
+        &self,
+        state: &RwLockWriteGuard<RoomState>,
+        window: u64,
+    ) -> Result<()> {
+        // anything longer than a second would make the lobby feel broken
+        let window = window.min(1000);
+        if window == 0 {
+            // a batched snapshot still waiting to go out is older than this one
+            self.pending_state.lock().unwrap().take();
+            return self.broadcast_msg(self.room_state(state));
+        }
+
+        let mut pending = self.pending_state.lock().unwrap();
+        let flush_scheduled = pending.is_some();
+        *pending = Some(self.room_state(state));
+
+        if !flush_scheduled {
+            let pending_state = self.pending_state.clone();
+            let broadcast = self.broadcaster();
+            tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(window)).await;
+                let msg = pending_state.lock().unwrap().take();
+                if let Some(msg) = msg {
+                    if broadcast.receiver_count() != 0 {
+                        let _ = broadcast.send(msg);
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    // only counts, so nobody learns who is still thinking
+    // sent whatever the room's settings, so a waiting screen never has to work
+    // out from the ready flags who the stage is still waiting on
+    fn broadcast_progress(&self, state: &RwLockWriteGuard<RoomState>) -> Result<()> {
+        let waiting_on = waiting_on(state);
+        self.broadcast_msg(ServerMsg::Progress {
+            ready_count: waiting_on.iter().filter(|info| info.ready).count(),
+            needed: waiting_on.len(),
+        })
+    }
+
+    fn broadcast_stage_progress(&self, state: &RwLockWriteGuard<RoomState>) -> Result<()> {
+        if !state.config.stage_progress {
+            return Ok(());
+        }
+
+        let others = state.players.len().saturating_sub(1);
+        let (submitted, total_expected) = match state.stage {
+            // the clue and the stage change arrive together
+            RoomStage::ActiveChooses => (0, 1),
+            // the storyteller's card is already in the center
+            RoomStage::PlayersChoose => (
+                state
+                    .round_state
+                    .player_to_current_card
+                    .len()
+                    .saturating_sub(1),
+                others,
+            ),
+            RoomStage::Voting => (
+                state.round_state.player_to_vote.len(),
+                others.saturating_sub(state.round_state.sitting_out.len()),
+            ),
+            _ => return Ok(()),
+        };
+
+        self.broadcast_msg(ServerMsg::StageProgress {
+            submitted,
+            total_expected,
+        })
+    }
+
+    fn broadcast_msg(&self, msg: ServerMsg) -> Result<()> {
+        // send only fails when nobody is listening, which can happen even after
+        // checking receiver_count; that's harmless, so never fail a transition over it
+        let _ = self.broadcaster().send(msg);
+        Ok(())
+    }
+
+    async fn send_msg(
+        &self,
+        state: &RwLockWriteGuard<'_, RoomState>,
+        name: &str,
+        msg: ServerMsg,
+    ) -> Result<()> {
+        // bots have no socket and nothing to read
+        if state.players.get(name).is_some_and(|p| p.bot) {
+            return Ok(());
+        }
+
+        let socket = state
+            .player_to_socket
+            .get(name)
+            .or_else(|| state.spectators.get(name))
+            .ok_or_else(|| {
+                warn!("Cannot find socket for {}", name);
+                anyhow!("Cannot find socket for {}", name)
+            })?;
+
+        socket.send(msg).await?;
+        Ok(())
+    }
+
+    // anything over the limits is dropped without telling the sender
+    fn spectator_chat(&self, state: &mut RwLockWriteGuard<'_, RoomState>, from: &str, text: &str) {
+        let text = text.trim();
+        let max_len = state.config.chat_max_len.unwrap_or(MAX_CHAT_LEN);
+        if text.is_empty() || text.graphemes(true).count() > max_len {
+            return;
+        }
+
+        let now_s = get_time_s();
+        if state
+            .muted_until_s
+            .get(from)
+            .is_some_and(|until| now_s < *until)
+        {
+            return;
+        }
+
+        let now_ms = get_time_ms();
+        let min_interval = u128::from(
+            state
+                .config
+                .chat_min_interval_ms
+                .unwrap_or(CHAT_MIN_INTERVAL_MS),
+        );
+        if state
+            .last_chat_ms
+            .get(from)
+            .is_some_and(|last| now_ms - last < min_interval)
+        {
+            return;
+        }
+        state.last_chat_ms.insert(from.to_string(), now_ms);
+
+        let msg = ServerMsg::SpectatorChat {
+            from: from.to_string(),
+            text: text.to_string(),
+        };
+        // chat isn't worth waiting on a slow spectator for, so full queues miss it
+        for tx in state.spectators.values() {
+            let _ = tx.try_send(msg.clone());
+        }
+    }
+
+    fn broadcaster(&self) -> broadcast::Sender<ServerMsg> {
+        self.broadcast.read().unwrap().clone()
+    }
+
+    // a new channel sized for the room; the old one closes once its last
+    // sender is gone
+    fn resize_broadcast(&self, max_players: usize) {
+        let (tx, _) = broadcast::channel(broadcast_capacity(max_players));
+        *self.broadcast.write().unwrap() = tx;
+    }
+
+    fn clear_ready(&self, state: &mut RwLockWriteGuard<RoomState>) {
+        for (_, player) in state.players.iter_mut() {
+            player.ready = false;
+        }
+    }
+
+    pub fn num_active(&self) -> usize {
+        self.broadcaster().receiver_count()
+    }
+
+    pub fn frame_stats(&self) -> FrameStats {
+        FrameStats {
+            frames_in: self.frames.frames_in.load(Ordering::Relaxed),
+            frames_out: self.frames.frames_out.load(Ordering::Relaxed),
+            bytes_in: self.frames.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.frames.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn idle_timeout_s(&self) -> Option<u64> {
+        self.idle_timeout_s
+    }
+
+    // every card the room can deal, for clients to preload; says nothing about
+    // who holds what
+    pub fn card_pool(&self) -> Arc<Vec<String>> {
+        self.base_deck.clone()
+    }
+
+    pub fn director_token(&self) -> Option<&str> {
+        self.director_token.as_deref()
+    }
+
+    pub fn abandoned_at_s(&self) -> Option<u64> {
+        Some(self.abandoned_at_s.load(Ordering::Relaxed)).filter(|at| *at != 0)
+    }
+
+    pub fn last_access(&self) -> u64 {
+        self.last_access.load(Ordering::Relaxed)
+    }
+
+    pub async fn stats(&self) -> RoomStats {
+        // never wait on a room that is in the middle of a long transition
+        let state = tokio::time::timeout(STATS_LOCK_TIMEOUT, self.state.read())
+            .await
+            .ok();
+
+        let connections = state.as_ref().map(|s| {
+            s.player_to_socket
+                .iter()
+                .map(|(player, tx)| {
+                    let queue_depth = tx.max_capacity() - tx.capacity();
+                    (
+                        player.clone(),
+                        ConnectionStats {
+                            queue_depth,
+                            queue_capacity: tx.max_capacity(),
+                            lagging: queue_depth >= LAGGING_QUEUE_DEPTH,
+                        },
+                    )
+                })
+                .collect::<HashMap<_, _>>()
+        });
+
+        // remember the last count so busy rooms still report something
+        if let Some(connections) = &connections {
+            self.lagging_connections.store(
+                connections.values().filter(|c| c.lagging).count(),
+                Ordering::Relaxed,
+            );
+        }
+
+        RoomStats {
+            num_active: self.num_active(),
+            last_access: self.last_access(),
+            busy: state.is_none(),
+            stage: state.as_ref().map(|s| s.stage),
+            created_at_s: state.as_ref().map(|s| s.created_at_s),
+            rounds_played: state.as_ref().map(|s| s.rounds_played),
+            num_players: state.as_ref().map(|s| s.players.len()),
+            num_spectators: state.as_ref().map(|s| s.spectators.len()),
+            broadcast_queue: self.broadcaster().len(),
+            broadcast_lagged: self.broadcast_lagged.load(Ordering::Relaxed),
+            lagging_connections: self.lagging_connections.load(Ordering::Relaxed),
+            frames: self.frame_stats(),
+            connections,
+            approx_bytes: state.as_deref().map(approx_bytes),
+        }
+    }
+
+    // drops bookkeeping left behind by players who are no longer seated.
+    // anything about a seated player, connected or not, is kept, so a game in
+    // progress is unaffected. returns the room's approximate size afterwards
+    pub fn trim(&self) -> Option<usize> {
+        let mut state = self.state.try_write().ok()?;
+        let state = &mut *state;
+
+        let players = &state.players;
+        state.disconnected_at.retain(|p, _| players.contains_key(p));
+        state
+            .storyteller_timeouts
+            .retain(|p, _| players.contains_key(p));
+        state
+            .storyteller_skips
+            .retain(|p, _| players.contains_key(p));
+        state.arranged_hands.retain(|p| players.contains_key(p));
+        let spectators = &state.spectators;
+        state
+            .capabilities
+            .retain(|p, _| players.contains_key(p) || spectators.contains_key(p));
+        state.last_chat_ms.retain(|p, _| spectators.contains_key(p));
+        let now = get_time_s();
+        state.muted_until_s.retain(|_, until| now < *until);
+
+        Some(approx_bytes(state))
+    }
+
+    // stage and connected players for /metrics, or None if the room is busy
+    pub fn stage_and_connected(&self) -> Option<(RoomStage, usize)> {
+        let state = self.state.try_read().ok()?;
+        let connected = state.players.values().filter(|p| p.connected).count();
+        Some((state.stage, connected))
+    }
+
+    // only public rooms that can still be joined; busy rooms are skipped like in can_resume
+    pub fn listing(&self) -> Option<PublicRoom> {
+        let state = self.state.try_read().ok()?;
+        if !state.config.public || !matches!(state.stage, RoomStage::Joining) {
+            return None;
+        }
+
+        Some(PublicRoom {
+            room_id: state.room_id.clone(),
+            players: state.players.len(),
+            max_players: state.config.max_players(),
+            has_password: state.password_hash.is_some(),
+        })
+    }
+
+    // the player has a seat here but no connection to it; a room that is busy
+    // right now is skipped rather than waited on
+    pub fn can_resume(&self, name: &str) -> bool {
+        self.state
+            .try_read()
+            .is_ok_and(|state| state.players.get(name).is_some_and(|p| !p.connected))
+    }
+
+    pub async fn reports(&self) -> Vec<Report> {
+        self.state.read().await.reports.clone()
+    }
+
+    pub async fn card_stats(&self) -> HashMap<String, CardStats> {
+        self.state.read().await.card_stats.clone()
+    }
+
+    // a game has been dealt and hasn't finished yet
+    pub async fn in_progress(&self) -> bool {
+        let state = self.state.read().await;
+        !matches!(
+            state.stage,
+            RoomStage::Joining | RoomStage::End | RoomStage::GameOver
+        )
+    }
+
+    // sent to everyone still connected as the server exits
+    pub fn notify_shutdown(&self) {
+        let _ = self.broadcast_msg(ServerMsg::ServerShutdown {});
+    }
+
+    // at any stage; everyone stays connected and is told the id to come back with
+    pub async fn migrate(&self, new_room_id: &str) {
+        let mut state = self.state.write().await;
+        state.room_id = new_room_id.to_string();
+        let _ = self.broadcast_msg(ServerMsg::RoomMigrated {
+            new_room_id: new_room_id.to_string(),
+        });
+        let _ = self.broadcast_state(&state);
+    }
+
+    pub async fn summary(&self) -> Option<GameSummary> {
+        let state = self.state.read().await;
+        if !matches!(state.stage, RoomStage::End | RoomStage::GameOver) {
+            return None;
+        }
+
+        Some(GameSummary {
+            room_id: state.room_id.clone(),
+            game_id: state.game_id.clone(),
+            standings: standings(&state),
+            rounds: state.round_history.clone(),
+            stats: state.player_stats.clone(),
+        })
+    }
+
+    pub async fn get_room_state(&self) -> ServerMsg {
+        let state = self.state.read().await;
+        self.room_state(&state)
+    }
+
+    fn deck_check(&self, state: &RwLockWriteGuard<RoomState>) -> ServerMsg {
+        // estimate for the players so far, but never fewer than a game needs
+        let players = state.players.len().max(MIN_PLAYERS);
+        let cards = self.base_deck.len();
+
+        // the deck is reshuffled from the discards once it runs low, so a full
+        // game only needs every hand dealt plus one more card per player
+        let hand_size = state.config.hand_size();
+        let needed_for_full_game = players * (hand_size + 1);
+        let estimated_rounds = match cards.checked_sub(players * hand_size) {
+            Some(left) => 1 + left / players,
+            None => 0,
+        };
+
+        ServerMsg::DeckCheck {
+            sufficient: cards >= needed_for_full_game,
+            cards,
+            needed_for_full_game,
+            estimated_rounds,
+        }
+    }
+
+    // only the host is told, and only while the game can still be set up
+    async fn send_deck_check(&self, state: &RwLockWriteGuard<'_, RoomState>) {
+        if !matches!(state.stage, RoomStage::Joining) {
+            return;
+        }
+        if let Some(host) = &state.host {
+            let _ = self.send_msg(state, host, self.deck_check(state)).await;
+        }
+    }
+
+    // takes either guard, so read-only callers don't need the write lock
+    fn room_state(&self, state: &RoomState) -> ServerMsg {
+        ServerMsg::RoomState {
+            room_id: state.room_id.clone(),
+            players: state.players.clone(),
+            stage: state.stage,
+            active_player: state.player_order.get(state.active_player).cloned(),
+            player_order: state.player_order.clone(),
+            round: state.round,
+            win_streaks: state.win_streaks.clone(),
+            games_played: state.games_played,
+            shuffle_commitment: shuffle_commitment(state),
+            teams: state.teams.clone(),
+            team_scores: team_scores(state),
+            host: state.host.clone(),
+            theme: state.config.theme.clone(),
+            multiplier: round_multiplier(state),
+            warmup: is_warmup(state),
+            paused: state.paused_for_players,
+            pool_size: self.base_deck.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ScoringRules, DEFAULT_HAND_SIZE, DEFAULT_MAX_PLAYERS};
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    const DECK_SIZE: usize = 100;
+
+    // seeded, so every test deals and orders players the same way each run;
+    // seed 1 unless the config picks another
+    fn test_room(config: RoomConfig) -> Arc<Room> {
+        room_with_deck(config, DECK_SIZE)
+    }
+
+    fn room_with_deck(config: RoomConfig, deck_size: usize) -> Arc<Room> {
+        let deck = Arc::new((0..deck_size).map(|i| format!("{}.png", i)).collect());
+        let config = RoomConfig {
+            seed: config.seed.or(Some(1)),
+            ..config
+        };
+        Room::new(
+            "test",
+            deck,
+            config,
+            events::channel(),
+            crate::name::DEFAULT_MAX_NAME_LEN,
+        )
+    }
+
+    // seats players in the lobby as if they had joined over a socket; what
+    // the room sends each of them lands in their inbox
+    async fn seat(room: &Room, names: &[&str]) -> HashMap<String, mpsc::Receiver<ServerMsg>> {
+        let mut state = room.state.write().await;
+        let mut inboxes = HashMap::new();
+        for name in names {
+            let color = free_color(&state).to_string();
+            state.players.insert(
+                name.to_string(),
+                PlayerInfo {
+                    connected: true,
+                    points: 0,
+                    ready: false,
+                    bot: false,
+                    color,
+                },
+            );
+            if state.host.is_none() {
+                state.host = Some(name.to_string());
+            }
+            // roomy enough that no test has to read its inbox to keep going
+            let (tx, rx) = mpsc::channel(1000);
+            state.player_to_socket.insert(name.to_string(), tx);
+            inboxes.insert(name.to_string(), rx);
+        }
+        inboxes
+    }
+
+    async fn send(room: &Room, name: &str, msg: serde_json::Value) {
+        room.handle_client_msg(&mut name.to_string(), WsMessage::Text(msg.to_string()))
+            .await
+            .unwrap();
+    }
+
+    // everyone readies up in the lobby, which deals the first round
+    async fn start(room: &Room) {
+        let names: Vec<String> = room.state.read().await.players.keys().cloned().collect();
+        for name in names {
+            send(room, &name, json!({ "Ready": {} })).await;
+        }
+    }
+
+    async fn storyteller(room: &Room) -> String {
+        let state = room.state.read().await;
+        current_storyteller(&state).unwrap().to_string()
+    }
+
+    async fn hand(room: &Room, name: &str) -> Vec<String> {
+        room.state.read().await.player_hand[name].clone()
+    }
+
+    // everyone but the storyteller, in turn order
+    fn guessers_of(state: &RoomState) -> Vec<String> {
+        let teller = current_storyteller(state);
+        state
+            .player_order
+            .iter()
+            .filter(|p| Some(p.as_str()) != teller)
+            .cloned()
+            .collect()
+    }
+
+    async fn guessers(room: &Room) -> Vec<String> {
+        guessers_of(&*room.state.read().await)
+    }
+
+    async fn give_clue(room: &Room) {
+        let teller = storyteller(room).await;
+        let card = hand(room, &teller).await[0].clone();
+        send(
+            room,
+            &teller,
+            json!({ "ActivePlayerChooseCard": { "card": card, "description": "a clue" } }),
+        )
+        .await;
+    }
+
+    async fn pick_first_card(room: &Room, name: &str) {
+        let card = hand(room, name).await[0].clone();
+        send(room, name, json!({ "PlayerChooseCard": { "card": card } })).await;
+    }
+
+    // error codes in a player's inbox so far
+    fn errors(inbox: &mut mpsc::Receiver<ServerMsg>) -> Vec<ErrorCode> {
+        std::iter::from_fn(|| inbox.try_recv().ok())
+            .filter_map(|msg| match msg {
+                ServerMsg::Error { code, .. } => Some(code),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // broadcasts sent so far that the test subscribed for
+    fn received(rx: &mut broadcast::Receiver<ServerMsg>) -> Vec<ServerMsg> {
+        std::iter::from_fn(|| rx.try_recv().ok()).collect()
+    }
+
+    // deals the first round of this many games in a row, each from the full
+    // deck, and counts the distinct cards anyone was dealt
+    async fn cards_seen(config: RoomConfig, games: usize) -> usize {
+        let room = test_room(config);
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        let mut state = room.state.write().await;
+        for _ in 0..games {
+            state.player_hand.clear();
+            state.deck = room.base_deck.to_vec();
+            state.round = 0;
+            room.init_round(&mut state).await.unwrap();
+        }
+        state.card_deal_count.len()
+    }
+
+    #[tokio::test]
+    async fn weighted_dealing_surfaces_unseen_cards() {
+        // eighteen cards a game, so five games can deal ninety different ones
+        let weighted = cards_seen(
+            RoomConfig {
+                weighted_dealing: true,
+                ..Default::default()
+            },
+            5,
+        )
+        .await;
+        let random = cards_seen(RoomConfig::default(), 5).await;
+        assert_eq!(weighted, 90);
+        assert!(random < weighted);
+    }
+
+    #[tokio::test]
+    async fn random_storytellers_each_go_once_a_cycle() {
+        let room = test_room(RoomConfig {
+            storyteller_selection: StorytellerSelection::Random,
+            ..Default::default()
+        });
+        let _inboxes = seat(&room, &["alice", "bob", "carol", "dave"]).await;
+        start(&room).await;
+
+        let mut tellers = vec![storyteller(&room).await];
+        for _ in 1..8 {
+            {
+                let mut state = room.state.write().await;
+                state.stage = RoomStage::Results;
+                room.init_round(&mut state).await.unwrap();
+            }
+            tellers.push(storyteller(&room).await);
+        }
+
+        for cycle in tellers.chunks(4) {
+            let distinct: HashSet<&String> = cycle.iter().collect();
+            assert_eq!(distinct.len(), 4, "{:?}", tellers);
+        }
+        // nobody goes twice in a row across the start of a new cycle
+        assert!(tellers.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    // the current round up to the vote, with everyone's first card in the center
+    async fn to_voting(room: &Room) {
+        give_clue(room).await;
+        for guesser in guessers(room).await {
+            pick_first_card(room, &guesser).await;
+        }
+        assert!(matches!(room.state.read().await.stage, RoomStage::Voting));
+    }
+
+    #[tokio::test]
+    async fn voting_with_nobody_left_to_vote_moves_on() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+        to_voting(&room).await;
+
+        let mut rx = room.broadcaster().subscribe();
+        let mut state = room.state.write().await;
+        for guesser in guessers_of(&state) {
+            state.players.get_mut(&guesser).unwrap().connected = false;
+        }
+        room.skip_voting_if_stuck(&mut state).unwrap();
+
+        assert!(matches!(state.stage, RoomStage::Results));
+        // the missing votes were filled in and scored
+        assert_eq!(state.round_state.player_to_vote.len(), 2);
+        assert!(received(&mut rx)
+            .iter()
+            .any(|msg| matches!(msg, ServerMsg::VotingSkipped { .. })));
+    }
+
+    #[tokio::test]
+    async fn results_go_ahead_with_nobody_listening() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+        to_voting(&room).await;
+
+        // the last listener goes away just before the results are sent
+        let rx = room.broadcaster().subscribe();
+        drop(rx);
+        assert_eq!(room.num_active(), 0);
+
+        let mut state = room.state.write().await;
+        room.init_results(&mut state).unwrap();
+        assert!(matches!(state.stage, RoomStage::Results));
+        assert!(room
+            .broadcast_msg(ServerMsg::VotingSkipped {
+                reason: "nobody".to_string()
+            })
+            .is_ok());
+    }
+
+    // a round at the vote with the storyteller first in turn order; everyone
+    // plays the card named after them, and votes go from voter to card owner
+    async fn score(config: RoomConfig, votes: &[(&str, &str)]) -> HashMap<String, u16> {
+        let room = test_room(config);
+        let names = ["teller", "a", "b", "c"];
+        let _inboxes = seat(&room, &names).await;
+        let mut state = room.state.write().await;
+        state.stage = RoomStage::Voting;
+        state.round = 1;
+        state.player_order = names.map(String::from).to_vec();
+        state.active_player = 0;
+        for name in names {
+            state
+                .round_state
+                .player_to_current_card
+                .insert(name.to_string(), format!("{}.png", name));
+        }
+        for (voter, owner) in votes {
+            state
+                .round_state
+                .player_to_vote
+                .insert(voter.to_string(), format!("{}.png", owner));
+        }
+        room.compute_results(&state)
+    }
+
+    fn points(expected: &[(&str, u16)]) -> HashMap<String, u16> {
+        expected
+            .iter()
+            .map(|(name, points)| (name.to_string(), *points))
+            .collect()
+    }
+
+    fn with_achievements() -> RoomConfig {
+        RoomConfig {
+            achievements: HashMap::from([
+                (Achievement::FooledEveryone, 2),
+                (Achievement::LoneCorrectGuesser, 3),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn a_decoy_with_every_other_vote_fooled_everyone() {
+        let votes = &[("a", "b"), ("b", "c"), ("c", "b")];
+        assert_eq!(
+            score(with_achievements(), votes).await,
+            points(&[("teller", 0), ("a", 2), ("b", 6), ("c", 3)])
+        );
+    }
+
+    #[tokio::test]
+    async fn the_only_one_to_find_the_card_is_the_lone_guesser() {
+        let votes = &[("a", "teller"), ("b", "c"), ("c", "b")];
+        assert_eq!(
+            score(with_achievements(), votes).await,
+            points(&[("teller", 3), ("a", 6), ("b", 1), ("c", 1)])
+        );
+        // and nothing extra when the room has no achievements
+        assert_eq!(
+            score(RoomConfig::default(), votes).await,
+            points(&[("teller", 3), ("a", 3), ("b", 1), ("c", 1)])
+        );
+    }
+
+    // from the vote to the next round: everyone finds the storyteller's card
+    // and readies up
+    async fn finish_round(room: &Room) {
+        let (teller_card, guessers) = {
+            let state = room.state.read().await;
+            let teller = current_storyteller(&state).unwrap();
+            (
+                state.round_state.player_to_current_card[teller].clone(),
+                guessers_of(&state),
+            )
+        };
+        for guesser in &guessers {
+            send(room, guesser, json!({ "Vote": { "card": teller_card } })).await;
+        }
+        assert!(matches!(room.state.read().await.stage, RoomStage::Results));
+        start(room).await;
+    }
+
+    #[tokio::test]
+    async fn nothing_from_a_round_carries_into_the_next() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+        to_voting(&room).await;
+        finish_round(&room).await;
+
+        {
+            let state = room.state.read().await;
+            assert_eq!(state.round, 2);
+            assert!(matches!(state.stage, RoomStage::ActiveChooses));
+            assert!(state.round_state.current_description.is_empty());
+            assert!(state.round_state.player_to_current_card.is_empty());
+            assert!(state.round_state.player_to_vote.is_empty());
+            assert!(state.round_state.storyteller_vote.is_none());
+            assert!(state.round_state.vote_times.is_empty());
+            assert!(state.round_state.results.is_none());
+        }
+
+        // the new storyteller picks from the hand they have now
+        to_voting(&room).await;
+        let state = room.state.read().await;
+        assert_eq!(state.round_state.player_to_current_card.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn resetting_the_round_clears_every_field() {
+        let room = test_room(RoomConfig::default());
+        let mut state = room.state.write().await;
+        let card = || "1.png".to_string();
+        let player = || "alice".to_string();
+        state.round_state = RoundState {
+            current_description: "a clue".to_string(),
+            player_to_current_card: HashMap::from([(player(), card())]),
+            player_to_vote: HashMap::from([(player(), card())]),
+            storyteller_vote: Some(card()),
+            vote_times: HashMap::from([(player(), 1)]),
+            results: Some(ServerMsg::VotingSkipped { reason: card() }),
+            round_acks: HashSet::from([player()]),
+            clue_timer_started: true,
+            audience_votes: HashMap::from([(player(), card())]),
+            sitting_out: HashSet::from([player()]),
+            center_cards: vec![card()],
+        };
+
+        room.reset_round_state(&mut state);
+        assert_eq!(
+            format!("{:?}", state.round_state),
+            format!("{:?}", RoundState::default())
+        );
+    }
+
+    #[test]
+    fn awkward_text_still_frames_in_both_encodings() {
+        // nothing a player can type makes serialization fail, which is why a
+        // failure only falls back to an error frame rather than being reported
+        let text = "\"}{\u{0}\n\u{202e}🃏 name\\";
+        let msg = || ServerMsg::SpectatorChat {
+            from: text.to_string(),
+            text: text.to_string(),
+        };
+
+        let WsMessage::Text(json) = Encoding::Json.frame(msg()) else {
+            panic!("json should be a text frame");
+        };
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["SpectatorChat"]["text"], text);
+
+        let WsMessage::Binary(bytes) = Encoding::Msgpack.frame(msg()) else {
+            panic!("msgpack should be a binary frame");
+        };
+        let value: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(value["SpectatorChat"]["from"], text);
+    }
+
+    #[tokio::test]
+    async fn a_held_card_in_the_deck_is_passed_over() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+
+        let mut state = room.state.write().await;
+        let mut deck: Vec<String> = (0..3).map(|i| format!("extra-{}.png", i)).collect();
+        for hand in state.player_hand.values_mut() {
+            deck.push(hand[0].clone());
+            hand.pop();
         }
+        state.deck = deck;
+        state.stage = RoomStage::Results;
+        room.init_round(&mut state).await.unwrap();
 
-        if let Err(e) = self.broadcast_msg(self.room_state(&state)) {
-            println!("Error sending broadcast: {}", e);
+        assert!(matches!(state.stage, RoomStage::ActiveChooses));
+        for hand in state.player_hand.values() {
+            assert_eq!(hand.iter().collect::<HashSet<_>>().len(), DEFAULT_HAND_SIZE);
         }
     }
 
-    async fn attempt_join(&self, socket: &mut WebSocket, name: &str) -> Result<()> {
-        if name.is_empty() {
-            socket
-                .send(ServerMsg::ErrorMsg("Name cannot be empty".to_string()).into())
-                .await?;
-            return Err(anyhow!("Name cannot be empty"));
+    #[tokio::test]
+    async fn a_deck_too_short_to_deal_ends_the_game_where_it_was() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+
+        let mut rx = room.broadcaster().subscribe();
+        let mut state = room.state.write().await;
+        for hand in state.player_hand.values_mut() {
+            hand.pop();
         }
+        // enough cards to skip the reload, but alice already holds all of them
+        let held = state.player_hand["alice"][0].clone();
+        state.deck = vec![held; 3];
+        state.stage = RoomStage::Results;
+        let storyteller = current_storyteller(&state).unwrap().to_string();
 
-        println!("Handling join for {}", name);
+        room.init_round(&mut state).await.unwrap();
+        assert!(matches!(state.stage, RoomStage::GameOver));
+        assert_eq!(state.round, 1);
+        assert_eq!(current_storyteller(&state), Some(storyteller.as_str()));
+        assert_eq!(state.storytellers_this_cycle, vec![storyteller]);
+        assert!(state
+            .player_hand
+            .values()
+            .all(|hand| hand.len() == DEFAULT_HAND_SIZE - 1));
+        drop(state);
 
-        let mut state = self.state.write().await;
+        assert!(received(&mut rx)
+            .iter()
+            .any(|msg| matches!(msg, ServerMsg::GameOver { .. })));
+        assert!(!room.in_progress().await);
+        assert!(room.summary().await.is_some());
+    }
 
-        if let Some(player) = state.players.get_mut(name) {
-            // player already exists in the game
-            // and not in joining anymore
-            // if in joining then player.active will be true
+    #[tokio::test]
+    async fn the_warmup_round_scores_nothing() {
+        let room = test_room(RoomConfig {
+            warmup_round: true,
+            ..Default::default()
+        });
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+        to_voting(&room).await;
 
-            if !player.connected {
-                player.connected = true;
-            } else {
-                socket
-                    .send(ServerMsg::ErrorMsg("Name already taken".to_string()).into())
-                    .await?;
-                return Err(anyhow!("Name already taken"));
-            }
-        } else if matches!(state.stage, RoomStage::Joining) {
-            // still in joining and not yet joined
-            if state.players.len() < 8 {
-                state.players.insert(
-                    name.to_string(),
-                    PlayerInfo {
-                        connected: true,
-                        points: 0,
-                        ready: false,
-                    },
+        let mut rx = room.broadcaster().subscribe();
+        finish_round(&room).await;
+        let warmup = received(&mut rx).into_iter().find_map(|msg| match msg {
+            ServerMsg::Results { warmup, .. } => Some(warmup),
+            _ => None,
+        });
+        assert_eq!(warmup, Some(true));
+        assert!(room
+            .state
+            .read()
+            .await
+            .players
+            .values()
+            .all(|p| p.points == 0));
+
+        // everyone finds the card again, and this time it counts
+        to_voting(&room).await;
+        finish_round(&room).await;
+        let state = room.state.read().await;
+        assert_eq!(state.players.values().map(|p| p.points).sum::<u16>(), 4);
+    }
+
+    // adds spectators the way a socket watching the room would
+    async fn watch(room: &Room, names: &[&str]) -> HashMap<String, mpsc::Receiver<ServerMsg>> {
+        let mut state = room.state.write().await;
+        let mut inboxes = HashMap::new();
+        for name in names {
+            let (tx, rx) = mpsc::channel(1000);
+            state.spectators.insert(name.to_string(), tx);
+            inboxes.insert(name.to_string(), rx);
+        }
+        inboxes
+    }
+
+    fn chat(inbox: &mut mpsc::Receiver<ServerMsg>) -> Vec<(String, String)> {
+        std::iter::from_fn(|| inbox.try_recv().ok())
+            .filter_map(|msg| match msg {
+                ServerMsg::SpectatorChat { from, text } => Some((from, text)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn spectator_chat_never_reaches_players() {
+        let room = test_room(RoomConfig::default());
+        let mut players = seat(&room, &["a", "b", "c"]).await;
+        let mut spectators = watch(&room, &["watcher", "lurker"]).await;
+        let mut rx = room.broadcaster().subscribe();
+        start(&room).await;
+        received(&mut rx);
+        for inbox in players.values_mut() {
+            while inbox.try_recv().is_ok() {}
+        }
+
+        send(
+            &room,
+            "watcher",
+            json!({ "Chat": { "text": "it's the owl" } }),
+        )
+        .await;
+
+        let said = vec![("watcher".to_string(), "it's the owl".to_string())];
+        for inbox in spectators.values_mut() {
+            assert_eq!(chat(inbox), said);
+        }
+        for inbox in players.values_mut() {
+            assert!(inbox.try_recv().is_err());
+        }
+        assert!(received(&mut rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn filled_in_votes_never_pick_the_voters_own_card() {
+        for seed in 0..50 {
+            let room = test_room(RoomConfig {
+                seed: Some(seed),
+                ..RoomConfig::default()
+            });
+            let _inboxes = seat(&room, &["a", "b", "c", "d"]).await;
+            start(&room).await;
+            to_voting(&room).await;
+
+            let mut state = room.state.write().await;
+            room.init_results(&mut state).unwrap();
+
+            let round = &state.round_state;
+            assert_eq!(round.player_to_vote.len(), 3, "seed {}", seed);
+            for (voter, card) in &round.player_to_vote {
+                assert_ne!(
+                    Some(card),
+                    round.player_to_current_card.get(voter),
+                    "seed {}",
+                    seed
                 );
-            } else {
-                socket
-                    .send(ServerMsg::ErrorMsg("Too many players!".to_string()).into())
-                    .await?;
-                return Err(anyhow!("Too many players!"));
             }
-        } else {
-            socket
-                .send(ServerMsg::ErrorMsg("Game has already started".to_string()).into())
-                .await?;
-            return Err(anyhow!("Game has already started"));
         }
+    }
 
-        self.broadcast_msg(self.room_state(&state).into())?; // will not receive this one yet
-        socket.send(self.room_state(&state).into()).await?;
-        if let Ok(msg) = self.get_msg(Some(name), &state) {
-            socket.send(msg.into()).await?;
+    #[tokio::test]
+    async fn a_voter_whose_only_option_is_their_own_card_sits_the_vote_out() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["a", "b", "c"]).await;
+        start(&room).await;
+        to_voting(&room).await;
+
+        // a duplicated card leaves one guesser looking at nothing but their own
+        let mut state = room.state.write().await;
+        let teller = current_storyteller(&state).unwrap().to_string();
+        let stuck = guessers_of(&state)[0].clone();
+        let card = state.round_state.player_to_current_card[&teller].clone();
+        state.round_state.center_cards = vec![card.clone()];
+        state
+            .round_state
+            .player_to_current_card
+            .insert(stuck.clone(), card.clone());
+
+        room.init_results(&mut state).unwrap();
+
+        assert!(matches!(state.stage, RoomStage::Results));
+        assert!(!state.round_state.player_to_vote.contains_key(&stuck));
+        for guesser in guessers_of(&state).iter().filter(|g| **g != stuck) {
+            assert_eq!(state.round_state.player_to_vote[guesser], card);
         }
+    }
 
-        Ok(())
+    // a four player game where two drop mid-round, leaving one short of the minimum
+    async fn drop_below_minimum(on_too_few_players: TooFewPlayers) -> (Arc<Room>, Vec<ServerMsg>) {
+        let room = test_room(RoomConfig {
+            on_too_few_players,
+            ..RoomConfig::default()
+        });
+        let _inboxes = seat(&room, &["a", "b", "c", "d"]).await;
+        start(&room).await;
+        give_clue(&room).await;
+
+        let mut rx = room.broadcaster().subscribe();
+        {
+            let mut state = room.state.write().await;
+            for guesser in guessers_of(&state).iter().take(2) {
+                state.players.get_mut(guesser).unwrap().connected = false;
+                room.check_player_count(&mut state).unwrap();
+            }
+        }
+        (room, received(&mut rx))
     }
 
-    async fn run_ws_loop(&self, socket: &mut WebSocket, name: &str) -> Result<()> {
-        let (tx, mut rx) = mpsc::channel(10);
-        self.state
+    fn too_few(msgs: &[ServerMsg]) -> Vec<bool> {
+        msgs.iter()
+            .filter_map(|msg| match msg {
+                ServerMsg::TooFewPlayers { connected, paused } => {
+                    assert_eq!(*connected, 2);
+                    Some(*paused)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn too_few_players_can_end_the_game() {
+        let (room, msgs) = drop_below_minimum(TooFewPlayers::End).await;
+
+        assert_eq!(too_few(&msgs), vec![false]);
+        assert!(msgs
+            .iter()
+            .any(|msg| matches!(msg, ServerMsg::EndGame { .. })));
+        let state = room.state.read().await;
+        assert!(matches!(state.stage, RoomStage::End));
+        assert!(!state.paused_for_players);
+    }
+
+    #[tokio::test]
+    async fn too_few_players_can_pause_the_game() {
+        let (room, msgs) = drop_below_minimum(TooFewPlayers::Pause).await;
+
+        // told once, however many drop
+        assert_eq!(too_few(&msgs), vec![true]);
+        let mut state = room.state.write().await;
+        assert!(matches!(state.stage, RoomStage::PlayersChoose));
+        assert!(state.paused_for_players);
+
+        // one coming back is enough to carry on
+        let back = guessers_of(&state)[0].clone();
+        state.players.get_mut(&back).unwrap().connected = true;
+        assert!(room.check_player_count(&mut state).unwrap());
+        assert!(!state.paused_for_players);
+    }
+
+    #[tokio::test]
+    async fn the_clue_timer_waits_for_every_ack() {
+        let room = test_room(RoomConfig {
+            clue_timeout_s: Some(60),
+            round_start_ack: true,
+            ..RoomConfig::default()
+        });
+        let _inboxes = seat(&room, &["a", "b", "c"]).await;
+        let mut rx = room.broadcaster().subscribe();
+        start(&room).await;
+
+        let timer_started = |msgs: Vec<ServerMsg>| {
+            msgs.iter()
+                .any(|msg| matches!(msg, ServerMsg::ClueTimerStarted { .. }))
+        };
+        assert!(!timer_started(received(&mut rx)));
+
+        // "c" is slow to render the new hand
+        send(&room, "a", json!({ "RoundReady": {} })).await;
+        send(&room, "b", json!({ "RoundReady": {} })).await;
+        assert!(!room.state.read().await.round_state.clue_timer_started);
+        assert!(!timer_started(received(&mut rx)));
+
+        send(&room, "c", json!({ "RoundReady": {} })).await;
+        assert!(room.state.read().await.round_state.clue_timer_started);
+        assert!(timer_started(received(&mut rx)));
+    }
+
+    #[tokio::test]
+    async fn a_player_who_sits_out_can_lose_their_seat() {
+        let room = test_room(RoomConfig {
+            on_no_submission: NoSubmission::Skip,
+            stage_progress: true,
+            ..Default::default()
+        });
+        let _inboxes = seat(&room, &["alice", "bob", "carol", "dave"]).await;
+        start(&room).await;
+        give_clue(&room).await;
+
+        let guessers = guessers(&room).await;
+        pick_first_card(&room, &guessers[0]).await;
+        pick_first_card(&room, &guessers[1]).await;
+        let sitter = &guessers[2];
+
+        let mut progress = room.broadcaster().subscribe();
+        {
+            let mut state = room.state.write().await;
+            room.init_voting(&mut state).unwrap();
+            assert!(state.round_state.sitting_out.contains(sitter));
+
+            room.free_seat(&mut state, sitter).await.unwrap();
+            assert!(state.round_state.sitting_out.is_empty());
+            assert!(matches!(state.stage, RoomStage::Voting));
+        }
+        let expected: Vec<usize> = received(&mut progress)
+            .into_iter()
+            .filter_map(|msg| match msg {
+                ServerMsg::StageProgress { total_expected, .. } => Some(total_expected),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(expected, [2]);
+
+        for (voter, other) in [(&guessers[0], &guessers[1]), (&guessers[1], &guessers[0])] {
+            let card = room.state.read().await.round_state.player_to_current_card[other].clone();
+            send(&room, voter, json!({ "Vote": { "card": card } })).await;
+        }
+        assert!(matches!(room.state.read().await.stage, RoomStage::Results));
+    }
+
+    #[tokio::test]
+    async fn lobby_updates_in_one_window_go_out_once() {
+        let room = test_room(RoomConfig::default());
+        let mut rx = room.broadcaster().subscribe();
+
+        for names in [&["a"][..], &["b"], &["c"]] {
+            let _inboxes = seat(&room, names).await;
+            let state = room.state.write().await;
+            room.broadcast_state_within(&state, 50).unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let snapshots: Vec<_> = received(&mut rx)
+            .into_iter()
+            .filter_map(|msg| match msg {
+                ServerMsg::RoomState { players, .. } => Some(players.len()),
+                _ => None,
+            })
+            .collect();
+        // only the latest snapshot is sent
+        assert_eq!(snapshots, vec![3]);
+    }
+
+    // frees the seat `offset` places from the storyteller's, mid-round, and
+    // returns who told the story before and who tells it after
+    async fn free_seat_near_storyteller(offset: isize) -> (String, String) {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["a", "b", "c", "d", "e"]).await;
+        start(&room).await;
+        room.state.write().await.active_player = 2;
+        give_clue(&room).await;
+
+        let mut state = room.state.write().await;
+        let before = current_storyteller(&state).unwrap().to_string();
+        let leaver = state.player_order[(2 + offset) as usize].clone();
+        room.free_seat(&mut state, &leaver).await.unwrap();
+
+        assert!(!state.player_order.contains(&leaver));
+        assert!(state.active_player < state.player_order.len());
+        let after = current_storyteller(&state)
+            .expect("the round still has a storyteller")
+            .to_string();
+        (before, after)
+    }
+
+    #[tokio::test]
+    async fn freeing_a_seat_keeps_the_storyteller() {
+        for offset in [-2, -1, 1, 2] {
+            let (before, after) = free_seat_near_storyteller(offset).await;
+            assert_eq!(before, after, "seat {} from the storyteller", offset);
+        }
+    }
+
+    #[tokio::test]
+    async fn freeing_the_storytellers_seat_passes_the_turn() {
+        let (before, after) = free_seat_near_storyteller(0).await;
+        assert_ne!(before, after);
+    }
+
+    #[tokio::test]
+    async fn spectator_chat_is_rate_limited_and_muted() {
+        let room = test_room(RoomConfig {
+            chat_min_interval_ms: Some(60_000),
+            ..RoomConfig::default()
+        });
+        let _players = seat(&room, &["host"]).await;
+        let mut spectators = watch(&room, &["watcher", "lurker"]).await;
+
+        send(&room, "watcher", json!({ "Chat": { "text": "first" } })).await;
+        send(&room, "watcher", json!({ "Chat": { "text": "too soon" } })).await;
+        let said = vec![("watcher".to_string(), "first".to_string())];
+        assert_eq!(chat(spectators.get_mut("lurker").unwrap()), said);
+
+        send(
+            &room,
+            "host",
+            json!({ "Mute": { "name": "lurker", "duration_s": 60 } }),
+        )
+        .await;
+        send(&room, "lurker", json!({ "Chat": { "text": "hello?" } })).await;
+        // the watcher only ever saw their own first message
+        assert_eq!(chat(spectators.get_mut("watcher").unwrap()), said);
+        assert!(chat(spectators.get_mut("lurker").unwrap()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn without_auto_fill_nothing_is_played_or_voted_for_anyone() {
+        let room = test_room(RoomConfig {
+            disable_auto_fill: true,
+            ..RoomConfig::default()
+        });
+        let _inboxes = seat(&room, &["a", "b", "c", "d"]).await;
+        start(&room).await;
+        give_clue(&room).await;
+        let guessers = guessers(&room).await;
+        for guesser in &guessers[1..] {
+            pick_first_card(&room, guesser).await;
+        }
+
+        // the deadline passes with one guesser still choosing
+        let mut state = room.state.write().await;
+        room.init_voting(&mut state).unwrap();
+        let idle = &guessers[0];
+        assert!(state.round_state.sitting_out.contains(idle));
+        assert!(!state.round_state.player_to_current_card.contains_key(idle));
+        assert_eq!(state.round_state.player_to_current_card.len(), 3);
+
+        // and then nobody votes
+        room.init_results(&mut state).unwrap();
+        assert!(state.round_state.player_to_vote.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_storyteller_vote_is_refused_without_dropping_them() {
+        let room = test_room(RoomConfig::default());
+        let mut inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+        to_voting(&room).await;
+
+        let teller = storyteller(&room).await;
+        let card = room.state.read().await.round_state.player_to_current_card
+            [&guessers(&room).await[0]]
+            .clone();
+        let inbox = inboxes.get_mut(&teller).unwrap();
+        while inbox.try_recv().is_ok() {}
+
+        send(&room, &teller, json!({ "Vote": { "card": card } })).await;
+        assert_eq!(errors(inbox), [ErrorCode::InvalidVote]);
+        assert!(room
+            .state
+            .read()
+            .await
+            .round_state
+            .player_to_vote
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_same_seed_deals_the_same_game() {
+        let mut games = vec![];
+        for _ in 0..2 {
+            let room = test_room(RoomConfig {
+                seed: Some(7),
+                ..RoomConfig::default()
+            });
+            let _inboxes = seat(&room, &["a", "b", "c", "d"]).await;
+            start(&room).await;
+            let hands = room.state.read().await.player_hand.clone();
+            to_voting(&room).await;
+            let state = room.state.read().await;
+            games.push((
+                state.player_order.clone(),
+                hands,
+                state.round_state.center_cards.clone(),
+            ));
+        }
+        assert_eq!(games[0], games[1]);
+    }
+
+    #[tokio::test]
+    async fn a_game_the_deck_ended_can_be_rematched() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+        {
+            let mut state = room.state.write().await;
+            room.deck_exhausted(&mut state).unwrap();
+            assert!(matches!(state.stage, RoomStage::GameOver));
+        }
+
+        let host = room.state.read().await.host.clone().unwrap();
+        send(&room, &host, json!({ "Rematch": {} })).await;
+        let state = room.state.read().await;
+        assert!(matches!(state.stage, RoomStage::Joining));
+        assert_eq!(state.round, 0);
+        assert!(state.player_hand.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_changed_vote_scores_as_the_last_one_cast() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["a", "b", "c", "d"]).await;
+        start(&room).await;
+        to_voting(&room).await;
+
+        let (teller, cards) = {
+            let state = room.state.read().await;
+            (
+                current_storyteller(&state).unwrap().to_string(),
+                state.round_state.player_to_current_card.clone(),
+            )
+        };
+        let guessers = guessers(&room).await;
+        let vote = |voter: &str, owner: &str| {
+            let card = cards[owner].clone();
+            let room = room.clone();
+            let voter = voter.to_string();
+            async move { send(&room, &voter, json!({ "Vote": { "card": card } })).await }
+        };
+
+        // the first guesser is fooled at first, then finds the card; the
+        // last guesser falls for the first guesser's decoy
+        vote(&guessers[0], &guessers[1]).await;
+        vote(&guessers[0], &teller).await;
+        vote(&guessers[1], &teller).await;
+        vote(&guessers[2], &guessers[0]).await;
+
+        let state = room.state.read().await;
+        assert!(matches!(state.stage, RoomStage::Results));
+        assert_eq!(
+            state.round_state.player_to_vote[&guessers[0]],
+            cards[&teller]
+        );
+        let expected: HashMap<String, u16> = [
+            (teller.clone(), 3),
+            (guessers[0].clone(), 4),
+            (guessers[1].clone(), 3),
+            (guessers[2].clone(), 0),
+        ]
+        .into();
+        assert_eq!(state.round_history.last().unwrap().point_change, expected);
+    }
+
+    fn custom_scoring() -> RoomConfig {
+        RoomConfig {
+            scoring: ScoringRules {
+                storyteller_points: 5,
+                guesser_points: 4,
+                all_or_none_points: 1,
+                bonus_per_vote: 2,
+            },
+            ..Default::default()
+        }
+    }
+
+    const ALL_FOUND: &[(&str, &str)] = &[("a", "teller"), ("b", "teller"), ("c", "teller")];
+    const NONE_FOUND: &[(&str, &str)] = &[("a", "b"), ("b", "c"), ("c", "b")];
+    const SOME_FOUND: &[(&str, &str)] = &[("a", "teller"), ("b", "teller"), ("c", "a")];
+
+    #[tokio::test]
+    async fn scores_with_the_standard_rules() {
+        let config = RoomConfig::default;
+        assert_eq!(
+            score(config(), ALL_FOUND).await,
+            points(&[("teller", 0), ("a", 2), ("b", 2), ("c", 2)])
+        );
+        assert_eq!(
+            score(config(), NONE_FOUND).await,
+            points(&[("teller", 0), ("a", 2), ("b", 4), ("c", 3)])
+        );
+        assert_eq!(
+            score(config(), SOME_FOUND).await,
+            points(&[("teller", 3), ("a", 4), ("b", 3), ("c", 0)])
+        );
+    }
+
+    #[tokio::test]
+    async fn scores_with_custom_rules() {
+        assert_eq!(
+            score(custom_scoring(), ALL_FOUND).await,
+            points(&[("teller", 0), ("a", 1), ("b", 1), ("c", 1)])
+        );
+        assert_eq!(
+            score(custom_scoring(), NONE_FOUND).await,
+            points(&[("teller", 0), ("a", 1), ("b", 5), ("c", 3)])
+        );
+        assert_eq!(
+            score(custom_scoring(), SOME_FOUND).await,
+            points(&[("teller", 5), ("a", 6), ("b", 4), ("c", 0)])
+        );
+    }
+
+    // every Progress broadcast so far, as (ready_count, needed)
+    fn progress(rx: &mut broadcast::Receiver<ServerMsg>) -> Vec<(usize, usize)> {
+        received(rx)
+            .into_iter()
+            .filter_map(|msg| match msg {
+                ServerMsg::Progress {
+                    ready_count,
+                    needed,
+                } => Some((ready_count, needed)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn picks_and_votes_report_progress_without_the_storyteller() {
+        // off by default, and not needed for Progress
+        let room = test_room(RoomConfig::default());
+        assert!(!room.state.read().await.config.stage_progress);
+        let _inboxes = seat(&room, &["a", "b", "c", "d"]).await;
+        start(&room).await;
+        give_clue(&room).await;
+
+        let mut rx = room.broadcaster().subscribe();
+        for guesser in guessers(&room).await {
+            pick_first_card(&room, &guesser).await;
+        }
+        assert_eq!(progress(&mut rx), [(1, 3), (2, 3), (3, 3)]);
+
+        let guessers = guessers(&room).await;
+        let teller = storyteller(&room).await;
+        let card = room.state.read().await.round_state.player_to_current_card[&teller].clone();
+        send(&room, &guessers[0], json!({ "Vote": { "card": card } })).await;
+        assert_eq!(progress(&mut rx), [(1, 3)]);
+    }
+
+    #[tokio::test]
+    async fn choosing_only_waits_on_connected_players() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["a", "b", "c", "d"]).await;
+        start(&room).await;
+        give_clue(&room).await;
+
+        let guessers = guessers(&room).await;
+        room.state
             .write()
             .await
-            .player_to_socket
-            .insert(name.to_string(), tx);
-        let mut broadcast_updates = self.broadcast.subscribe();
+            .players
+            .get_mut(&guessers[2])
+            .unwrap()
+            .connected = false;
+
+        let mut rx = room.broadcaster().subscribe();
+        pick_first_card(&room, &guessers[0]).await;
+        assert_eq!(progress(&mut rx), [(1, 2)]);
+        assert!(matches!(
+            room.state.read().await.stage,
+            RoomStage::PlayersChoose
+        ));
+
+        pick_first_card(&room, &guessers[1]).await;
+        let state = room.state.read().await;
+        assert!(matches!(state.stage, RoomStage::Voting));
+        // the one who dropped still has a card in the center
+        assert_eq!(state.round_state.player_to_current_card.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn a_full_room_playing_to_the_vote_doesnt_overflow_the_broadcast() {
+        let room = test_room(RoomConfig::default());
+        let names: Vec<String> = (0..DEFAULT_MAX_PLAYERS).map(|i| i.to_string()).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let _inboxes = seat(&room, &names).await;
+
+        // nobody reads until everyone has readied, been dealt and played a card,
+        // a state update each time
+        let mut rx = room.broadcaster().subscribe();
+        start(&room).await;
+        to_voting(&room).await;
 
         loop {
-            tokio::select! {
-                msg = broadcast_updates.recv() => {
-                    socket.send(msg?.into()).await?;
-                }
-                msg = socket.recv() => {
-                    match msg {
-                        Some(Ok(msg)) => {
-                            self.handle_client_msg(name, msg).await?;
-                        }
-                        _ => break
-                    }
-                },
-                msg = rx.recv() => {
-                    match msg {
-                        Some(msg) => {
-                            socket.send(msg.into()).await?;
-                        }
-                        _ => break,
-                    }
-                }
+            match rx.try_recv() {
+                Ok(_) => {}
+                Err(broadcast::error::TryRecvError::Empty) => break,
+                Err(e) => panic!("broadcast overflowed: {:?}", e),
             }
         }
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn played_cards_come_back_so_a_small_deck_lasts() {
+        // enough for the hands and a round or so on top, nowhere near the game
+        let deck_size = 4 * DEFAULT_HAND_SIZE + 6;
+        let room = room_with_deck(
+            RoomConfig {
+                target_score: Some(100),
+                ..RoomConfig::default()
+            },
+            deck_size,
+        );
+        let _inboxes = seat(&room, &["a", "b", "c", "d"]).await;
+        start(&room).await;
+
+        let rounds = 2 * deck_size / DEFAULT_HAND_SIZE;
+        for _ in 0..rounds {
+            to_voting(&room).await;
+            finish_round(&room).await;
+            let state = room.state.read().await;
+            assert!(matches!(state.stage, RoomStage::ActiveChooses));
+            for hand in state.player_hand.values() {
+                assert_eq!(hand.len(), DEFAULT_HAND_SIZE);
+            }
+        }
+        assert_eq!(room.state.read().await.round as usize, rounds + 1);
     }
 
-    fn broadcast_msg(&self, msg: ServerMsg) -> Result<()> {
-        if self.broadcast.receiver_count() != 0 {
-            self.broadcast.send(msg)?;
+    #[tokio::test]
+    async fn a_reshuffle_never_deals_a_card_twice() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+
+        let mut state = room.state.write().await;
+        // the deck is spent and the discard pile holds the played cards, along
+        // with copies of cards still in hands
+        let mut discard: Vec<String> = state.deck.drain(..).take(3).collect();
+        for hand in state.player_hand.values_mut() {
+            discard.push(hand.pop().unwrap());
+            discard.push(hand[0].clone());
         }
-        Ok(())
+        state.discard = discard;
+        state.stage = RoomStage::Results;
+        room.init_round(&mut state).await.unwrap();
+        assert!(matches!(state.stage, RoomStage::ActiveChooses));
+
+        let dealt: Vec<&String> = state.player_hand.values().flatten().collect();
+        assert_eq!(dealt.len(), 3 * DEFAULT_HAND_SIZE);
+        assert_eq!(dealt.iter().collect::<HashSet<_>>().len(), dealt.len());
     }
 
-    async fn send_msg(
-        &self,
-        state: &RwLockWriteGuard<'_, RoomState>,
-        name: &str,
-        msg: ServerMsg,
-    ) -> Result<()> {
-        let socket = state.player_to_socket.get(name).ok_or_else(|| {
-            println!("Cannot find socket for {}", name);
-            anyhow!("Cannot find socket for {}", name)
-        })?;
+    #[tokio::test]
+    async fn skips_the_round_when_the_storyteller_never_chose() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
 
-        socket.send(msg.into()).await?;
-        Ok(())
+        let teller = storyteller(&room).await;
+        let mut state = room.state.write().await;
+        state.players.get_mut(&teller).unwrap().connected = false;
+        room.init_results(&mut state).unwrap();
+
+        assert!(matches!(state.stage, RoomStage::Results));
+        assert!(state.round_history.is_empty());
+        assert!(state.players.values().all(|p| p.points == 0));
+
+        // a player coming back still has results to look at
+        let results = room.get_msg(Some(&teller), &state).unwrap();
+        assert!(matches!(
+            results,
+            ServerMsg::Results { active_card, .. } if active_card.is_empty()
+        ));
     }
 
-    fn clear_ready(&self, state: &mut RwLockWriteGuard<RoomState>) {
-        for (_, player) in state.players.iter_mut() {
-            player.ready = false;
+    #[tokio::test]
+    async fn a_pick_without_a_hand_is_an_error() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+
+        give_clue(&room).await;
+
+        let other = &guessers(&room).await[0];
+        let card = hand(&room, other).await[0].clone();
+        room.state.write().await.player_hand.remove(other);
+        let msg = json!({ "PlayerChooseCard": { "card": card } }).to_string();
+        assert!(room
+            .handle_client_msg(&mut other.to_string(), WsMessage::Text(msg))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn a_round_the_deck_cant_deal_isnt_counted() {
+        let room = test_room(RoomConfig::default());
+        let _inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        start(&room).await;
+
+        let mut state = room.state.write().await;
+        assert_eq!(state.rounds_played, 1);
+        // everyone played a card and there's nothing left to draw
+        for hand in state.player_hand.values_mut() {
+            hand.pop();
+        }
+        state.deck.clear();
+        state.discard.clear();
+        state.stage = RoomStage::Results;
+        room.init_round(&mut state).await.unwrap();
+
+        assert!(matches!(state.stage, RoomStage::GameOver));
+        assert_eq!(state.round, 1);
+        assert_eq!(state.rounds_played, 1);
+    }
+
+    // the GameOver sent when the deck runs out with these scores
+    async fn game_over(scores: &[(&str, u16)]) -> (String, Vec<Standing>) {
+        let room = test_room(RoomConfig::default());
+        let names: Vec<&str> = scores.iter().map(|(name, _)| *name).collect();
+        let _inboxes = seat(&room, &names).await;
+        let mut rx = room.broadcaster().subscribe();
+        {
+            let mut state = room.state.write().await;
+            for (name, points) in scores {
+                state.players.get_mut(*name).unwrap().points = *points;
+            }
+            room.deck_exhausted(&mut state).unwrap();
         }
+        received(&mut rx)
+            .into_iter()
+            .find_map(|msg| match msg {
+                ServerMsg::GameOver {
+                    winner, standings, ..
+                } => Some((winner, standings)),
+                _ => None,
+            })
+            .unwrap()
     }
 
-    pub fn num_active(&self) -> usize {
-        self.broadcast.receiver_count()
+    fn ranks(standings: &[Standing]) -> Vec<(&str, usize)> {
+        standings
+            .iter()
+            .map(|standing| (standing.name.as_str(), standing.rank))
+            .collect()
     }
 
-    pub fn last_access(&self) -> u64 {
-        self.last_access.load(Ordering::Relaxed)
+    #[tokio::test]
+    async fn the_top_score_wins() {
+        let (winner, standings) = game_over(&[("alice", 4), ("bob", 9), ("carol", 6)]).await;
+        assert_eq!(winner, "bob");
+        assert_eq!(ranks(&standings), [("bob", 1), ("carol", 2), ("alice", 3)]);
     }
 
-    pub async fn get_room_state(&self) -> ServerMsg {
-        let state = self.state.write().await;
-        self.room_state(&state)
+    #[tokio::test]
+    async fn a_tie_for_first_shares_the_rank() {
+        let (winner, standings) = game_over(&[("carol", 9), ("alice", 4), ("bob", 9)]).await;
+        assert_eq!(winner, "bob");
+        assert_eq!(ranks(&standings), [("bob", 1), ("carol", 1), ("alice", 3)]);
     }
 
-    fn room_state(&self, state: &RwLockWriteGuard<RoomState>) -> ServerMsg {
-        ServerMsg::RoomState {
-            room_id: state.room_id.clone(),
-            players: state.players.clone(),
-            stage: state.stage,
-            active_player: state.player_order.get(state.active_player).cloned(),
-            player_order: state.player_order.clone(),
-            round: state.round,
+    #[tokio::test]
+    async fn everyone_tied_is_everyone_first() {
+        let (winner, standings) = game_over(&[("carol", 5), ("alice", 5), ("bob", 5)]).await;
+        assert_eq!(winner, "alice");
+        assert_eq!(ranks(&standings), [("alice", 1), ("bob", 1), ("carol", 1)]);
+    }
+
+    #[tokio::test]
+    async fn vote_progress_counts_only_who_can_vote() {
+        let room = test_room(RoomConfig {
+            reveal_vote_progress: true,
+            on_no_submission: NoSubmission::Skip,
+            ..Default::default()
+        });
+        let _inboxes = seat(&room, &["alice", "bob", "carol", "dave", "erin"]).await;
+        start(&room).await;
+        give_clue(&room).await;
+
+        // one sits out and another leaves once voting starts
+        let guessers = guessers(&room).await;
+        for guesser in &guessers[..3] {
+            pick_first_card(&room, guesser).await;
+        }
+        {
+            let mut state = room.state.write().await;
+            room.init_voting(&mut state).unwrap();
+            state.players.get_mut(&guessers[2]).unwrap().connected = false;
+        }
+
+        let mut rx = room.broadcaster().subscribe();
+        let card = room.state.read().await.round_state.player_to_current_card[&guessers[1]].clone();
+        send(&room, &guessers[0], json!({ "Vote": { "card": card } })).await;
+        let progress: Vec<(usize, usize)> = received(&mut rx)
+            .into_iter()
+            .filter_map(|msg| match msg {
+                ServerMsg::VotingProgress { cast, total } => Some((cast, total)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(progress, [(1, 2)]);
+    }
+
+    #[tokio::test]
+    async fn settings_can_grow_the_room_while_the_deck_allows() {
+        let room = test_room(RoomConfig::default());
+        let mut inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        let host = room.state.read().await.host.clone().unwrap();
+        let mut before = room.broadcaster().subscribe();
+
+        let settings = json!({ "max_players": 12, "hand_size": 8 });
+        send(
+            &room,
+            &host,
+            json!({ "UpdateSettings": { "settings": settings } }),
+        )
+        .await;
+        assert!(errors(inboxes.get_mut(&host).unwrap()).is_empty());
+        assert_eq!(room.state.read().await.config.max_players(), 12);
+
+        // the old channel is closed for connections to move off, and the new
+        // one holds a burst for the bigger room
+        assert!(matches!(
+            before.try_recv(),
+            Err(broadcast::error::TryRecvError::Closed)
+        ));
+        let mut after = room.broadcaster().subscribe();
+        for _ in 0..broadcast_capacity(12) {
+            room.broadcast_msg(ServerMsg::Pong {}).unwrap();
         }
+        assert_eq!(received(&mut after).len(), broadcast_capacity(12));
+    }
+
+    #[tokio::test]
+    async fn settings_the_deck_cant_deal_are_turned_down() {
+        let room = test_room(RoomConfig::default());
+        let mut inboxes = seat(&room, &["alice", "bob", "carol"]).await;
+        let host = room.state.read().await.host.clone().unwrap();
+
+        // twelve hands of ten is more than the hundred cards there are
+        let settings = json!({ "max_players": 12, "hand_size": 10 });
+        send(
+            &room,
+            &host,
+            json!({ "UpdateSettings": { "settings": settings } }),
+        )
+        .await;
+        assert_eq!(
+            errors(inboxes.get_mut(&host).unwrap()),
+            [ErrorCode::DeckTooSmall]
+        );
+        let state = room.state.read().await;
+        assert_eq!(state.config.max_players(), DEFAULT_MAX_PLAYERS);
+        assert_eq!(state.config.hand_size(), DEFAULT_HAND_SIZE);
     }
 }
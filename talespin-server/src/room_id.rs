@@ -0,0 +1,67 @@
+use rand::seq::SliceRandom;
+use std::ops::RangeInclusive;
+
+pub const DEFAULT_LEN: usize = 4;
+pub const LEN_RANGE: RangeInclusive<usize> = 4..=8;
+// tries at an unused id before giving up and reporting the server full
+pub const MAX_ATTEMPTS: usize = 32;
+
+const LETTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+// 0 and 1 are left out since they read like o and l
+const LETTERS_AND_DIGITS: &[u8] = b"abcdefghijklmnopqrstuvwxyz23456789";
+
+// shape of newly generated room ids
+#[derive(Debug, Clone, Copy)]
+pub struct RoomIdFormat {
+    pub len: usize,
+    pub digits: bool,
+}
+
+impl Default for RoomIdFormat {
+    fn default() -> Self {
+        RoomIdFormat {
+            len: DEFAULT_LEN,
+            digits: false,
+        }
+    }
+}
+
+impl RoomIdFormat {
+    // optional, e.g. ROOM_ID_LEN=6 ROOM_ID_DIGITS=true
+    pub fn from_env() -> Self {
+        let len = std::env::var("ROOM_ID_LEN")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|len| LEN_RANGE.contains(len))
+            .unwrap_or(DEFAULT_LEN);
+        let digits = std::env::var("ROOM_ID_DIGITS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(false);
+        RoomIdFormat { len, digits }
+    }
+
+    pub fn generate(&self) -> String {
+        let alphabet = if self.digits {
+            LETTERS_AND_DIGITS
+        } else {
+            LETTERS
+        };
+        let mut rng = rand::thread_rng();
+        (0..self.len)
+            .map(|_| *alphabet.choose(&mut rng).unwrap() as char)
+            .collect()
+    }
+}
+
+// room codes are typed by hand, so case and stray spaces don't matter; None if
+// the id could never have been generated in any format, so rooms created before
+// the format changed stay reachable
+pub fn normalize(room_id: &str) -> Option<String> {
+    let room_id = room_id.trim().to_ascii_lowercase();
+    let valid = LEN_RANGE.contains(&room_id.len())
+        && room_id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+    valid.then_some(room_id)
+}
@@ -0,0 +1,124 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::Path, sync::Arc};
+use tracing::{info, warn};
+
+use crate::events::Event;
+use crate::room::{get_time_s, Room};
+
+pub const SNAPSHOT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+// every room, keyed by id; rooms are kept as json so one that no longer
+// loads doesn't take the rest down with it
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Snapshot {
+    at_s: u64,
+    rooms: HashMap<String, serde_json::Value>,
+}
+
+// written to a temporary file first, so a crash mid-write leaves the last snapshot whole
+pub async fn save(path: &Path, rooms: Vec<(String, Arc<Room>)>) -> Result<()> {
+    let mut snapshot = Snapshot {
+        at_s: get_time_s(),
+        rooms: HashMap::new(),
+    };
+    for (room_id, room) in rooms {
+        match room.snapshot().await {
+            Ok(value) => {
+                snapshot.rooms.insert(room_id, value);
+            }
+            Err(e) => warn!("Could not snapshot room {}: {:?}", room_id, e),
+        }
+    }
+
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(&snapshot)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Option<Snapshot>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+// rooms from the last snapshot, or none if there isn't one yet. a snapshot
+// that can't be read is moved aside to look at later, and the server starts
+// empty rather than not at all
+pub fn load(
+    path: &Path,
+    events: &tokio::sync::broadcast::Sender<Event>,
+    max_name_len: usize,
+) -> Vec<(String, Arc<Room>)> {
+    let snapshot = match read(path) {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return Vec::new(),
+        Err(e) => {
+            let mut bad = path.as_os_str().to_owned();
+            bad.push(".bad");
+            warn!(
+                "Could not read snapshot {}, moving it to {}: {:?}",
+                path.display(),
+                bad.to_string_lossy(),
+                e
+            );
+            if let Err(e) = std::fs::rename(path, &bad) {
+                warn!("Could not move snapshot aside: {:?}", e);
+            }
+            return Vec::new();
+        }
+    };
+
+    let mut rooms = Vec::new();
+    for (room_id, value) in snapshot.rooms {
+        match Room::restore(value, events.clone(), max_name_len) {
+            Ok(room) => rooms.push((room_id, room)),
+            Err(e) => warn!("Could not restore room {}: {:?}", room_id, e),
+        }
+    }
+    info!(
+        "Restored {} rooms from a snapshot {}s old",
+        rooms.len(),
+        get_time_s().saturating_sub(snapshot.at_s)
+    );
+    rooms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_corrupt_snapshot_is_moved_aside() {
+        let path = std::env::temp_dir().join(format!("talespin-{}-snapshot", std::process::id()));
+        std::fs::write(&path, b"{ not json").unwrap();
+
+        let rooms = load(
+            &path,
+            &crate::events::channel(),
+            crate::name::DEFAULT_MAX_NAME_LEN,
+        );
+        assert!(rooms.is_empty());
+        assert!(!path.exists());
+
+        let mut bad = path.into_os_string();
+        bad.push(".bad");
+        assert_eq!(std::fs::read(&bad).unwrap(), b"{ not json");
+        std::fs::remove_file(&bad).unwrap();
+    }
+
+    #[test]
+    fn no_snapshot_is_no_rooms() {
+        let path = std::env::temp_dir().join("talespin-no-such-snapshot");
+        assert!(load(
+            &path,
+            &crate::events::channel(),
+            crate::name::DEFAULT_MAX_NAME_LEN
+        )
+        .is_empty());
+    }
+}
@@ -0,0 +1,530 @@
+// drives the real server binary over websockets
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+const DECK_SIZE: usize = 60;
+const ADMIN_TOKEN: &str = "test-admin";
+
+// a server on a free port with a deck of its own, stopped when dropped
+struct Server {
+    child: Child,
+    port: u16,
+    cards_dir: PathBuf,
+}
+
+impl Server {
+    fn start(name: &str) -> Self {
+        // empty files are enough: cards whose size can't be read are still dealt
+        let cards_dir =
+            std::env::temp_dir().join(format!("talespin-{}-{}-cards", name, std::process::id()));
+        std::fs::create_dir_all(&cards_dir).unwrap();
+        for i in 0..DECK_SIZE {
+            std::fs::write(cards_dir.join(format!("{}.png", i)), b"").unwrap();
+        }
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_talespin-server"))
+            .env("PORT", "0")
+            .env("CARDS_DIR", &cards_dir)
+            .env("ADMIN_TOKEN", ADMIN_TOKEN)
+            // the port is read off the "Listening on" line, logged at info
+            .env("RUST_LOG", "info")
+            // nothing from the developer's shell changes what the server loads
+            .env_remove("SNAPSHOT_PATH")
+            .env_remove("MIN_CARD_RESOLUTION")
+            .env_remove("DEFAULT_DECK_THEME")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let port = loop {
+            let line = lines
+                .next()
+                .expect("server exited before listening")
+                .unwrap();
+            if let Some(addr) = line.split("Listening on ").nth(1) {
+                let port: String = addr
+                    .rsplit(':')
+                    .next()
+                    .unwrap()
+                    .chars()
+                    .take_while(char::is_ascii_digit)
+                    .collect();
+                break port.parse().unwrap();
+            }
+        };
+        // keep reading so the server never blocks on a full pipe
+        std::thread::spawn(move || lines.for_each(drop));
+
+        Server {
+            child,
+            port,
+            cards_dir,
+        }
+    }
+
+    async fn create_room(&self) -> String {
+        let created: Value = reqwest::Client::new()
+            .post(format!("http://127.0.0.1:{}/create", self.port))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        created["RoomState"]["room_id"]
+            .as_str()
+            .expect("no room id in the created room")
+            .to_string()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.cards_dir);
+    }
+}
+
+struct Client {
+    name: String,
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    // the latest RoomState seen, whatever was being waited for at the time
+    state: Value,
+}
+
+impl Client {
+    // connected, but not in any room yet
+    async fn connect(server: &Server, name: &str) -> Self {
+        let (ws, _) = connect_async(format!("ws://127.0.0.1:{}/ws", server.port))
+            .await
+            .unwrap();
+        Client {
+            name: name.to_string(),
+            ws,
+            state: Value::Null,
+        }
+    }
+
+    async fn join(server: &Server, room_id: &str, name: &str) -> Self {
+        let mut client = Client::connect(server, name).await;
+        client
+            .send(json!({ "JoinRoom": { "room_id": room_id, "name": name } }))
+            .await;
+        client.recv("RoomState").await;
+        client
+    }
+
+    async fn send(&mut self, msg: Value) {
+        self.ws.send(Message::Text(msg.to_string())).await.unwrap();
+    }
+
+    // the body of the next message of this kind; anything else is skipped
+    async fn recv(&mut self, kind: &str) -> Value {
+        let (got, body) = self.recv_any(&[kind, "Error"]).await;
+        if got == "Error" {
+            panic!("{} got an error: {}", self.name, body);
+        }
+        body
+    }
+
+    // the kind and body of the next message of any of these kinds
+    async fn recv_any(&mut self, kinds: &[&str]) -> (String, Value) {
+        let name = self.name.clone();
+        tokio::time::timeout(RECV_TIMEOUT, async {
+            loop {
+                let msg = self.ws.next().await.expect("connection closed").unwrap();
+                let Message::Text(text) = msg else {
+                    continue;
+                };
+                let mut msg: Value = serde_json::from_str(&text).unwrap();
+                if let Some(state) = msg.get("RoomState") {
+                    self.state = state.clone();
+                }
+                for kind in kinds {
+                    if let Some(body) = msg.get_mut(*kind) {
+                        return (kind.to_string(), body.take());
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{} never got any of {:?}", name, kinds))
+    }
+
+    // the server hung up, with or without a close frame
+    async fn closed(&mut self) -> bool {
+        tokio::time::timeout(RECV_TIMEOUT, async {
+            loop {
+                match self.ws.next().await {
+                    None | Some(Err(_)) | Some(Ok(Message::Close(_))) => return true,
+                    Some(Ok(_)) => {}
+                }
+            }
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    // the room state once the room has reached this stage
+    async fn state_in(&mut self, stage: &str) -> Value {
+        while self.state["stage"] != stage {
+            self.recv("RoomState").await;
+        }
+        self.state.clone()
+    }
+}
+
+fn first_card(hand: &Value) -> String {
+    hand[0].as_str().expect("empty hand").to_string()
+}
+
+// the storyteller and then everyone else put down the first card in their
+// hand, and everyone finds the storyteller's card
+async fn play_round(clients: &mut [Client], hands: &[Value], storyteller_idx: usize) {
+    let storyteller_card = first_card(&hands[storyteller_idx]);
+    clients[storyteller_idx]
+        .send(json!({
+            "ActivePlayerChooseCard": { "card": storyteller_card, "description": "a clue" }
+        }))
+        .await;
+    for (i, client) in clients.iter_mut().enumerate() {
+        if i != storyteller_idx {
+            client.recv("PlayersChoose").await;
+            let card = first_card(&hands[i]);
+            client
+                .send(json!({ "PlayerChooseCard": { "card": card } }))
+                .await;
+        }
+    }
+    for client in clients.iter_mut() {
+        client.recv("BeginVoting").await;
+    }
+    for (i, client) in clients.iter_mut().enumerate() {
+        if i != storyteller_idx {
+            client
+                .send(json!({ "Vote": { "card": storyteller_card } }))
+                .await;
+        }
+    }
+    for client in clients.iter_mut() {
+        client.recv("Results").await;
+    }
+}
+
+// everyone joins and readies up; the hands dealt, in join order, and which
+// client is the storyteller
+async fn start_game(server: &Server, names: &[&str]) -> (Vec<Client>, Vec<Value>, usize) {
+    let room_id = server.create_room().await;
+
+    let mut clients = Vec::new();
+    for name in names {
+        clients.push(Client::join(server, &room_id, name).await);
+    }
+    for client in clients.iter_mut() {
+        client.send(json!({ "Ready": {} })).await;
+    }
+
+    let mut hands = Vec::new();
+    for client in clients.iter_mut() {
+        hands.push(client.recv("StartRound").await["hand"].clone());
+    }
+    let state = clients[0].state_in("ActiveChooses").await;
+    let storyteller = state["active_player"].as_str().unwrap();
+    let storyteller_idx = clients.iter().position(|c| c.name == storyteller).unwrap();
+    (clients, hands, storyteller_idx)
+}
+
+#[tokio::test]
+async fn a_player_who_drops_while_joining_leaves_no_seat_behind() {
+    let server = Server::start("drop");
+    let room_id = server.create_room().await;
+    let mut host = Client::join(&server, &room_id, "alice").await;
+
+    // the join is read before the hangup, so it gets in and sending it the
+    // room is the first thing to fail
+    let mut ghost = Client::connect(&server, "ghost").await;
+    ghost
+        .send(json!({ "JoinRoom": { "room_id": room_id, "name": "ghost" } }))
+        .await;
+    drop(ghost);
+
+    // seated, then gone again
+    let mut seated = false;
+    loop {
+        let here = host.recv("RoomState").await["players"]
+            .get("ghost")
+            .is_some();
+        if seated && !here {
+            break;
+        }
+        seated |= here;
+    }
+
+    // the name is free again
+    let ghost = Client::join(&server, &room_id, "ghost").await;
+    let players = ghost.state["players"].as_object().unwrap();
+    let mut names: Vec<&String> = players.keys().collect();
+    names.sort();
+    assert_eq!(names, ["alice", "ghost"]);
+}
+
+#[tokio::test]
+async fn only_one_of_two_joins_with_the_same_name_gets_in() {
+    let server = Server::start("same-name");
+    let room_id = server.create_room().await;
+
+    let mut first = Client::connect(&server, "alice").await;
+    let mut second = Client::connect(&server, "alice").await;
+    let join = json!({ "JoinRoom": { "room_id": room_id, "name": "alice" } });
+    tokio::join!(first.send(join.clone()), second.send(join));
+
+    let (first, second) = tokio::join!(
+        first.recv_any(&["RoomState", "Error"]),
+        second.recv_any(&["RoomState", "Error"])
+    );
+    let mut kinds = [first.0.as_str(), second.0.as_str()];
+    kinds.sort();
+    assert_eq!(kinds, ["Error", "RoomState"]);
+    let error = if first.0 == "Error" {
+        first.1
+    } else {
+        second.1
+    };
+    assert_eq!(error["code"], "NameTaken");
+}
+
+#[tokio::test]
+async fn a_malformed_first_message_is_answered_and_closed() {
+    let server = Server::start("malformed");
+    let room_id = server.create_room().await;
+
+    let frames = [
+        json!({ "JoinRoom": { "room_id": room_id } }).to_string(),
+        json!({ "JoinRoom": { "name": "alice" } }).to_string(),
+        json!({ "Ready": {} }).to_string(),
+        "not json".to_string(),
+    ];
+    for frame in frames {
+        let mut client = Client::connect(&server, "alice").await;
+        client.ws.send(Message::Text(frame.clone())).await.unwrap();
+        let error = client.recv_any(&["Error"]).await.1;
+        assert_eq!(error["code"], "MalformedJoin", "for {}", frame);
+        assert!(client.closed().await, "left open after {}", frame);
+    }
+}
+
+#[tokio::test]
+async fn a_player_who_readied_and_dropped_during_results_stays_ready() {
+    let server = Server::start("ready-drop");
+    let (mut clients, hands, storyteller_idx) =
+        start_game(&server, &["alice", "bob", "carol", "dave"]).await;
+    play_round(&mut clients, &hands, storyteller_idx).await;
+    let room_id = clients[0].state["room_id"].as_str().unwrap().to_string();
+
+    // ready, gone and back again before anyone else is ready
+    let mut leaver = clients.pop().unwrap();
+    leaver.send(json!({ "Ready": {} })).await;
+    while leaver.state["players"][leaver.name.as_str()]["ready"] != true {
+        leaver.recv("RoomState").await;
+    }
+    leaver.ws.close(None).await.ok();
+    drop(leaver);
+    while clients[0].state["players"]["dave"]["connected"] != false {
+        clients[0].recv("RoomState").await;
+    }
+
+    let back = Client::join(&server, &room_id, "dave").await;
+    assert_eq!(back.state["players"]["dave"]["ready"], true);
+    clients.push(back);
+
+    // the others readying is all the next round waits on
+    for client in clients[..3].iter_mut() {
+        client.send(json!({ "Ready": {} })).await;
+    }
+    for client in clients.iter_mut() {
+        client.recv("StartRound").await;
+    }
+}
+
+#[tokio::test]
+async fn a_second_join_on_the_same_connection_is_refused() {
+    let server = Server::start("rejoin");
+    let room_id = server.create_room().await;
+    let other_room = server.create_room().await;
+    let mut client = Client::join(&server, &room_id, "alice").await;
+
+    client
+        .send(json!({ "JoinRoom": { "room_id": other_room, "name": "alice2" } }))
+        .await;
+    let error = client.recv_any(&["Error"]).await.1;
+    assert_eq!(error["code"], "AlreadyJoined");
+
+    // still in the first room, and only once
+    client.send(json!({ "TimeSync": {} })).await;
+    client.recv("ServerTime").await;
+    let players = client.state["players"].as_object().unwrap();
+    assert_eq!(players.keys().collect::<Vec<_>>(), ["alice"]);
+}
+
+#[tokio::test]
+async fn a_card_reload_only_reaches_rooms_created_after_it() {
+    let server = Server::start("reload");
+    // the host of a new room is told how many cards it has to play with
+    let deck_size = |room_id: String| {
+        let server = &server;
+        async move {
+            let mut host = Client::join(server, &room_id, "host").await;
+            host.recv("DeckCheck").await["cards"].as_u64().unwrap() as usize
+        }
+    };
+
+    let before = server.create_room().await;
+    for i in DECK_SIZE..DECK_SIZE + 10 {
+        std::fs::write(server.cards_dir.join(format!("{}.png", i)), b"").unwrap();
+    }
+    let res = reqwest::Client::new()
+        .post(format!(
+            "http://127.0.0.1:{}/admin/cards/reload",
+            server.port
+        ))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    let after = server.create_room().await;
+
+    assert_eq!(deck_size(before).await, DECK_SIZE);
+    assert_eq!(deck_size(after).await, DECK_SIZE + 10);
+}
+
+#[tokio::test]
+async fn voting_starts_when_a_player_leaves_instead_of_choosing() {
+    let server = Server::start("leave");
+    let (mut clients, hands, storyteller_idx) =
+        start_game(&server, &["alice", "bob", "carol", "dave"]).await;
+
+    let storyteller_card = first_card(&hands[storyteller_idx]);
+    clients[storyteller_idx]
+        .send(json!({
+            "ActivePlayerChooseCard": { "card": storyteller_card, "description": "a clue" }
+        }))
+        .await;
+
+    // two of the three pick a card and the last one leaves
+    let others: Vec<usize> = (0..clients.len())
+        .filter(|i| *i != storyteller_idx)
+        .collect();
+    for &i in &others[..2] {
+        clients[i].recv("PlayersChoose").await;
+        let card = first_card(&hands[i]);
+        clients[i]
+            .send(json!({ "PlayerChooseCard": { "card": card } }))
+            .await;
+    }
+    let mut leaver = clients.remove(others[2]);
+    leaver.ws.close(None).await.ok();
+    drop(leaver);
+
+    // nobody is left to wait on, and the one who left still gets a card in
+    for client in clients.iter_mut() {
+        let voting = client.recv("BeginVoting").await;
+        assert_eq!(voting["center_cards"].as_array().unwrap().len(), 4);
+    }
+}
+
+#[tokio::test]
+async fn plays_a_round() {
+    let server = Server::start("round");
+    let (mut clients, hands, storyteller_idx) =
+        start_game(&server, &["alice", "bob", "carol"]).await;
+    let storyteller = clients[storyteller_idx].name.clone();
+
+    let storyteller_card = first_card(&hands[storyteller_idx]);
+    clients[storyteller_idx]
+        .send(json!({
+            "ActivePlayerChooseCard": { "card": storyteller_card, "description": "a clue" }
+        }))
+        .await;
+
+    // everyone else puts down the first card in their hand
+    let others: Vec<usize> = (0..clients.len())
+        .filter(|i| *i != storyteller_idx)
+        .collect();
+    for &i in &others {
+        let choose = clients[i].recv("PlayersChoose").await;
+        assert_eq!(choose["description"], "a clue");
+        let card = first_card(&hands[i]);
+        clients[i]
+            .send(json!({ "PlayerChooseCard": { "card": card } }))
+            .await;
+    }
+
+    for client in clients.iter_mut() {
+        let voting = client.recv("BeginVoting").await;
+        assert_eq!(voting["center_cards"].as_array().unwrap().len(), 3);
+    }
+
+    // one finds the storyteller's card, the other is fooled by the first's
+    let (guesser, fooled) = (others[0], others[1]);
+    let guesser_name = clients[guesser].name.clone();
+    let fooled_name = clients[fooled].name.clone();
+    let guesser_card = first_card(&hands[guesser]);
+    clients[guesser]
+        .send(json!({ "Vote": { "card": storyteller_card } }))
+        .await;
+    clients[fooled]
+        .send(json!({ "Vote": { "card": guesser_card } }))
+        .await;
+
+    for client in clients.iter_mut() {
+        let results = client.recv("Results").await;
+        assert_eq!(results["active_card"], storyteller_card.as_str());
+
+        // some but not all found it: 3 to the storyteller and to whoever found
+        // it, plus 1 for each vote a decoy drew
+        let points = &results["point_change"];
+        assert_eq!(points[storyteller.as_str()], 3);
+        assert_eq!(points[guesser_name.as_str()], 4);
+        assert_eq!(points[fooled_name.as_str()], 0);
+    }
+}
+
+#[tokio::test]
+async fn a_room_renamed_mid_game_tells_everyone_its_new_id() {
+    let server = Server::start("rename");
+    let (mut clients, _, _) = start_game(&server, &["alice", "bob", "carol"]).await;
+    let room_id = clients[0].state["room_id"].as_str().unwrap().to_string();
+
+    let res = reqwest::Client::new()
+        .post(format!(
+            "http://127.0.0.1:{}/admin/rooms/{}/rename",
+            server.port, room_id
+        ))
+        .bearer_auth(ADMIN_TOKEN)
+        .json(&json!({ "new_room_id": "renamed" }))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+
+    for client in clients.iter_mut() {
+        let migrated = client.recv("RoomMigrated").await;
+        assert_eq!(migrated["new_room_id"], "renamed");
+        let state = client.recv("RoomState").await;
+        assert_eq!(state["room_id"], "renamed");
+        assert_eq!(state["stage"], "ActiveChooses");
+    }
+}